@@ -0,0 +1,28 @@
+//Guesses the platform and quirks of roms from their code
+//
+//Usage: analyze <rom>...
+
+use chip8::analyze;
+use std::env;
+use std::fs;
+use std::process;
+
+pub fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: analyze <rom>...");
+        process::exit(2);
+    }
+
+    for (idx, path) in paths.iter().enumerate() {
+        let rom = fs::read(path).unwrap_or_else(|err| {
+            eprintln!("analyze: {}: {}", path, err);
+            process::exit(2);
+        });
+        if idx > 0 {
+            println!();
+        }
+        println!("{}", path);
+        println!("{}", analyze(&rom).report());
+    }
+}
@@ -0,0 +1,34 @@
+//Usage: assembler <source> <rom>
+//Sources ending in .8o are compiled as Octo programs
+
+use chip8::{assemble, compile_octo};
+use std::env;
+use std::fs;
+use std::process;
+
+pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: assembler <source> <rom>");
+        process::exit(2);
+    }
+
+    let source = fs::read_to_string(&args[0]).unwrap_or_else(|err| {
+        eprintln!("assembler: {}: {}", args[0], err);
+        process::exit(2);
+    });
+    let rom = if args[0].ends_with(".8o") {
+        compile_octo(&source)
+    } else {
+        assemble(&source)
+    };
+    let rom = rom.unwrap_or_else(|err| {
+        eprintln!("{}: {}", args[0], err);
+        process::exit(1);
+    });
+    if let Err(err) = fs::write(&args[1], &rom) {
+        eprintln!("assembler: {}: {}", args[1], err);
+        process::exit(2);
+    }
+    println!("{}: {} bytes", args[1], rom.len());
+}
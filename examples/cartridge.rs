@@ -0,0 +1,77 @@
+//Packs and unpacks Octo cartridges
+//
+//Usage:
+//  cartridge unpack <cart.gif> <source.8o>     extracts the program and prints the options
+//  cartridge pack <source.8o> <cart.gif> [--tickrate N] [--platform chip8|schip|xochip]
+
+use chip8::{Cartridge, CartridgeOptions, Platform};
+use std::env;
+use std::fs;
+use std::process;
+
+fn fail(message: String) -> ! {
+    eprintln!("cartridge: {}", message);
+    process::exit(1);
+}
+
+fn unpack(gif: &str, source: &str) {
+    let data = fs::read(gif).unwrap_or_else(|err| fail(format!("{}: {}", gif, err)));
+    let cartridge = Cartridge::from_gif(&data).unwrap_or_else(|err| fail(format!("{}: {}", gif, err)));
+    if let Err(err) = fs::write(source, &cartridge.program) {
+        fail(format!("{}: {}", source, err));
+    }
+
+    let options = &cartridge.options;
+    println!("platform: {:?}", options.platform());
+    println!("tickrate: {}", options.tickrate);
+    println!("quirks: {:?}", options.quirks);
+    println!("palette: {}", options.palette().iter().map(|rgb| format!("#{:06x}", rgb)).collect::<Vec<String>>().join(" "));
+    match cartridge.rom() {
+        Ok(rom) => println!("rom: {} bytes", rom.len()),
+        Err(err) => println!("{}", err),
+    }
+}
+
+fn pack(source: &str, gif: &str, args: &[String]) {
+    let program = fs::read_to_string(source).unwrap_or_else(|err| fail(format!("{}: {}", source, err)));
+    let mut options = CartridgeOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| fail(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--tickrate" => options.tickrate = value.parse().unwrap_or_else(|_| fail(format!("invalid tickrate {}", value))),
+            "--platform" => {
+                let platform = match value.as_str() {
+                    "chip8" => Platform::Chip8,
+                    "schip" => Platform::SuperChip,
+                    "xochip" => Platform::XoChip,
+                    _ => fail(format!("unknown platform {}", value)),
+                };
+                options.max_size = platform.octo_max_size();
+                options.quirks = platform.quirks();
+            },
+            _ => fail(format!("unknown option {}", arg)),
+        }
+    }
+
+    let cartridge = Cartridge::new(&program, options);
+    if let Err(err) = cartridge.rom() {
+        fail(format!("{}: {}", source, err));
+    }
+    if let Err(err) = fs::write(gif, cartridge.to_gif()) {
+        fail(format!("{}: {}", gif, err));
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("unpack") if args.len() == 3 => unpack(&args[1], &args[2]),
+        Some("pack") if args.len() >= 3 => pack(&args[1], &args[2], &args[3..]),
+        _ => {
+            eprintln!("usage: cartridge unpack <cart.gif> <source.8o>");
+            eprintln!("       cartridge pack <source.8o> <cart.gif> [--tickrate N] [--platform chip8|schip|xochip]");
+            process::exit(2);
+        },
+    }
+}
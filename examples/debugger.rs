@@ -0,0 +1,44 @@
+use chip8::{Config, Cpu, Debugger, Rewind, DEFAULT_INSTRUCTIONS_PER_FRAME, FRAME_RATE};
+use std::env;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, Read, Write};
+
+pub fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| "./rom/IBM".to_string());
+    let mut file = File::open(&path).unwrap();
+    let mut buffer: Vec<u8> = Vec::new();
+    let _ = file.read_to_end(&mut buffer);
+
+    let mut cpu = match Cpu::try_with_config(&buffer, Config::default()) {
+        Ok(cpu) => cpu,
+        Err(err) => {
+            eprintln!("debugger: {}: {}", path, err);
+            return;
+        },
+    };
+    let mut debugger = Debugger::new(DEFAULT_INSTRUCTIONS_PER_FRAME);
+    //One snapshot per frame, 30 seconds of history
+    debugger.rewind = Some(Rewind::new(30 * FRAME_RATE as usize, 1));
+    println!("{}: type help for the list of commands", path);
+
+    let stdin = stdin();
+    let mut last = String::new();
+    loop {
+        print!("(chip8) ");
+        stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 || line.trim() == "quit" {
+            break;
+        }
+        //An empty line repeats the previous command
+        if !line.trim().is_empty() {
+            last = line;
+        }
+
+        let output = debugger.command(&mut cpu, &last);
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+    }
+}
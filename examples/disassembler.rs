@@ -1,23 +1,14 @@
-use chip8::disassemble;
+use chip8::disassemble_recursive;
+use std::env;
 use std::fs::File;
 use std::io::Read;
 
-fn run(buffer: &Vec<u8>) {
-    let mut pc = 0x200;
-
-    while pc < buffer.len() {
-        let opcode: u16 = (u16::from(buffer[pc]) << 8) | u16::from(buffer[pc+1]);
-        disassemble(opcode);
-        pc += 2;
-    }
-}
-
+//Usage: disassembler [rom], the rom is disassembled following its control flow
 pub fn main() {
-    let mut file = File::open("./rom/IBM").unwrap();
+    let path = env::args().nth(1).unwrap_or_else(|| "./rom/IBM".to_string());
+    let mut file = File::open(&path).unwrap();
     let mut buffer: Vec<u8> = Vec::new();
     let _ = file.read_to_end(&mut buffer);
 
-    let mut memory: Vec<u8> = vec![0x0; 0x200+buffer.len()];
-    memory[0x200..].copy_from_slice(&buffer[..]);
-    run(&memory);
-}
\ No newline at end of file
+    print!("{}", disassemble_recursive(&buffer, 0x200));
+}
@@ -0,0 +1,26 @@
+use chip8::{Config, Cpu, GdbStub, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+//Usage: gdbserver <rom> [port], then `target remote localhost:port`
+pub fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| "./rom/IBM".to_string());
+    let port = env::args().nth(2).unwrap_or_else(|| "1234".to_string());
+    let mut file = File::open(&path).unwrap();
+    let mut buffer: Vec<u8> = Vec::new();
+    let _ = file.read_to_end(&mut buffer);
+
+    let mut cpu = match Cpu::try_with_config(&buffer, Config::default()) {
+        Ok(cpu) => cpu,
+        Err(err) => {
+            eprintln!("gdbserver: {}: {}", path, err);
+            return;
+        },
+    };
+    let mut stub = GdbStub::new(DEFAULT_INSTRUCTIONS_PER_FRAME);
+    println!("{}: waiting for gdb on 127.0.0.1:{}", path, port);
+    if let Err(err) = stub.listen(&mut cpu, format!("127.0.0.1:{}", port)) {
+        eprintln!("gdbserver: {}", err);
+    }
+}
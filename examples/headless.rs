@@ -0,0 +1,431 @@
+//Runs a rom without a window and dumps the final machine state
+//
+//Usage: headless <rom> [options]
+//  --frames N          frames to run (default 600)
+//  --until-pc ADDR     stop before executing the instruction at ADDR
+//  --ipf N             instructions per frame (default 10)
+//  --seed N            seed of the RND generator (default 0)
+//  --platform NAME     chip8, schip or xochip (default chip8)
+//  --quirks NAME       legacy, vip, chip48, schip or xochip (default: preset of the platform)
+//  --stack-depth N     levels of the call stack (default: 12 on chip8, 16 otherwise)
+//  --memory-stack      keep the call stack in memory at 0xEA0 like the VIP, at most 24 levels
+//  --key FRAME:+K      press key K (hex) at the start of FRAME, -K releases it
+//  --keys FILE         file with one FRAME +K / FRAME -K event per line, # starts a comment
+//  --format FORMAT     text or json (default text)
+//  --record FILE       write the keys and the state of every frame to a movie
+//  --play FILE         replay a movie, its platform, quirks, stack, seed and ipf replace the options
+//  --trace FILE        write one line per executed instruction to FILE, - for stdout
+//  --trace-range A-B   only trace instructions with PC between A and B (hex)
+//  --profile FILE      write a profile report to FILE, - for stdout
+//  --coverage FILE     write a disassembly of the rom annotated with coverage to FILE, - for stdout
+//  --coverage-map FILE write the coverage map of the rom to FILE, - for stdout
+//
+//A rom ending in .gif is an Octo cartridge, its program is compiled and its
+//tickrate, platform and quirks replace --ipf, --platform and --quirks
+//
+//The run also stops when the rom executes EXIT or faults, the exit code is 1 on a fault
+//or when a replayed movie diverges
+
+use chip8::{Cartridge, Config, Coverage, Cpu, CpuError, Json, Movie, MovieError, Platform, Profiler, Quirks, Tracer, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::process;
+
+struct Options {
+    rom: String,
+    frames: u64,
+    until_pc: Option<u16>,
+    ipf: usize,
+    seed: u64,
+    platform: Platform,
+    quirks: Option<Quirks>,
+    stack_depth: Option<usize>,
+    memory_stack: bool,
+    keys: BTreeMap<u64, Vec<(usize, bool)>>,
+    json: bool,
+    record: Option<String>,
+    play: Option<Movie>,
+    trace: Option<String>,
+    trace_range: Option<RangeInclusive<u16>>,
+    profile: Option<String>,
+    coverage: Option<String>,
+    coverage_map: Option<String>,
+}
+
+enum Stop {
+    Frames,
+    Pc,
+    Halted,
+    Error(CpuError),
+    Diverged(MovieError),
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    let parsed = if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        value.parse()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", value))
+}
+
+fn parse_addr(value: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(value)?).map_err(|_| format!("address '{}' out of range", value))
+}
+
+//Parses FRAME:+K or FRAME +K
+fn parse_key_event(event: &str, keys: &mut BTreeMap<u64, Vec<(usize, bool)>>) -> Result<(), String> {
+    let mut parts = event.splitn(2, |c: char| c == ':' || c.is_whitespace());
+    let frame = parse_number(parts.next().unwrap_or("").trim())?;
+    let key = parts.next().unwrap_or("").trim();
+    let (pressed, key) = match key.chars().next() {
+        Some('+') => (true, &key[1..]),
+        Some('-') => (false, &key[1..]),
+        _ => return Err(format!("invalid key event '{}'", event)),
+    };
+    let key = usize::from_str_radix(key, 16)
+        .ok()
+        .filter(|&key| key < 0x10)
+        .ok_or_else(|| format!("invalid key in '{}'", event))?;
+
+    keys.entry(frame).or_default().push((key, pressed));
+    Ok(())
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        rom: String::new(),
+        frames: 600,
+        until_pc: None,
+        ipf: DEFAULT_INSTRUCTIONS_PER_FRAME,
+        seed: 0,
+        platform: Platform::Chip8,
+        quirks: None,
+        stack_depth: None,
+        memory_stack: false,
+        keys: BTreeMap::new(),
+        json: false,
+        record: None,
+        play: None,
+        trace: None,
+        trace_range: None,
+        profile: None,
+        coverage: None,
+        coverage_map: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--frames" => options.frames = parse_number(&value()?)?,
+            "--until-pc" => options.until_pc = Some(parse_addr(&value()?)?),
+            "--ipf" => options.ipf = parse_number(&value()?)? as usize,
+            "--seed" => options.seed = parse_number(&value()?)?,
+            "--platform" => options.platform = match value()?.as_str() {
+                "chip8" => Platform::Chip8,
+                "schip" => Platform::SuperChip,
+                "xochip" => Platform::XoChip,
+                other => return Err(format!("unknown platform '{}'", other)),
+            },
+            "--quirks" => options.quirks = Some(match value()?.as_str() {
+                "legacy" => Quirks::default(),
+                "vip" => Quirks::cosmac_vip(),
+                "chip48" => Quirks::chip48(),
+                "schip" => Quirks::superchip(),
+                "xochip" => Quirks::xochip(),
+                other => return Err(format!("unknown quirks '{}'", other)),
+            }),
+            "--stack-depth" => options.stack_depth = Some(parse_number(&value()?)? as usize),
+            "--memory-stack" => options.memory_stack = true,
+            "--key" => parse_key_event(&value()?, &mut options.keys)?,
+            "--keys" => {
+                let path = value()?;
+                let script = fs::read_to_string(&path)
+                    .map_err(|err| format!("{}: {}", path, err))?;
+                for line in script.lines() {
+                    let line = line.split('#').next().unwrap_or("").trim();
+                    if !line.is_empty() {
+                        parse_key_event(line, &mut options.keys)?;
+                    }
+                }
+            },
+            "--format" => options.json = match value()?.as_str() {
+                "text" => false,
+                "json" => true,
+                other => return Err(format!("unknown format '{}'", other)),
+            },
+            "--record" => options.record = Some(value()?),
+            "--play" => {
+                let path = value()?;
+                let movie = fs::read(&path).map_err(|err| format!("{}: {}", path, err))?;
+                options.play = Some(Movie::from_bytes(&movie).map_err(|err| format!("{}: {}", path, err))?);
+            },
+            "--profile" => options.profile = Some(value()?),
+            "--coverage" => options.coverage = Some(value()?),
+            "--coverage-map" => options.coverage_map = Some(value()?),
+            "--trace" => options.trace = Some(value()?),
+            "--trace-range" => {
+                let range = value()?;
+                let mut bounds = range.splitn(2, '-')
+                    .map(|addr| u16::from_str_radix(addr.trim_start_matches("0x"), 16).ok());
+                match (bounds.next().flatten(), bounds.next().flatten()) {
+                    (Some(start), Some(end)) => options.trace_range = Some(start..=end),
+                    _ => return Err(format!("invalid range '{}'", range)),
+                }
+            },
+            _ if options.rom.is_empty() && !arg.starts_with("--") => options.rom = arg,
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("missing rom".to_string());
+    }
+    Ok(options)
+}
+
+fn run(cpu: &mut Cpu, options: &Options, movie: &mut Option<Movie>) -> (u64, u64, Stop) {
+    let start = cpu.cycles;
+
+    for frame in 0..options.frames {
+        for &(key, pressed) in options.keys.get(&frame).into_iter().flatten() {
+            cpu.keyboard.set_key(key, pressed);
+        }
+
+        match cpu.run_frame_until(options.ipf, options.until_pc) {
+            Ok(info) if info.reached => return (frame, cpu.cycles - start, Stop::Pc),
+            Ok(_) => {},
+            Err(err) => return (frame, cpu.cycles - start, Stop::Error(err)),
+        }
+        if let Some(movie) = movie.as_mut() {
+            movie.record(cpu);
+        }
+        if cpu.halted {
+            return (frame + 1, cpu.cycles - start, Stop::Halted);
+        }
+    }
+
+    (options.frames, cpu.cycles - start, Stop::Frames)
+}
+
+fn play(cpu: &mut Cpu, movie: &Movie) -> (u64, u64, Stop) {
+    let mut instructions = 0;
+    for frame in 0..movie.frames.len() {
+        match movie.play_frame(cpu, frame) {
+            Ok(info) => instructions += info.instructions as u64,
+            Err(MovieError::Cpu { error, .. }) => return (frame as u64, instructions, Stop::Error(error)),
+            Err(err) => return (frame as u64, instructions, Stop::Diverged(err)),
+        }
+    }
+    (movie.frames.len() as u64, instructions, Stop::Frames)
+}
+
+//FNV-1a, stable across runs and platforms
+fn memory_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn display_rows(cpu: &Cpu) -> Vec<String> {
+    let display = &cpu.display;
+    (0..display.height())
+        .map(|y| (0..display.width())
+            .map(|x| match display.get_planes(x, y) {
+                0 => '.',
+                1 => '#',
+                2 => '+',
+                _ => '@',
+            })
+            .collect())
+        .collect()
+}
+
+fn hex_list<T: std::fmt::LowerHex>(values: &[T], width: usize) -> Vec<String> {
+    values.iter().map(|value| format!("{:0width$x}", value, width = width)).collect()
+}
+
+fn print_text(cpu: &Cpu, frames: u64, instructions: u64, stop: &Stop) {
+    match stop {
+        Stop::Frames => println!("stop: frames"),
+        Stop::Pc => println!("stop: pc"),
+        Stop::Halted => println!("stop: halted"),
+        Stop::Error(err) => println!("stop: error: {}", err),
+        Stop::Diverged(err) => println!("stop: diverged: {}", err),
+    }
+    println!("frames: {}", frames);
+    println!("instructions: {}", instructions);
+    println!("pc: {:04x}", cpu.register.pc);
+    println!("i: {:04x}", cpu.register.i);
+    println!("v: {}", hex_list(&cpu.register.v, 2).join(" "));
+    println!("stack: {}", hex_list(cpu.register.stack.as_slice(), 4).join(" "));
+    println!("delay: {:02x}", cpu.register.delay);
+    println!("sound: {:02x}", cpu.register.sound);
+    println!("memory: {:016x}", memory_hash(&cpu.memory.data));
+    println!("display: {}x{}", cpu.display.width(), cpu.display.height());
+    for row in display_rows(cpu) {
+        println!("{}", row);
+    }
+}
+
+fn quote(value: &str) -> String {
+    Json::String(value.to_string()).to_string()
+}
+
+fn quote_list(values: &[String]) -> String {
+    let quoted: Vec<String> = values.iter().map(|value| quote(value)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+fn print_json(cpu: &Cpu, frames: u64, instructions: u64, stop: &Stop) {
+    let (stop, error) = match stop {
+        Stop::Frames => ("frames", "null".to_string()),
+        Stop::Pc => ("pc", "null".to_string()),
+        Stop::Halted => ("halted", "null".to_string()),
+        Stop::Error(err) => ("error", quote(&err.to_string())),
+        Stop::Diverged(err) => ("diverged", quote(&err.to_string())),
+    };
+
+    println!("{{");
+    println!("  \"stop\": \"{}\",", stop);
+    println!("  \"error\": {},", error);
+    println!("  \"frames\": {},", frames);
+    println!("  \"instructions\": {},", instructions);
+    println!("  \"pc\": {},", cpu.register.pc);
+    println!("  \"i\": {},", cpu.register.i);
+    println!("  \"v\": {:?},", cpu.register.v);
+    println!("  \"stack\": {:?},", cpu.register.stack.as_slice());
+    println!("  \"delay\": {},", cpu.register.delay);
+    println!("  \"sound\": {},", cpu.register.sound);
+    println!("  \"memory_hash\": \"{:016x}\",", memory_hash(&cpu.memory.data));
+    println!("  \"width\": {},", cpu.display.width());
+    println!("  \"height\": {},", cpu.display.height());
+    println!("  \"display\": {}", quote_list(&display_rows(cpu)));
+    println!("}}");
+}
+
+//Writes a report to a file, - is stdout
+fn write_report(path: &str, report: &str) {
+    if path == "-" {
+        println!("{}\n", report);
+    } else if let Err(err) = fs::write(path, format!("{}\n", report)) {
+        eprintln!("headless: {}: {}", path, err);
+    }
+}
+
+pub fn main() {
+    let mut options = parse_args().unwrap_or_else(|err| {
+        eprintln!("headless: {}", err);
+        process::exit(2);
+    });
+    let mut rom = fs::read(&options.rom).unwrap_or_else(|err| {
+        eprintln!("headless: {}: {}", options.rom, err);
+        process::exit(2);
+    });
+    if options.rom.ends_with(".gif") {
+        let cartridge = Cartridge::from_gif(&rom).and_then(|cartridge| Ok((cartridge.rom()?, cartridge)));
+        let (program, cartridge) = cartridge.unwrap_or_else(|err| {
+            eprintln!("headless: {}: {}", options.rom, err);
+            process::exit(2);
+        });
+        rom = program;
+        options.ipf = cartridge.options.tickrate;
+        options.platform = cartridge.options.platform();
+        options.quirks = Some(cartridge.options.quirks);
+    }
+
+    let tracer = options.trace.as_ref().map(|path| {
+        let tracer = if path == "-" {
+            Ok(Tracer::to_writer(Box::new(io::stdout())))
+        } else {
+            Tracer::to_file(path)
+        };
+        let tracer = tracer.unwrap_or_else(|err| {
+            eprintln!("headless: {}: {}", path, err);
+            process::exit(2);
+        });
+        match options.trace_range.clone() {
+            Some(range) => tracer.with_range(range),
+            None => tracer,
+        }
+    });
+
+    let (mut cpu, frames, instructions, stop) = if let Some(movie) = options.play.as_ref() {
+        let mut cpu = movie.cpu(&rom).unwrap_or_else(|err| {
+            eprintln!("headless: {}: {}", options.rom, err);
+            process::exit(2);
+        });
+        cpu.tracer = tracer;
+        cpu.profiler = options.profile.as_ref().map(|_| Profiler::new());
+        if options.coverage.is_some() || options.coverage_map.is_some() {
+            cpu.coverage = Some(Coverage::new(cpu.memory.data.len()));
+        }
+        let (frames, instructions, stop) = play(&mut cpu, movie);
+        (cpu, frames, instructions, stop)
+    } else {
+        let mut config = Config {
+            seed: Some(options.seed),
+            memory_stack: options.memory_stack,
+            ..Config::new(options.platform)
+        };
+        if let Some(depth) = options.stack_depth {
+            config.stack_depth = depth;
+        }
+        if let Some(quirks) = options.quirks {
+            config.quirks = quirks;
+        }
+        let mut cpu = Cpu::try_with_config(&rom, config).unwrap_or_else(|err| {
+            eprintln!("headless: {}: {}", options.rom, err);
+            process::exit(2);
+        });
+        cpu.tracer = tracer;
+        cpu.profiler = options.profile.as_ref().map(|_| Profiler::new());
+        if options.coverage.is_some() || options.coverage_map.is_some() {
+            cpu.coverage = Some(Coverage::new(cpu.memory.data.len()));
+        }
+        let mut movie = options.record.as_ref().map(|_| Movie::new(&rom, config, options.ipf));
+        let (frames, instructions, stop) = run(&mut cpu, &options, &mut movie);
+
+        if let (Some(path), Some(movie)) = (options.record.as_ref(), movie) {
+            if let Err(err) = fs::write(path, movie.to_bytes()) {
+                eprintln!("headless: {}: {}", path, err);
+                process::exit(2);
+            }
+        }
+        (cpu, frames, instructions, stop)
+    };
+
+    if let Some(mut tracer) = cpu.tracer.take() {
+        if let Err(err) = tracer.flush() {
+            eprintln!("headless: trace: {}", err);
+        }
+    }
+
+    if let (Some(path), Some(profiler)) = (options.profile.as_ref(), cpu.profiler.as_ref()) {
+        write_report(path, &profiler.report(&cpu.memory.data, 20));
+    }
+    if let Some(coverage) = cpu.coverage.as_ref() {
+        let range = 0x200..0x200 + rom.len();
+        if let Some(path) = options.coverage.as_ref() {
+            write_report(path, &coverage.listing(&cpu.memory.data, range.clone()));
+        }
+        if let Some(path) = options.coverage_map.as_ref() {
+            write_report(path, &coverage.map(range));
+        }
+    }
+
+    if options.json {
+        print_json(&cpu, frames, instructions, &stop);
+    } else {
+        print_text(&cpu, frames, instructions, &stop);
+    }
+
+    if let Stop::Error(_) | Stop::Diverged(_) = stop {
+        process::exit(1);
+    }
+}
@@ -1,65 +1,176 @@
-use minifb::{Window, WindowOptions, Scale};
-use chip8::{Cpu, BINDINGS};
-use std::io::{Read, stdin};
-use std::fs::File;
-
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
-
-const ROMS: &[&'static str] = &[
-    "./rom/IBM",
-    "./rom/INVADERS",
-    "./rom/MAZE",
-    "./rom/MISSILE",
-];
-
-fn rom_number() -> usize {
-    let mut rom = String::new();
+use minifb::{Key, Window, WindowOptions, Scale};
+use chip8::{analyze, bindings_with, Cartridge, Config, Cpu, Movie, Rewind, RomDb, RomInfo, HIRES_WIDTH, HIRES_HEIGHT, DEFAULT_INSTRUCTIONS_PER_FRAME, FRAME_RATE};
+use std::time::Duration;
+use std::io::stdin;
+use std::env;
+use std::fs;
+use std::process;
+
+const ROM_DIR: &str = "./rom";
+
+//Seconds of history kept for rewinding with backspace
+const REWIND_SECONDS: usize = 10;
 
-    for (idx, name) in ROMS.iter().enumerate() {
+//Asks for one of the roms in ROM_DIR
+fn choose_rom() -> String {
+    let mut roms: Vec<String> = fs::read_dir(ROM_DIR)
+        .expect("Error reading the rom directory")
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_none_or(|extension| extension != "json"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    roms.sort();
+
+    for (idx, name) in roms.iter().enumerate() {
         println!("{}:\t{}", idx, name);
     }
 
+    let mut rom = String::new();
     println!("Enter the rom number");
     stdin().read_line(&mut rom)
         .expect("Error reading rom number");
 
-    rom.trim().parse::<usize>().unwrap()
+    roms[rom.trim().parse::<usize>().unwrap()].clone()
 }
 
-pub fn run(rom: &[u8], name: &str) {
-    let mut cpu = Cpu::new(rom);
+//With a path the keys of every frame are recorded to a movie, written when the window closes
+pub fn run(rom: &[u8], info: &RomInfo, name: &str, record: Option<&str>) {
+    let config = Config {
+        platform: info.platform,
+        quirks: info.quirks,
+        ..Config::new(info.platform)
+    };
+    let instructions_per_frame = info.tickrate.unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME);
+    let bindings = bindings_with(&info.keys);
+    let mut movie = Movie::new(rom, config, instructions_per_frame);
+    let mut cpu = Cpu::try_with_config(rom, movie.config()).unwrap_or_else(|err| fail(format!("{}: {}", name, err)));
+    if let Some(colors) = info.colors {
+        cpu.display.palette = colors;
+    }
+    let mut rewind = Rewind::new(REWIND_SECONDS * FRAME_RATE as usize, 1);
+    rewind.record(&cpu);
     let mut window = Window::new(
         name,
-        WIDTH,
-        HEIGHT,
+        HIRES_WIDTH,
+        HIRES_HEIGHT,
         WindowOptions{
-            scale: Scale::X8,
+            scale: Scale::X4,
             ..WindowOptions::default()
         }
     ).unwrap();
+    window.limit_update_rate(Some(Duration::from_secs(1) / FRAME_RATE));
 
     while window.is_open() {
-        for i in 0..10 {
-            cpu.next();
+        //Holding backspace plays the recent frames backwards
+        if window.is_key_down(Key::Backspace) {
+            let rewound = rewind.rewind(&mut cpu, 1).is_some();
+            movie.frames.truncate(cpu.frames as usize);
+            if rewound && cpu.display.take_dirty() {
+                let (width, height) = (cpu.display.width(), cpu.display.height());
+                window.update_with_buffer(&cpu.display.get_buffer(), width, height).unwrap();
+            } else {
+                window.update();
+            }
+            continue;
         }
 
-        cpu.decrement_timers();
+        //Several host keys can press the same CHIP-8 key
+        let mut keys = [false; 16];
+        for (key, idx) in bindings.iter() {
+            keys[*idx] |= window.is_key_down(*key);
+        }
+        for (idx, &pressed) in keys.iter().enumerate() {
+            cpu.keyboard.set_key(idx, pressed);
+        }
+
+        let frame = match cpu.run_frame(instructions_per_frame) {
+            Ok(frame) => frame,
+            Err(err) => {
+                //Kept so that replaying the movie reproduces the fault
+                movie.record(&cpu);
+                eprintln!("{}: {}", name, err);
+                break;
+            }
+        };
+
+        rewind.record(&cpu);
+        movie.record(&cpu);
+
+        if frame.display_changed {
+            let (width, height) = (cpu.display.width(), cpu.display.height());
+            window.update_with_buffer(&cpu.display.get_buffer(), width, height).unwrap();
+        } else {
+            window.update();
+        }
+    }
 
-        for (key, idx) in BINDINGS {
-            cpu.keyboard.set_key(*idx, window.is_key_down(*key));
+    if let Some(path) = record {
+        if let Err(err) = fs::write(path, movie.to_bytes()) {
+            eprintln!("{}: {}", path, err);
         }
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("run: {}", message);
+    process::exit(2);
+}
 
-        window.update_with_buffer(&cpu.display.get_buffer(), WIDTH, HEIGHT).unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(10));
+//The rom and what is known about it, cartridges carry their own options
+fn load(path: &str, db: &RomDb) -> (Vec<u8>, RomInfo) {
+    let data = fs::read(path).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    if !path.ends_with(".gif") {
+        let info = db.identify(&data);
+        return (data, info);
     }
+
+    let cartridge = Cartridge::from_gif(&data).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let rom = cartridge.rom().unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let options = &cartridge.options;
+    let info = RomInfo {
+        platform: options.platform(),
+        quirks: options.quirks,
+        tickrate: Some(options.tickrate),
+        colors: Some(options.palette()),
+        ..db.identify(&rom)
+    };
+    (rom, info)
 }
 
+//Usage: run [--record FILE] [--db FILE] [ROM]
+//The rom is looked up in the bundled database, and first in the one given
+//with --db, to pick its platform, quirks, speed, keys and colors. Without a
+//rom one of the roms in ./rom is asked for
 pub fn main() {
-    let idx = rom_number();
-    let mut file = File::open(ROMS[idx]).unwrap();
-    let mut buffer: Vec<u8> = Vec::new();
-    let _ = file.read_to_end(&mut buffer);
+    let mut record = None;
+    let mut db = RomDb::bundled();
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record = Some(args.next().unwrap_or_else(|| fail("--record needs a file".to_string()))),
+            "--db" => {
+                let file = args.next().unwrap_or_else(|| fail("--db needs a file".to_string()));
+                let text = fs::read_to_string(&file).unwrap_or_else(|err| fail(format!("{}: {}", file, err)));
+                let local = RomDb::from_json(&text).unwrap_or_else(|err| fail(format!("{}: {}", file, err)));
+                db = db.with_local(local);
+            },
+            _ => path = Some(arg),
+        }
+    }
 
-    run(&buffer, ROMS[idx]);
-}
\ No newline at end of file
+    let path = path.unwrap_or_else(choose_rom);
+    let (rom, info) = load(&path, &db);
+    let title = match (&info.title, &info.author) {
+        (Some(title), Some(author)) => format!("{} by {}", title, author),
+        (Some(title), None) => title.clone(),
+        (None, _) => {
+            println!("{}: unknown rom, guessed from its code:\n{}", path, analyze(&rom).report());
+            path.clone()
+        },
+    };
+    println!("{}", title);
+
+    run(&rom, &info, &title, record.as_deref());
+}
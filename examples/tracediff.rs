@@ -0,0 +1,35 @@
+//Compares two execution traces and reports the first divergence
+//
+//Usage: tracediff <left> <right>
+//The exit code is 1 if the traces differ
+
+use chip8::diff_traces;
+use std::env;
+use std::fs;
+use std::process;
+
+fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("tracediff: {}: {}", path, err);
+        process::exit(2);
+    })
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: tracediff <left> <right>");
+        process::exit(2);
+    }
+
+    match diff_traces(&read(&args[0]), &read(&args[1])) {
+        None => println!("traces match"),
+        Some(divergence) => {
+            println!("first divergence at line {} of {} and line {} of {}, field {}",
+                divergence.left_line, args[0], divergence.right_line, args[1], divergence.field);
+            println!("< {}", divergence.left.as_deref().unwrap_or("(end of trace)"));
+            println!("> {}", divergence.right.as_deref().unwrap_or("(end of trace)"));
+            process::exit(1);
+        },
+    }
+}
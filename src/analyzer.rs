@@ -0,0 +1,281 @@
+//Static guess of the platform and quirks a rom was written for
+//
+//Only the instructions reached by the recursive disassembly are examined, so
+//sprites and other data are not mistaken for opcodes. The findings:
+//  XO-CHIP instructions (F000 NNNN, 5XY2, 5XY3, FN01, F002, FX3A, 00DN)
+//  SUPER-CHIP instructions (00FF, 00FE, 00CN, 00FB, 00FC, 00FD, FX30, FX75, FX85)
+//  roms too large for 4 KiB of memory
+//  shifts with X != Y != 0, written for the VIP where Vy is shifted into Vx
+//  shifts with Y = 0, written for interpreters that shift Vx in place
+//  FX55/FX65 followed by another FX55/FX65/FX33 without setting I, relying
+//  on the VIP increment
+//  0NNN calls to machine code, which only the VIP runs
+
+use super::disassembler::{disassemble, disassemble_recursive};
+use super::platform::Platform;
+use super::quirks::{IndexIncrement, Quirks};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+const START: u16 = 0x200;
+const SCAN_LIMIT: usize = 64;       //instructions followed after a load or store
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finding {
+    XoChipOpcode,
+    SuperChipOpcode,
+    LargeRom,
+    ShiftUsesVy,
+    ShiftInPlace,
+    IndexIncrement,
+    MachineCode,
+}
+
+impl Finding {
+    fn description(self) -> &'static str {
+        match self {
+            Finding::XoChipOpcode => "XO-CHIP instruction",
+            Finding::SuperChipOpcode => "SUPER-CHIP instruction",
+            Finding::LargeRom => "rom larger than 4 KiB of memory allows",
+            Finding::ShiftUsesVy => "shift of Vy into Vx",
+            Finding::ShiftInPlace => "shift of Vx in place",
+            Finding::IndexIncrement => "load/store reached again without setting I",
+            Finding::MachineCode => "call to machine code",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evidence {
+    pub addr: u16,
+    pub opcode: u16,
+    pub finding: Finding,
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.finding == Finding::LargeRom {
+            return write!(f, "{}", self.finding.description());
+        }
+        write!(f, "{:04x}: {:04x}  {:<20} {}", self.addr, self.opcode, disassemble(self.opcode).to_string(), self.finding.description())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub confidence: f64,                //0 to 1
+    pub evidence: Vec<Evidence>,
+    pub instructions: usize,            //instructions reached by the disassembly
+}
+
+fn is_xochip(opcode: u16) -> bool {
+    opcode == 0xf000
+        || opcode == 0xf002
+        || opcode & 0xf0ff == 0xf001
+        || opcode & 0xf0ff == 0xf03a
+        || opcode & 0xf00f == 0x5002
+        || opcode & 0xf00f == 0x5003
+        || opcode & 0xfff0 == 0x00d0
+}
+
+fn is_superchip(opcode: u16) -> bool {
+    matches!(opcode, 0x00fb..=0x00ff)
+        || opcode & 0xfff0 == 0x00c0
+        || matches!(opcode & 0xf0ff, 0xf030 | 0xf075 | 0xf085)
+}
+
+fn is_machine_code(opcode: u16) -> bool {
+    opcode & 0xf000 == 0x0000 && opcode != 0x00e0 && opcode != 0x00ee && !is_superchip(opcode) && !is_xochip(opcode)
+}
+
+fn sets_index(opcode: u16) -> bool {
+    opcode & 0xf000 == 0xa000 || opcode == 0xf000 || matches!(opcode & 0xf0ff, 0xf01e | 0xf029 | 0xf030)
+}
+
+fn transfers_memory(opcode: u16) -> bool {
+    matches!(opcode & 0xf0ff, 0xf033 | 0xf055 | 0xf065)
+}
+
+//Whether the load or store at addr reaches a memory transfer, itself too, along
+//straight code and jumps without I being set in between. Calls and returns
+//end the search because I may be set elsewhere
+fn reaches_transfer(code: &BTreeMap<u16, u16>, addr: u16) -> bool {
+    let mut visited = BTreeSet::new();
+    let mut pc = addr.wrapping_add(2);
+    for _ in 0..SCAN_LIMIT {
+        let opcode = match code.get(&pc) {
+            Some(&opcode) => opcode,
+            None => return false,
+        };
+        if !visited.insert(pc) {
+            return false;
+        }
+        if transfers_memory(opcode) {
+            return true;
+        }
+        if sets_index(opcode) || opcode & 0xf000 == 0x2000 || opcode & 0xf000 == 0xb000 || opcode == 0x00ee || opcode == 0x00fd {
+            return false;
+        }
+        pc = if opcode & 0xf000 == 0x1000 { opcode & 0x0fff } else { pc.wrapping_add(2) };
+    }
+    false
+}
+
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let listing = disassemble_recursive(rom, START);
+    let code: BTreeMap<u16, u16> = listing.lines.iter()
+        .filter(|line| line.code && line.line.bytes.len() >= 2)
+        .map(|line| (line.line.addr, u16::from(line.line.bytes[0]) << 8 | u16::from(line.line.bytes[1])))
+        .collect();
+
+    let mut evidence = Vec::new();
+    if rom.len() > Platform::Chip8.memory_size() - usize::from(START) {
+        evidence.push(Evidence { addr: START, opcode: 0, finding: Finding::LargeRom });
+    }
+    for (&addr, &opcode) in code.iter() {
+        let (x, y) = ((opcode >> 8) & 0xf, (opcode >> 4) & 0xf);
+        let finding = if is_xochip(opcode) {
+            Some(Finding::XoChipOpcode)
+        } else if is_superchip(opcode) {
+            Some(Finding::SuperChipOpcode)
+        } else if is_machine_code(opcode) {
+            Some(Finding::MachineCode)
+        } else if opcode & 0xf007 == 0x8006 && x != y {
+            Some(if y == 0 { Finding::ShiftInPlace } else { Finding::ShiftUsesVy })
+        } else if matches!(opcode & 0xf0ff, 0xf055 | 0xf065) && reaches_transfer(&code, addr) {
+            Some(Finding::IndexIncrement)
+        } else {
+            None
+        };
+        if let Some(finding) = finding {
+            evidence.push(Evidence { addr, opcode, finding });
+        }
+    }
+
+    let count = |finding: Finding| evidence.iter().filter(|evidence| evidence.finding == finding).count();
+    let xochip = count(Finding::XoChipOpcode) + count(Finding::LargeRom);
+    let superchip = count(Finding::SuperChipOpcode);
+    let (uses_vy, in_place) = (count(Finding::ShiftUsesVy), count(Finding::ShiftInPlace));
+    let increment = count(Finding::IndexIncrement);
+    let machine_code = count(Finding::MachineCode);
+
+    let (platform, mut confidence) = if xochip > 0 {
+        (Platform::XoChip, 0.6 + 0.1 * xochip.min(3) as f64)
+    } else if superchip > 0 {
+        (Platform::SuperChip, 0.6 + 0.1 * superchip.min(3) as f64)
+    } else {
+        //Nothing newer was found, the more code was seen the more it means
+        (Platform::Chip8, if code.len() >= 32 { 0.6 } else { 0.4 })
+    };
+
+    //Programs that shift in place and never rely on the increment were
+    //written for the CHIP-48 and its successors
+    let mut quirks = platform.quirks();
+    if platform == Platform::Chip8 && in_place > 0 && uses_vy == 0 && increment == 0 {
+        quirks = Quirks::chip48();
+    }
+    if uses_vy > 0 || in_place > 0 {
+        quirks.shift_uses_vy = uses_vy > in_place;
+        confidence += if uses_vy > 0 && in_place > 0 { -0.1 } else { 0.1 };
+    }
+    if increment > 0 {
+        quirks.load_store = IndexIncrement::XPlusOne;
+        confidence += if platform == Platform::SuperChip { -0.1 } else { 0.1 };
+    }
+    if machine_code > 0 {
+        //Only the VIP ran machine code, anything newer contradicts it
+        confidence += if platform == Platform::Chip8 { 0.1 } else { -0.2 };
+    }
+    if !listing.unresolved.is_empty() {
+        confidence -= 0.05;
+    }
+
+    Analysis {
+        platform,
+        quirks,
+        confidence: confidence.clamp(0.05, 0.99),
+        evidence,
+        instructions: code.len(),
+    }
+}
+
+impl Analysis {
+    //Text report with one line per evidence
+    pub fn report(&self) -> String {
+        let mut lines = vec![
+            format!("platform: {:?} ({:.0}% confidence)", self.platform, self.confidence * 100.0),
+            format!("quirks: {:?}", self.quirks),
+            format!("instructions reached: {}", self.instructions),
+        ];
+        if self.evidence.is_empty() {
+            lines.push("evidence: none".to_string());
+        } else {
+            lines.push("evidence:".to_string());
+            lines.extend(self.evidence.iter().map(|evidence| format!("  {}", evidence)));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(analysis: &Analysis) -> Vec<(u16, Finding)> {
+        analysis.evidence.iter().map(|evidence| (evidence.addr, evidence.finding)).collect()
+    }
+
+    #[test]
+    fn newer_instructions_pick_the_platform() {
+        //LD I, long 0x300; JP 204
+        let analysis = analyze(&[0xf0, 0x00, 0x03, 0x00, 0x12, 0x04]);
+        assert_eq!(analysis.platform, Platform::XoChip);
+        assert_eq!(findings(&analysis), vec![(0x200, Finding::XoChipOpcode)]);
+
+        //HIGH; JP 202
+        let analysis = analyze(&[0x00, 0xff, 0x12, 0x02]);
+        assert_eq!(analysis.platform, Platform::SuperChip);
+        assert_eq!(analysis.instructions, 2);
+
+        let analysis = analyze(&vec![0x12; 0x1000]);
+        assert_eq!((analysis.platform, findings(&analysis)), (Platform::XoChip, vec![(0x200, Finding::LargeRom)]));
+    }
+
+    #[test]
+    fn data_after_a_jump_is_not_examined() {
+        //JP 204; 00FF as sprite data; JP 204
+        let analysis = analyze(&[0x12, 0x04, 0x00, 0xff, 0x12, 0x04]);
+        assert_eq!(analysis.platform, Platform::Chip8);
+        assert!(analysis.evidence.is_empty());
+        assert_eq!(analysis.instructions, 2);
+    }
+
+    #[test]
+    fn shifts_and_transfers_pick_the_quirks() {
+        //SHR V1; JP 202
+        let analysis = analyze(&[0x81, 0x06, 0x12, 0x02]);
+        assert_eq!(findings(&analysis), vec![(0x200, Finding::ShiftInPlace)]);
+        assert_eq!(analysis.quirks, Quirks::chip48());
+
+        //LD I, 300; SHR V1, V2; LD [I], V1; LD V1, [I]; JP 208
+        let analysis = analyze(&[0xa3, 0x00, 0x81, 0x26, 0xf1, 0x55, 0xf1, 0x65, 0x12, 0x08]);
+        assert_eq!(findings(&analysis), vec![(0x202, Finding::ShiftUsesVy), (0x204, Finding::IndexIncrement)]);
+        assert!(analysis.quirks.shift_uses_vy);
+        assert_eq!(analysis.quirks.load_store, IndexIncrement::XPlusOne);
+
+        //Setting I between the transfers is no evidence
+        let analysis = analyze(&[0xa3, 0x00, 0xf1, 0x55, 0xa3, 0x00, 0xf1, 0x65, 0x12, 0x08]);
+        assert!(analysis.evidence.is_empty());
+    }
+
+    #[test]
+    fn machine_code_calls_are_reported() {
+        //SYS 234; JP 202
+        let analysis = analyze(&[0x02, 0x34, 0x12, 0x02]);
+        assert_eq!(findings(&analysis), vec![(0x200, Finding::MachineCode)]);
+        assert!(analysis.report().contains("0200: 0234"));
+    }
+}
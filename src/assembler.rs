@@ -0,0 +1,481 @@
+//CHIP-8 assembler, it accepts the mnemonics printed by the disassembler
+//
+//Source syntax, keywords are case insensitive:
+//  label:              a label, it can be followed by an instruction
+//  NAME = expr         a constant, NAME equ expr works too
+//  db expr, "text"     bytes
+//  dw expr, ...        big endian words
+//  org expr            moves to expr, the gap is filled with zeros
+//  ; comment
+//Expressions are numbers (42, 0x2a, 0b101010), labels and constants joined
+//by + and -. Lines of a disassembly listing ("0200: 00e0      CLS") are
+//accepted, the address and the bytes are skipped
+
+use super::instruction::{encode, Instruction};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+//Error in the source, lines are numbered from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+enum Operand {
+    Reg(u8),
+    Range(u8, u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Hf,
+    R,
+    Pitch,
+    Long(i64),
+    Value(i64),
+}
+
+enum Statement {
+    Instruction(String, Vec<String>),
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+//Statement placed at an address, waiting for the labels to be known
+struct Item {
+    line: usize,
+    addr: usize,
+    statement: Statement,
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' || first == '.' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+//Text before a ; that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..idx],
+            _ => {},
+        }
+    }
+    line
+}
+
+//Drops the "0200: 00e0" prefix of a listing line. Only lines with the whole
+//listing shape qualify, an address, an even number of hex digits and a known
+//mnemonic, so that a label such as "beef: db 1" is kept
+fn strip_listing(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    let is_hex = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit());
+    match trimmed.get(..5) {
+        Some(prefix) if prefix.ends_with(':') && is_hex(&prefix[..4]) => {
+            let rest = trimmed[5..].trim_start();
+            let mut words = rest.split_whitespace();
+            let bytes = words.next().unwrap_or("");
+            let mnemonic = words.next().unwrap_or("").to_uppercase();
+            let known = MNEMONICS.contains(&mnemonic.as_str()) || DIRECTIVES.contains(&mnemonic.as_str());
+            if is_hex(bytes) && bytes.len().is_multiple_of(2) && known {
+                &rest[bytes.len()..]
+            } else {
+                line
+            }
+        },
+        _ => line,
+    }
+}
+
+//Splits operands on the commas outside strings
+fn split_operands(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            },
+            ',' if !quoted => operands.push(std::mem::take(&mut current).trim().to_string()),
+            _ => current.push(c),
+        }
+    }
+    operands.push(current.trim().to_string());
+    operands
+}
+
+pub(crate) fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => digit.to_digit(16).map(|idx| idx as u8),
+        _ => None,
+    }
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>,
+}
+
+impl Assembler {
+    //Sum of terms joined by + and -
+    fn eval(&self, expr: &str) -> Result<i64, String> {
+        let expr = expr.trim();
+        if expr.is_empty() {
+            return Err("missing value".to_string());
+        }
+
+        let mut total = 0i64;
+        let mut sign = 1;
+        let mut term = String::new();
+        let mut terms = Vec::new();
+        for c in expr.chars().chain(std::iter::once('+')) {
+            if (c == '+' || c == '-') && !term.trim().is_empty() {
+                terms.push((sign, std::mem::take(&mut term)));
+                sign = if c == '-' { -1 } else { 1 };
+            } else if (c == '+' || c == '-') && term.trim().is_empty() {
+                if c == '-' {
+                    sign = -sign;
+                }
+            } else {
+                term.push(c);
+            }
+        }
+
+        for (sign, term) in terms {
+            let term = term.trim();
+            let value = match parse_number(term) {
+                Some(value) => value,
+                None if is_identifier(term) => *self.symbols.get(term)
+                    .ok_or_else(|| format!("undefined symbol '{}'", term))?,
+                None => return Err(format!("invalid value '{}'", term)),
+            };
+            total += sign * value;
+        }
+        Ok(total)
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        let upper = text.to_uppercase();
+        if let Some(reg) = parse_register(text) {
+            return Ok(Operand::Reg(reg));
+        }
+        let mut range = text.splitn(2, '-');
+        if let (Some(x), Some(y)) = (range.next().and_then(parse_register), range.next().and_then(|y| parse_register(y.trim()))) {
+            return Ok(Operand::Range(x, y));
+        }
+
+        Ok(match upper.as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::Dt,
+            "ST" => Operand::St,
+            "K" => Operand::K,
+            "F" => Operand::F,
+            "B" => Operand::B,
+            "HF" => Operand::Hf,
+            "R" => Operand::R,
+            "PITCH" => Operand::Pitch,
+            _ if upper.starts_with("LONG ") => Operand::Long(self.eval(&text[5..])?),
+            _ => Operand::Value(self.eval(text)?),
+        })
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Vec<u16>, String> {
+        let operands = operands.iter()
+            .map(|text| self.operand(text))
+            .collect::<Result<Vec<Operand>, String>>()?;
+
+        let check = |value: i64, min: i64, max: i64, what: &str| {
+            if value < min || value > max {
+                Err(format!("{} {} out of range", what, value))
+            } else {
+                Ok(value)
+            }
+        };
+        let addr = |value: i64| check(value, 0, 0xfff, "address").map(|value| value as u16);
+        let byte = |value: i64| check(value, -128, 0xff, "byte").map(|value| value as u8);
+        let nibble = |value: i64| check(value, 0, 0xf, "nibble").map(|value| value as u8);
+
+        use Operand::*;
+        let instruction = match (mnemonic, operands.as_slice()) {
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SYS", [Value(a)]) => Instruction::Sys(addr(*a)?),
+            ("JP", [Value(a)]) => Instruction::Jp(addr(*a)?),
+            ("JP", [Reg(0), Value(a)]) => Instruction::JpV0(addr(*a)?),
+            ("CALL", [Value(a)]) => Instruction::Call(addr(*a)?),
+            ("SE", [Reg(x), Reg(y)]) => Instruction::SeReg(*x, *y),
+            ("SE", [Reg(x), Value(kk)]) => Instruction::SeByte(*x, byte(*kk)?),
+            ("SNE", [Reg(x), Reg(y)]) => Instruction::SneReg(*x, *y),
+            ("SNE", [Reg(x), Value(kk)]) => Instruction::SneByte(*x, byte(*kk)?),
+            ("LD", [Reg(x), Value(kk)]) => Instruction::LdByte(*x, byte(*kk)?),
+            ("LD", [Reg(x), Reg(y)]) => Instruction::LdReg(*x, *y),
+            ("LD", [I, Value(a)]) => Instruction::LdI(addr(*a)?),
+            ("LD", [I, Long(a)]) => {
+                let a = check(*a, 0, 0xffff, "address")?;
+                return Ok(vec![encode(Instruction::LdILong), a as u16]);
+            },
+            ("LD", [Reg(x), Dt]) => Instruction::LdVxDt(*x),
+            ("LD", [Reg(x), K]) => Instruction::LdVxK(*x),
+            ("LD", [Dt, Reg(x)]) => Instruction::LdDtVx(*x),
+            ("LD", [St, Reg(x)]) => Instruction::LdStVx(*x),
+            ("LD", [F, Reg(x)]) => Instruction::LdF(*x),
+            ("LD", [B, Reg(x)]) => Instruction::LdB(*x),
+            ("LD", [IndirectI, Reg(x)]) => Instruction::LdMemVx(*x),
+            ("LD", [Reg(x), IndirectI]) => Instruction::LdVxMem(*x),
+            ("LD", [IndirectI, Range(x, y)]) => Instruction::SaveRange(*x, *y),
+            ("LD", [Range(x, y), IndirectI]) => Instruction::LoadRange(*x, *y),
+            ("LD", [Hf, Reg(x)]) => Instruction::LdHf(*x),
+            ("LD", [R, Reg(x)]) => Instruction::LdRVx(*x),
+            ("LD", [Reg(x), R]) => Instruction::LdVxR(*x),
+            ("LD", [Pitch, Reg(x)]) => Instruction::Pitch(*x),
+            ("ADD", [Reg(x), Value(kk)]) => Instruction::AddByte(*x, byte(*kk)?),
+            ("ADD", [Reg(x), Reg(y)]) => Instruction::AddReg(*x, *y),
+            ("ADD", [I, Reg(x)]) => Instruction::AddI(*x),
+            ("OR", [Reg(x), Reg(y)]) => Instruction::Or(*x, *y),
+            ("AND", [Reg(x), Reg(y)]) => Instruction::And(*x, *y),
+            ("XOR", [Reg(x), Reg(y)]) => Instruction::Xor(*x, *y),
+            ("SUB", [Reg(x), Reg(y)]) => Instruction::Sub(*x, *y),
+            ("SUBN", [Reg(x), Reg(y)]) => Instruction::Subn(*x, *y),
+            ("SHR", [Reg(x), Reg(y)]) => Instruction::Shr(*x, *y),
+            ("SHR", [Reg(x)]) => Instruction::Shr(*x, 0),
+            ("SHL", [Reg(x), Reg(y)]) => Instruction::Shl(*x, *y),
+            ("SHL", [Reg(x)]) => Instruction::Shl(*x, 0),
+            ("RND", [Reg(x), Value(kk)]) => Instruction::Rnd(*x, byte(*kk)?),
+            ("DRW", [Reg(x), Reg(y), Value(n)]) => Instruction::Drw(*x, *y, nibble(*n)?),
+            ("SKP", [Reg(x)]) => Instruction::Skp(*x),
+            ("SKNP", [Reg(x)]) => Instruction::Sknp(*x),
+            ("SCD", [Value(n)]) => Instruction::ScrollDown(nibble(*n)?),
+            ("SCU", [Value(n)]) => Instruction::ScrollUp(nibble(*n)?),
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::Low,
+            ("HIGH", []) => Instruction::High,
+            ("PLANE", [Value(n)]) => Instruction::Plane(nibble(*n)?),
+            ("AUDIO", []) => Instruction::Audio,
+            (mnemonic, _) if MNEMONICS.contains(&mnemonic) => return Err(format!("invalid operands for {}", mnemonic)),
+            (mnemonic, _) => return Err(format!("unknown instruction '{}'", mnemonic)),
+        };
+        Ok(vec![encode(instruction)])
+    }
+
+    fn data(&self, operands: &[String], words: bool) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        for operand in operands {
+            if let Some(text) = operand.strip_prefix('"') {
+                let text = text.strip_suffix('"').ok_or_else(|| format!("unterminated string {}", operand))?;
+                data.extend_from_slice(text.as_bytes());
+            } else if words {
+                let value = self.eval(operand)?;
+                if !(-0x8000..=0xffff).contains(&value) {
+                    return Err(format!("word {} out of range", value));
+                }
+                data.extend_from_slice(&(value as u16).to_be_bytes());
+            } else {
+                let value = self.eval(operand)?;
+                if !(-128..=0xff).contains(&value) {
+                    return Err(format!("byte {} out of range", value));
+                }
+                data.push(value as u8);
+            }
+        }
+        Ok(data)
+    }
+}
+
+const MNEMONICS: &[&str] = &[
+    "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN",
+    "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH",
+    "PLANE", "AUDIO",
+];
+
+const DIRECTIVES: &[&str] = &["DB", "BYTE", "DW", "WORD"];
+
+//Bytes of a string operand after its opening quote, the closing one is
+//stripped the same way data does
+fn string_len(text: &str) -> usize {
+    text.strip_suffix('"').unwrap_or(text).len()
+}
+
+//Size in bytes of a statement, known before the labels are
+fn statement_size(statement: &Statement) -> usize {
+    match statement {
+        Statement::Instruction(mnemonic, operands) => {
+            let long = mnemonic == "LD" && operands.len() == 2 && operands[1].to_uppercase().starts_with("LONG ");
+            if long { 4 } else { 2 }
+        },
+        Statement::Bytes(operands) => operands.iter()
+            .map(|operand| match operand.strip_prefix('"') {
+                Some(text) => string_len(text),
+                None => 1,
+            })
+            .sum(),
+        Statement::Words(operands) => operands.iter()
+            .map(|operand| match operand.strip_prefix('"') {
+                Some(text) => string_len(text),
+                None => 2,
+            })
+            .sum(),
+    }
+}
+
+//Assembles a program loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_at(source, 0x200)
+}
+
+//Assembles a program loaded at base, the result starts at base
+pub fn assemble_at(source: &str, base: usize) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler { symbols: HashMap::new() };
+    let mut items = Vec::new();
+    let mut addr = base;
+
+    for (idx, line) in source.lines().enumerate() {
+        let number = idx + 1;
+        let error = |message: String| AsmError { line: number, message };
+        let mut text = strip_listing(strip_comment(line)).trim();
+
+        //Labels
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if assembler.symbols.insert(label.to_string(), addr as i64).is_some() {
+                return Err(error(format!("duplicate symbol '{}'", label)));
+            }
+            text = text[colon+1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (head, rest) = match text.find(char::is_whitespace) {
+            Some(idx) => (&text[..idx], text[idx..].trim()),
+            None => (text, ""),
+        };
+
+        //Constants
+        let constant = if let Some(expr) = rest.strip_prefix('=') {
+            Some(expr)
+        } else if rest.len() > 4 && rest[..4].eq_ignore_ascii_case("equ ") {
+            Some(&rest[4..])
+        } else {
+            None
+        };
+        if let Some(expr) = constant {
+            if !is_identifier(head) {
+                return Err(error(format!("invalid constant name '{}'", head)));
+            }
+            let value = assembler.eval(expr).map_err(error)?;
+            if assembler.symbols.insert(head.to_string(), value).is_some() {
+                return Err(error(format!("duplicate symbol '{}'", head)));
+            }
+            continue;
+        }
+
+        let mnemonic = head.to_uppercase();
+        let operands = split_operands(rest);
+        let statement = match mnemonic.as_str() {
+            "ORG" => {
+                let target = assembler.eval(rest).map_err(error)?;
+                if target < addr as i64 {
+                    return Err(error(format!("org {:#x} is before the current address {:#x}", target, addr)));
+                }
+                addr = target as usize;
+                continue;
+            },
+            "DB" | "BYTE" => Statement::Bytes(operands),
+            "DW" | "WORD" => Statement::Words(operands),
+            _ => Statement::Instruction(mnemonic, operands),
+        };
+
+        let size = statement_size(&statement);
+        items.push(Item { line: number, addr, statement });
+        addr += size;
+    }
+
+    let mut rom = vec![0; addr - base];
+    for item in items {
+        let error = |message: String| AsmError { line: item.line, message };
+        let bytes = match &item.statement {
+            Statement::Instruction(mnemonic, operands) => assembler.instruction(mnemonic, operands)
+                .map_err(error)?
+                .iter()
+                .flat_map(|word| word.to_be_bytes().to_vec())
+                .collect(),
+            Statement::Bytes(operands) => assembler.data(operands, false).map_err(error)?,
+            Statement::Words(operands) => assembler.data(operands, true).map_err(error)?,
+        };
+        let start = item.addr - base;
+        rom[start..start+bytes.len()].copy_from_slice(&bytes);
+    }
+
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble_rom;
+
+    #[test]
+    fn bundled_roms_reassemble_from_their_listing() {
+        for name in ["IBM", "INVADERS", "MAZE", "MISSILE"].iter() {
+            let rom = std::fs::read(format!("{}/rom/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+            let listing: Vec<String> = disassemble_rom(&rom, 0x200).iter().map(|line| line.to_string()).collect();
+            assert_eq!(assemble(&listing.join("\n")).unwrap(), rom, "{}", name);
+        }
+    }
+
+    #[test]
+    fn quoted_strings_have_the_same_size_in_both_passes() {
+        assert_eq!(assemble("db \"ab\"\"\ndw \"c\", 1").unwrap(), b"ab\"c\x00\x01".to_vec());
+    }
+
+    #[test]
+    fn labels_that_look_like_listing_bytes_are_kept() {
+        assert_eq!(assemble("beef: db 1\ndw beef").unwrap(), vec![0x01, 0x02, 0x00]);
+        assert_eq!(assemble("cafe: dw cafe").unwrap(), vec![0x02, 0x00]);
+        assert_eq!(assemble("0200: 6001      LD   V0, 1").unwrap(), vec![0x60, 0x01]);
+    }
+}
@@ -0,0 +1,39 @@
+//XO-CHIP sound generator, the buzzer plays the 128 bit pattern in a loop
+//while the sound timer is not zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Audio {
+    pub pattern: [u8; 16],      //1 bit samples, most significant bit first
+    pub pitch: u8,              //playback rate is 4000 * 2^((pitch - 64) / 48) Hz
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Audio::new()
+    }
+}
+
+impl Audio {
+    //Square wave at the default pitch, the sound of a plain CHIP-8 buzzer
+    pub fn new() -> Self {
+        let mut pattern = [0x00; 16];
+        for byte in pattern.iter_mut().step_by(2) {
+            *byte = 0xff;
+        }
+
+        Audio {
+            pattern,
+            pitch: 64,
+        }
+    }
+
+    //Samples played per second
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((f64::from(self.pitch) - 64.0) / 48.0)
+    }
+
+    //Value of the nth sample of the pattern, n wraps around the 128 samples
+    pub fn sample(&self, n: usize) -> bool {
+        let n = n % 128;
+        (self.pattern[n / 8] & (0x80 >> (n % 8))) != 0
+    }
+}
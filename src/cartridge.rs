@@ -0,0 +1,342 @@
+//Octo cartridges, GIF images that carry the source of a program and the
+//options it runs with
+//
+//The payload is hidden in the color indexes of the frames: each pixel holds
+//a nibble in the low 4 bits of its index, two consecutive pixels a byte
+//(high nibble first). The high 4 bits of the index select the color of the
+//label, the palette maps all the indexes of a label color to almost the same
+//RGB value. The frames are read one after the other and the bytes are:
+//  length      u32 big endian
+//  json        length bytes of UTF-8 {"options": {...}, "program": "..."}
+//
+//The options use the Octo names, the ones the emulator has no use for are
+//kept so that writing a cartridge back does not lose them
+
+use super::assembler::AsmError;
+use super::cpu::{Config, Cpu};
+use super::error::LoadError;
+use super::gif;
+use super::json::Json;
+use super::octo::compile_octo;
+use super::platform::Platform;
+use super::quirks::{IndexIncrement, Quirks};
+
+use std::error::Error;
+use std::fmt;
+
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+const FRAME_DELAY: u16 = 10;
+
+//RGB of the label colors: background, body, paper and ink
+const LABEL_COLORS: [u32; 4] = [0x202020, 0x606060, 0xe8e0c8, 0x302818];
+
+//Octo quirk flags, true is the behaviour that differs from XO-CHIP
+const QUIRK_KEYS: [&str; 5] = ["shiftQuirks", "loadStoreQuirks", "jumpQuirks", "logicQuirks", "clipQuirks"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    BadGif(&'static str),
+    Truncated,                  //the image holds fewer bytes than the payload length
+    Invalid(String),            //the payload is not the JSON of a cartridge
+    Compile(AsmError),
+    Load(LoadError),            //the compiled program does not fit in memory
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::BadGif(reason) => write!(f, "invalid cartridge image: {}", reason),
+            CartridgeError::Truncated => write!(f, "cartridge payload is truncated"),
+            CartridgeError::Invalid(reason) => write!(f, "invalid cartridge payload: {}", reason),
+            CartridgeError::Compile(err) => write!(f, "cartridge program: {}", err),
+            CartridgeError::Load(err) => write!(f, "cartridge program: {}", err),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CartridgeOptions {
+    pub tickrate: usize,                //instructions per frame
+    pub fill_color: u32,                //plane 1
+    pub fill_color2: u32,               //plane 2
+    pub blend_color: u32,               //both planes
+    pub background_color: u32,
+    pub buzz_color: u32,
+    pub quiet_color: u32,
+    pub quirks: Quirks,
+    pub max_size: usize,                //largest program Octo accepts, it picks the platform
+    pub other: Vec<(String, Json)>,     //options the emulator does not use
+}
+
+//Options of a new Octo project
+impl Default for CartridgeOptions {
+    fn default() -> Self {
+        CartridgeOptions {
+            tickrate: 20,
+            fill_color: 0xffcc00,
+            fill_color2: 0xff6600,
+            blend_color: 0x662200,
+            background_color: 0x996600,
+            buzz_color: 0xffaa00,
+            quiet_color: 0x000000,
+            quirks: Quirks::xochip(),
+            max_size: 3584,
+            other: Vec::new(),
+        }
+    }
+}
+
+fn parse_color(value: &Json) -> Option<u32> {
+    let text = value.as_str()?.trim_start_matches('#');
+    match text.len() {
+        6 => u32::from_str_radix(text, 16).ok(),
+        3 => u32::from_str_radix(text, 16).ok().map(|rgb| {
+            let (r, g, b) = (rgb >> 8 & 0xf, rgb >> 4 & 0xf, rgb & 0xf);
+            (r * 0x11) << 16 | (g * 0x11) << 8 | (b * 0x11)
+        }),
+        _ => None,
+    }
+}
+
+impl CartridgeOptions {
+    //Octo names its platforms by the memory they leave to the program
+    pub fn platform(&self) -> Platform {
+        match self.max_size {
+            0..=3232 => Platform::Chip8,
+            3233..=3584 => Platform::SuperChip,
+            _ => Platform::XoChip,
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            platform: self.platform(),
+            quirks: self.quirks,
+            ..Config::new(self.platform())
+        }
+    }
+
+    //Display palette indexed by pixel value
+    pub fn palette(&self) -> [u32; 4] {
+        [self.background_color, self.fill_color, self.fill_color2, self.blend_color]
+    }
+
+    fn from_json(json: &Json) -> Result<Self, CartridgeError> {
+        let entries = match json {
+            Json::Object(entries) => entries,
+            _ => return Err(CartridgeError::Invalid("options is not an object".to_string())),
+        };
+        let invalid = |key: &str| CartridgeError::Invalid(format!("invalid option {}", key));
+        let mut options = CartridgeOptions::default();
+        let mut flags = [false; 5];
+
+        for (key, value) in entries {
+            let color = || parse_color(value).ok_or_else(|| invalid(key));
+            match key.as_str() {
+                "tickrate" => options.tickrate = value.as_f64().ok_or_else(|| invalid(key))? as usize,
+                "maxSize" => options.max_size = value.as_f64().ok_or_else(|| invalid(key))? as usize,
+                "fillColor" => options.fill_color = color()?,
+                "fillColor2" => options.fill_color2 = color()?,
+                "blendColor" => options.blend_color = color()?,
+                "backgroundColor" => options.background_color = color()?,
+                "buzzColor" => options.buzz_color = color()?,
+                "quietColor" => options.quiet_color = color()?,
+                key if QUIRK_KEYS.contains(&key) => {
+                    let idx = QUIRK_KEYS.iter().position(|&name| name == key).unwrap_or(0);
+                    flags[idx] = value.as_bool().ok_or_else(|| invalid(key))?;
+                },
+                _ => options.other.push((key.clone(), value.clone())),
+            }
+        }
+
+        options.quirks = Quirks {
+            shift_uses_vy: !flags[0],
+            load_store: if flags[1] { IndexIncrement::Unchanged } else { IndexIncrement::XPlusOne },
+            jump_uses_vx: flags[2],
+            logic_resets_vf: flags[3],
+            clip_sprites: flags[4],
+        };
+        Ok(options)
+    }
+
+    //Octo has no flag for the CHIP-48 load/store increment, it is written as
+    //the XO-CHIP one
+    fn to_json(&self) -> Json {
+        let color = |rgb: u32| Json::String(format!("#{:06X}", rgb & 0xffffff));
+        let quirks = &self.quirks;
+        let flags = [
+            !quirks.shift_uses_vy,
+            quirks.load_store == IndexIncrement::Unchanged,
+            quirks.jump_uses_vx,
+            quirks.logic_resets_vf,
+            quirks.clip_sprites,
+        ];
+
+        let mut entries = vec![
+            ("tickrate".to_string(), Json::Number(self.tickrate as f64)),
+            ("fillColor".to_string(), color(self.fill_color)),
+            ("fillColor2".to_string(), color(self.fill_color2)),
+            ("blendColor".to_string(), color(self.blend_color)),
+            ("backgroundColor".to_string(), color(self.background_color)),
+            ("buzzColor".to_string(), color(self.buzz_color)),
+            ("quietColor".to_string(), color(self.quiet_color)),
+        ];
+        entries.extend(QUIRK_KEYS.iter().zip(flags.iter()).map(|(key, &flag)| (key.to_string(), Json::Bool(flag))));
+        entries.push(("maxSize".to_string(), Json::Number(self.max_size as f64)));
+        entries.extend(self.other.iter().cloned());
+        Json::Object(entries)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cartridge {
+    pub program: String,                //Octo source
+    pub options: CartridgeOptions,
+}
+
+//Label color of every pixel: a cartridge with a paper label and contacts
+fn label() -> Vec<u8> {
+    let mut pixels = vec![0u8; WIDTH * HEIGHT];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let body = (8..WIDTH-8).contains(&x) && (4..HEIGHT-4).contains(&y)
+                && !(x >= WIDTH - 20 && y < 12);
+            let paper = (20..WIDTH-28).contains(&x) && (10..HEIGHT-18).contains(&y);
+            let lines = paper && (26..WIDTH-34).contains(&x) && (y - 10) % 8 == 5;
+            let contacts = (16..WIDTH-16).contains(&x) && (HEIGHT-12..HEIGHT-6).contains(&y) && x % 6 < 4;
+            pixels[y * WIDTH + x] = if lines || contacts {
+                3
+            } else if paper {
+                2
+            } else if body {
+                1
+            } else {
+                0
+            };
+        }
+    }
+    pixels
+}
+
+impl Cartridge {
+    pub fn new(program: &str, options: CartridgeOptions) -> Self {
+        Cartridge {
+            program: program.to_string(),
+            options,
+        }
+    }
+
+    pub fn from_gif(data: &[u8]) -> Result<Self, CartridgeError> {
+        let frames = gif::decode(data).map_err(CartridgeError::BadGif)?;
+        let nibbles: Vec<u8> = frames.iter()
+            .flat_map(|pixels| pixels.iter().map(|index| index & 0xf))
+            .collect();
+        let bytes: Vec<u8> = nibbles.chunks_exact(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect();
+
+        if bytes.len() < 4 {
+            return Err(CartridgeError::Truncated);
+        }
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let payload = bytes.get(4..4+len).ok_or(CartridgeError::Truncated)?;
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| CartridgeError::Invalid("payload is not UTF-8".to_string()))?;
+        let json = Json::parse(text).map_err(CartridgeError::Invalid)?;
+
+        let program = json.get("program")
+            .and_then(Json::as_str)
+            .ok_or_else(|| CartridgeError::Invalid("missing program".to_string()))?;
+        let options = match json.get("options") {
+            Some(options) => CartridgeOptions::from_json(options)?,
+            None => CartridgeOptions::default(),
+        };
+        Ok(Cartridge::new(program, options))
+    }
+
+    pub fn to_gif(&self) -> Vec<u8> {
+        let json = Json::Object(vec![
+            ("options".to_string(), self.options.to_json()),
+            ("program".to_string(), Json::String(self.program.clone())),
+        ]).to_string();
+        let mut bytes = (json.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(json.as_bytes());
+
+        //Colors of a label differ only in the low bit of each channel
+        let mut palette = [0u32; 256];
+        for (idx, color) in palette.iter_mut().enumerate() {
+            let nibble = (idx & 0xf) as u32;
+            let base = LABEL_COLORS[(idx >> 4) & 0x3] & 0xfefefe;
+            *color = base | (nibble & 0x1) << 16 | (nibble >> 1 & 0x1) << 8 | (nibble >> 2 & 0x1);
+        }
+
+        let label = label();
+        let per_frame = WIDTH * HEIGHT / 2;
+        let frames: Vec<Vec<u8>> = bytes.chunks(per_frame)
+            .map(|chunk| {
+                let mut pixels = label.iter().map(|color| color << 4).collect::<Vec<u8>>();
+                for (idx, byte) in chunk.iter().enumerate() {
+                    pixels[2 * idx] |= byte >> 4;
+                    pixels[2 * idx + 1] |= byte & 0xf;
+                }
+                pixels
+            })
+            .collect();
+        gif::encode(WIDTH, HEIGHT, &palette, &frames, FRAME_DELAY)
+    }
+
+    //Compiles the program, the rom starts at 0x200
+    pub fn rom(&self) -> Result<Vec<u8>, CartridgeError> {
+        compile_octo(&self.program).map_err(CartridgeError::Compile)
+    }
+
+    //Cpu running the program with the platform, quirks and colors of the options
+    pub fn cpu(&self) -> Result<Cpu, CartridgeError> {
+        let mut cpu = Cpu::try_with_config(&self.rom()?, self.options.config())
+            .map_err(CartridgeError::Load)?;
+        cpu.display.palette = self.options.palette();
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cartridge_round_trips_through_gif() {
+        let options = CartridgeOptions {
+            tickrate: 500,
+            fill_color: 0x123456,
+            quirks: Quirks::superchip(),
+            max_size: 3584,
+            other: vec![("screenRotation".to_string(), Json::Number(90.0))],
+            ..CartridgeOptions::default()
+        };
+        //Long enough to span several frames, with non-ASCII text
+        let program = format!(": main\n  v0 := 1 # caf\u{e9}\n{}  loop again\n", "  # padding\n".repeat(1000));
+        let cartridge = Cartridge::new(&program, options);
+
+        let loaded = Cartridge::from_gif(&cartridge.to_gif()).unwrap();
+        assert_eq!(loaded, cartridge);
+        assert_eq!(loaded.cpu().unwrap().platform, Platform::SuperChip);
+    }
+
+    #[test]
+    fn damaged_images_are_rejected() {
+        let gif = Cartridge::new(": main\n", CartridgeOptions::default()).to_gif();
+        assert_eq!(Cartridge::from_gif(b"GIF"), Err(CartridgeError::BadGif("not a gif")));
+        assert!(Cartridge::from_gif(&gif[..gif.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn octo_max_sizes_pick_their_platform() {
+        for &platform in &[Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            let options = CartridgeOptions { max_size: platform.octo_max_size(), ..CartridgeOptions::default() };
+            assert_eq!(options.platform(), platform);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//Code and data coverage of every memory byte
+//
+//A byte is executed when it is fetched as part of an instruction, read when
+//an instruction loads it as data (DXYN, FX65, 5XY3, F002) and written when an
+//instruction stores it (FX33, FX55, 5XY2)
+//
+//The coverage map has one line per run of bytes with the same flags:
+//  START-END FLAGS
+//with hex addresses and FLAGS made of x (executed), r (read), w (written)
+//or - when the bytes were never touched
+
+use super::disassembler::{disassemble_rom, Disassembly, Line};
+
+use std::ops::Range;
+
+pub const EXECUTED: u8 = 0x1;
+pub const READ: u8 = 0x2;
+pub const WRITTEN: u8 = 0x4;
+
+//Bytes of a range by the way they were used, a byte can be counted more than once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoverageSummary {
+    pub executed: usize,
+    pub read: usize,
+    pub written: usize,
+    pub untouched: usize,
+}
+
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+fn flag_text(flags: u8) -> String {
+    if flags == 0 {
+        return "-".to_string();
+    }
+    [(EXECUTED, 'x'), (READ, 'r'), (WRITTEN, 'w')].iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|&(_, letter)| letter)
+        .collect()
+}
+
+impl Coverage {
+    pub fn new(memory_size: usize) -> Self {
+        Coverage {
+            flags: vec![0; memory_size],
+        }
+    }
+
+    fn mark(&mut self, addr: usize, flag: u8) {
+        if let Some(flags) = self.flags.get_mut(addr) {
+            *flags |= flag;
+        }
+    }
+
+    pub fn on_execute(&mut self, addr: usize, len: usize) {
+        for addr in addr..addr+len {
+            self.mark(addr, EXECUTED);
+        }
+    }
+
+    pub fn on_read(&mut self, addr: usize) {
+        self.mark(addr, READ);
+    }
+
+    pub fn on_write(&mut self, addr: usize) {
+        self.mark(addr, WRITTEN);
+    }
+
+    //Bitmask of EXECUTED, READ and WRITTEN for every byte
+    pub fn flags(&self) -> &[u8] {
+        &self.flags
+    }
+
+    pub fn clear(&mut self) {
+        self.flags.iter_mut().for_each(|flags| *flags = 0);
+    }
+
+    fn clamp(&self, range: Range<usize>) -> Range<usize> {
+        let end = range.end.min(self.flags.len());
+        range.start.min(end)..end
+    }
+
+    pub fn summary(&self, range: Range<usize>) -> CoverageSummary {
+        let mut summary = CoverageSummary::default();
+        for &flags in self.flags[self.clamp(range)].iter() {
+            summary.executed += usize::from(flags & EXECUTED != 0);
+            summary.read += usize::from(flags & READ != 0);
+            summary.written += usize::from(flags & WRITTEN != 0);
+            summary.untouched += usize::from(flags == 0);
+        }
+        summary
+    }
+
+    //Machine readable map of range, see the top of the file
+    pub fn map(&self, range: Range<usize>) -> String {
+        let range = self.clamp(range);
+        let summary = self.summary(range.clone());
+        let mut lines = vec![format!("# executed {} read {} written {} untouched {} of {} bytes",
+            summary.executed, summary.read, summary.written, summary.untouched, range.len())];
+
+        let mut start = range.start;
+        while start < range.end {
+            let flags = self.flags[start];
+            let end = (start..range.end)
+                .find(|&addr| self.flags[addr] != flags)
+                .unwrap_or(range.end);
+            lines.push(format!("{:04x}-{:04x} {}", start, end - 1, flag_text(flags)));
+            start = end;
+        }
+        lines.join("\n")
+    }
+
+    //Disassembly of range where executed bytes are decoded as instructions and
+    //every other byte is listed as data, each line ends with its flags
+    pub fn listing(&self, memory: &[u8], range: Range<usize>) -> String {
+        let range = self.clamp(range);
+        let range = range.start..range.end.min(memory.len());
+        let executed = |addr: usize| self.flags.get(addr).is_some_and(|flags| flags & EXECUTED != 0);
+        let mut lines = Vec::new();
+
+        let mut addr = range.start;
+        while addr < range.end {
+            let line = if executed(addr) && addr + 1 < range.end && executed(addr + 1) {
+                //F000 NNNN only merges when its address was fetched too
+                let len = if addr + 3 < range.end && executed(addr + 2) && executed(addr + 3) { 4 } else { 2 };
+                disassemble_rom(&memory[addr..addr+len], addr as u16).remove(0)
+            } else {
+                let flags = self.flags[addr];
+                let len = (addr..range.end.min(addr + 4))
+                    .take_while(|&next| self.flags[next] == flags && !(executed(next) && executed(next + 1)))
+                    .count()
+                    .max(1);
+                let bytes = &memory[addr..addr+len];
+                let operands: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+                Line {
+                    addr: addr as u16,
+                    bytes: bytes.to_vec(),
+                    disassembly: Disassembly::new(u16::from(bytes[0]), "DB", &operands),
+                }
+            };
+
+            let flags = self.flags[addr..addr+line.bytes.len()]
+                .iter()
+                .fold(0, |all, &flags| all | flags);
+            lines.push(format!("{:<48} ; {}", line.to_string(), flag_text(flags)));
+            addr += line.bytes.len();
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    const PROGRAM: &[u8] = &[
+        0xa2, 0x0a,     //200: LD I, 20A
+        0xd0, 0x01,     //202: DRW V0, V0, 1
+        0xa3, 0x00,     //204: LD I, 300
+        0xf0, 0x55,     //206: LD [I], V0
+        0x12, 0x08,     //208: JP 208
+        0x80, 0x00,     //20A: sprite
+    ];
+
+    fn covered() -> Cpu {
+        let mut cpu = Cpu::new(PROGRAM);
+        cpu.coverage = Some(Coverage::new(cpu.memory.data.len()));
+        for _ in 0..6 {
+            cpu.next().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn coverage_marks_executed_read_and_written_bytes() {
+        let cpu = covered();
+        let coverage = cpu.coverage.as_ref().unwrap();
+        assert_eq!(coverage.flags()[0x300], WRITTEN);
+        assert_eq!(coverage.summary(0x200..0x302),
+            CoverageSummary { executed: 10, read: 1, written: 1, untouched: 246 });
+        assert_eq!(coverage.map(0x200..0x20c), "\
+# executed 10 read 1 written 0 untouched 1 of 12 bytes
+0200-0209 x
+020a-020a r
+020b-020b -");
+    }
+
+    #[test]
+    fn listing_decodes_executed_bytes_only() {
+        let cpu = covered();
+        let listing = cpu.coverage.as_ref().unwrap().listing(&cpu.memory.data, 0x206..0x20c);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with("; x"));
+        assert!(lines[2].contains("DB") && lines[2].ends_with("; r"));
+        assert!(lines[3].contains("DB") && lines[3].ends_with("; -"));
+    }
+}
@@ -1,13 +1,20 @@
 #![allow(dead_code)]
-extern crate rand;
 
 use super::memory::{Data, Memory};
 use super::keyboard::Keyboard;
 use super::register::Register;
-use super::display::Display;
-
-use std::thread;
-use rand::Rng;
+use super::display::{Display, PLANES};
+use super::error::{CpuError, Fault, LoadError};
+use super::instruction::{decode, Instruction};
+use super::quirks::{IndexIncrement, Quirks};
+use super::platform::Platform;
+use super::audio::Audio;
+use super::random::{Random, RandomSource};
+use super::watch::Watchpoints;
+use super::trace::Tracer;
+use super::profile::Profiler;
+use super::coverage::Coverage;
+use super::stack::{memory_slot, Stack, DEFAULT_STACK_DEPTH};
 
 const DIGITS: &[u8] = &[
     0xf0, 0x90, 0x90, 0x90, 0xf0,       //0
@@ -33,31 +40,220 @@ const DIGIT_INDEX: &[u16] = &[
     0x00, 0x05, 0x0a, 0x0f, 0x14, 0x19, 0x1e, 0x23, 0x28, 0x2d, 0x32, 0x37, 0x3c, 0x41, 0x46, 0x4b
 ];
 
+const BIG_DIGITS: &[u8] = &[
+    0xff, 0xff, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff,     //0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xff, 0xff,     //1
+    0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff,     //2
+    0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff,     //3
+    0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0x03, 0x03,     //4
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff,     //5
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff,     //6
+    0xff, 0xff, 0x03, 0x03, 0x06, 0x0c, 0x18, 0x18, 0x18, 0x18,     //7
+    0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff,     //8
+    0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff,     //9
+    0x7e, 0xff, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xc3,     //A
+    0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc,     //B
+    0x3c, 0xff, 0xc3, 0xc0, 0xc0, 0xc0, 0xc0, 0xc3, 0xff, 0x3c,     //C
+    0xfc, 0xfe, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xfe, 0xfc,     //D
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff,     //E
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xc0, 0xc0,     //F
+];
+
+//The big font is stored right after the small one
+const BIG_DIGITS_START: u16 = 0x50;
+const BIG_DIGIT_SIZE: u16 = 10;
+
+//Roms are loaded and start running there
+const PROGRAM_START: usize = 0x200;
+
+fn check_rom_size(rom: &[u8], platform: Platform) -> Result<(), LoadError> {
+    let capacity = platform.memory_size() - PROGRAM_START;
+    if rom.len() > capacity {
+        return Err(LoadError::RomTooLarge { size: rom.len(), capacity });
+    }
+    Ok(())
+}
+
+//Result of a successfully executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepInfo {
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+//Machine the cpu emulates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: Option<u64>,          //seed of the RND generator, random if None
+    pub stack_depth: usize,         //levels of the call stack
+    pub memory_stack: bool,         //keep the call stack in memory like the VIP
+}
+
+impl Config {
+    //Platform with the quirks of its reference interpreter
+    pub fn new(platform: Platform) -> Self {
+        Config {
+            platform,
+            quirks: platform.quirks(),
+            seed: None,
+            stack_depth: platform.stack_depth(),
+            memory_stack: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            platform: Platform::Chip8,
+            quirks: Quirks::default(),
+            seed: None,
+            stack_depth: DEFAULT_STACK_DEPTH,
+            memory_stack: false,
+        }
+    }
+}
+
+//Frames per second, the timers are decremented once per frame
+pub const FRAME_RATE: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 10;
+
+//Result of a 60 Hz frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub instructions: usize,        //instructions executed, less than requested if the cpu halted
+    pub display_changed: bool,      //the display has to be redrawn
+    pub sound_active: bool,         //the buzzer sounds during this frame
+    pub reached: bool,              //stopped before the until address of run_frame_until
+}
+
 pub struct Cpu {
-    pub memory: Data,
+    pub memory: Data,               //direct accesses bypass watchpoints and coverage
     pub display: Display,
     pub register: Register,
     pub keyboard: Keyboard,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub audio: Audio,
+    pub rng: Box<dyn RandomSource>,
+    pub watchpoints: Watchpoints,
+    pub tracer: Option<Tracer>,     //logs every instruction before it runs
+    pub profiler: Option<Profiler>, //counts every executed instruction and frame
+    pub coverage: Option<Coverage>, //marks every byte fetched, read or written
+    pub halted: bool,               //set by EXIT, the cpu keeps executing it
+    pub cycles: u64,                //instructions executed since reset
+    pub frames: u64,                //timer decrements since reset
+    current: StepInfo,              //instruction being executed
 }
 
 impl Cpu {
+    //The constructors that do not start with try_ panic when the rom does not
+    //fit in the memory of the platform
     pub fn new(rom: &[u8]) -> Self {
-        let mut memory = Data::new(0x1000);
-        memory.data[0..DIGITS.len()].copy_from_slice(&DIGITS[..]);
-        memory.data[0x200..0x200+rom.len()].copy_from_slice(&rom[..]);
+        Cpu::with_config(rom, Config::default())
+    }
+
+    pub fn with_quirks(rom: &[u8], quirks: Quirks) -> Self {
+        Cpu::with_config(rom, Config { quirks, ..Config::default() })
+    }
+
+    pub fn with_config(rom: &[u8], config: Config) -> Self {
+        Cpu::try_with_config(rom, config).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    //Uses a custom generator for RND, config.seed is ignored
+    pub fn with_rng(rom: &[u8], config: Config, rng: Box<dyn RandomSource>) -> Self {
+        Cpu::try_with_rng(rom, config, rng).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    //Fails with RomTooLarge when the rom does not fit in the memory of the
+    //platform
+    pub fn try_with_config(rom: &[u8], config: Config) -> Result<Self, LoadError> {
+        let rng = match config.seed {
+            Some(seed) => Random::new(seed),
+            None => Random::from_entropy(),
+        };
+        Cpu::try_with_rng(rom, config, Box::new(rng))
+    }
+
+    pub fn try_with_rng(rom: &[u8], config: Config, rng: Box<dyn RandomSource>) -> Result<Self, LoadError> {
+        check_rom_size(rom, config.platform)?;
+        let mut memory = Data::new(config.platform.memory_size());
+        let big_digits = usize::from(BIG_DIGITS_START);
+        memory.data[0..DIGITS.len()].copy_from_slice(DIGITS);
+        memory.data[big_digits..big_digits+BIG_DIGITS.len()].copy_from_slice(BIG_DIGITS);
+        memory.data[PROGRAM_START..PROGRAM_START+rom.len()].copy_from_slice(rom);
+        let mut register = Register::new();
+        register.stack = Stack::new(config.stack_depth, config.memory_stack);
 
-        Cpu {
+        Ok(Cpu {
             memory,
             display: Display::new(),
             keyboard: Keyboard::new(),
-            register: Register::new()
+            register,
+            platform: config.platform,
+            quirks: config.quirks,
+            audio: Audio::new(),
+            rng,
+            watchpoints: Watchpoints::new(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            halted: false,
+            cycles: 0,
+            frames: 0,
+            current: StepInfo { pc: 0, opcode: 0 },
+        })
+    }
+
+    fn get_next_u16(&mut self) -> Result<u16, Fault> {
+        let pc = usize::from(self.register.pc);
+        if pc + 1 >= self.memory.size() {
+            return Err(Fault::MemoryOutOfRange(pc));
+        }
+        let result = self.memory.get_u16(pc);
+        self.register.pc = self.register.pc.wrapping_add(2);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.on_execute(pc, 2);
         }
+        Ok(result)
     }
 
-    fn get_next_u16(&mut self) -> u16 {
-        let result = self.memory.get_u16(usize::from(self.register.pc));
-        self.register.pc += 2;
-        result
+    //Every data access of an instruction goes through read_u8 and write_u8
+    //so that watchpoints and coverage see it
+    fn read_u8(&mut self, addr: usize) -> Result<u8, Fault> {
+        if addr >= self.memory.size() {
+            return Err(Fault::MemoryOutOfRange(addr));
+        }
+        let value = self.memory.get_u8(addr);
+        self.watchpoints.on_read(self.current.pc, self.current.opcode, addr, value);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.on_read(addr);
+        }
+        Ok(value)
+    }
+
+    fn write_u8(&mut self, addr: usize, value: u8) -> Result<(), Fault> {
+        if addr >= self.memory.size() {
+            return Err(Fault::MemoryOutOfRange(addr));
+        }
+        let old = self.memory.get_u8(addr);
+        self.memory.set_u8(addr, value);
+        self.watchpoints.on_write(self.current.pc, self.current.opcode, addr, old, value);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.on_write(addr);
+        }
+        Ok(())
+    }
+
+    fn key_index(&self, idx: usize) -> Result<usize, Fault> {
+        let key = self.register.v[idx];
+        if usize::from(key) >= self.keyboard.state.len() {
+            return Err(Fault::InvalidKey(key));
+        }
+        Ok(usize::from(key))
     }
 
     pub fn decrement_timers(&mut self) {
@@ -67,16 +263,90 @@ impl Cpu {
         if self.register.sound > 0 {
             self.register.sound -= 1;
         }
+        self.frames += 1;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.on_frame();
+        }
+    }
+
+    //Runs one frame: executes instructions_per_frame instructions, then
+    //decrements the timers once. On error the timers are left untouched
+    pub fn run_frame(&mut self, instructions_per_frame: usize) -> Result<FrameInfo, CpuError> {
+        self.run_frame_until(instructions_per_frame, None)
+    }
+
+    //Like run_frame, but stops before executing the instruction at until. The
+    //frame is then left incomplete and the timers untouched
+    pub fn run_frame_until(&mut self, instructions_per_frame: usize, until: Option<u16>) -> Result<FrameInfo, CpuError> {
+        let mut instructions = 0;
+        let mut reached = false;
+        while instructions < instructions_per_frame && !self.halted {
+            if until == Some(self.register.pc) {
+                reached = true;
+                break;
+            }
+            self.next()?;
+            instructions += 1;
+        }
+
+        let sound_active = self.register.sound > 0;
+        if !reached {
+            self.decrement_timers();
+        }
+
+        Ok(FrameInfo {
+            instructions,
+            display_changed: self.display.take_dirty(),
+            sound_active,
+            reached,
+        })
     }
 }
 
 impl Cpu {
-    //Call subroutine at addr
+    //Call subroutine at addr, the return address is also written to memory
+    //when the stack lives there
     //Instructions:
     //  CALL
-    fn call(&mut self, addr: u16) {
-        self.register.stack.push(self.register.pc);
+    fn call(&mut self, addr: u16) -> Result<(), Fault> {
+        if self.register.stack.is_full() {
+            return Err(Fault::StackOverflow);
+        }
+        let ret = self.register.pc;
+        if self.register.stack.in_memory {
+            let slot = memory_slot(self.register.stack.len());
+            let [high, low] = ret.to_be_bytes();
+            self.write_u8(slot, high)?;
+            self.write_u8(slot + 1, low)?;
+        }
+        self.register.stack.push(ret)?;
         self.register.pc = addr;
+        Ok(())
+    }
+
+    //Return from a subroutine, to the address in memory when the stack lives
+    //there
+    //Instructions:
+    //  RET
+    fn ret(&mut self) -> Result<(), Fault> {
+        let ret = self.register.stack.pop()?;
+        self.register.pc = if self.register.stack.in_memory {
+            let slot = memory_slot(self.register.stack.len());
+            u16::from_be_bytes([self.read_u8(slot)?, self.read_u8(slot + 1)?])
+        } else {
+            ret
+        };
+        Ok(())
+    }
+
+    //Skip the next instruction, on XO-CHIP LD I, LONG is 4 bytes long
+    fn skip(&mut self) {
+        let pc = usize::from(self.register.pc);
+        let long = self.platform.has_xochip()
+            && pc + 1 < self.memory.size()
+            && self.memory.get_u16(pc) == 0xf000;
+        let len = if long { 4 } else { 2 };
+        self.register.pc = self.register.pc.wrapping_add(len);
     }
 
     //Skip next Instructions if Vx = value
@@ -85,7 +355,7 @@ impl Cpu {
     //  SE  Vx, Vy
     fn skip_if_equal(&mut self, idx: usize, value: u8) {
         if self.register.v[idx] == value {
-            self.register.pc += 2;
+            self.skip();
         }
     }
 
@@ -95,7 +365,7 @@ impl Cpu {
     //  SNE Vx, Vy
     fn skip_if_not_equal(&mut self, idx: usize, value: u8) {
         if self.register.v[idx] != value {
-            self.register.pc += 2;
+            self.skip();
         }
     }
 
@@ -103,197 +373,518 @@ impl Cpu {
     //Instructions:
     //  RND Vx, byte
     fn random(&mut self, idx: usize, byte: u8) {
-        let mut rnd = rand::thread_rng();
-        let value = rnd.gen_range(0, 256) as u8;
-        self.register.v[idx] = value & byte;
+        self.register.v[idx] = self.rng.next_u8() & byte;
     }
 
-    //Adds two values and set the carry flag
+    //Stores a + b in Vx and sets the carry flag, the flag is written last so
+    //it wins when Vx is VF
     //Instructions:
     //  ADD Vx, Vy
     //VF = carry
-    fn add(&mut self, a: u8, b: u8) -> u8 {
-        let result = a.wrapping_add(b);
-        self.register.v[0xf] = ((u16::from(a) + u16::from(b)) > 0xff) as u8;
-        result
+    fn add(&mut self, x: usize, a: u8, b: u8) {
+        let (result, carry) = a.overflowing_add(b);
+        self.register.v[x] = result;
+        self.register.v[0xf] = carry as u8;
     }
 
-    //Subtract two values and set the borrow flag
+    //Stores a - b in Vx and sets the borrow flag, the flag is written last so
+    //it wins when Vx is VF
     //Instructions:
     //  SUB Vx, Vy
     //  SUBN Vx, Vy
     //VF = NOT borrow
-    fn sub(&mut self, a: u8, b: u8) -> u8 {
-        let result = a.wrapping_sub(b);
-        self.register.v[0xf] = (a > b) as u8;
-        result
+    fn sub(&mut self, x: usize, a: u8, b: u8) {
+        let (result, borrow) = a.overflowing_sub(b);
+        self.register.v[x] = result;
+        self.register.v[0xf] = !borrow as u8;
+    }
+
+    //Performs a left shift on Vx, or on Vy when the shift quirk is enabled
+    //Instructions:
+    //  SHL Vx {, Vy}
+    //VF = msb == 1
+    fn shift_left(&mut self, x: usize, y: usize) {
+        let value = self.register.v[if self.quirks.shift_uses_vy { y } else { x }];
+        self.register.v[x] = value << 1;
+        self.register.v[0xf] = ((value & 0x80) != 0) as u8;
+    }
+
+    //Performs a right shift on Vx, or on Vy when the shift quirk is enabled
+    //Instructions:
+    //  SHR Vx {, Vy}
+    //VF = lsb == 1
+    fn shift_right(&mut self, x: usize, y: usize) {
+        let value = self.register.v[if self.quirks.shift_uses_vy { y } else { x }];
+        self.register.v[x] = value >> 1;
+        self.register.v[0xf] = ((value & 0x01) != 0) as u8;
+    }
+
+    //Applies a bitwise operation, VF is cleared when the logic quirk is enabled
+    //Instructions:
+    //  OR  Vx, Vy
+    //  AND Vx, Vy
+    //  XOR Vx, Vy
+    fn logic(&mut self, x: usize, y: usize, op: fn(u8, u8) -> u8) {
+        self.register.v[x] = op(self.register.v[x], self.register.v[y]);
+        if self.quirks.logic_resets_vf {
+            self.register.v[0xf] = 0;
+        }
     }
 
-    //Performs a left shift on a register
+    //Jumps to addr plus V0, or plus Vx when the jump quirk is enabled
     //Instructions:
-    //  SHL Vx
-    //VF = msb(Vx) == 1
-    fn shift_left(&mut self, idx: usize) {
-        self.register.v[0xf] = ((self.register.v[idx] & 0x80) != 0) as u8;
-        self.register.v[idx] <<= 1;
+    //  JP  V0, addr
+    fn jump_offset(&mut self, addr: u16) {
+        let idx = if self.quirks.jump_uses_vx { usize::from(addr >> 8) } else { 0x0 };
+        self.register.pc = u16::from(self.register.v[idx]).wrapping_add(addr);
     }
 
-    //Performs a right shift on a register
+    //Adds Vx to I, VF is set when I leaves the address space of the platform:
+    //past 0xFFF with 4 KiB of memory, past 0xFFFF on XO-CHIP
     //Instructions:
-    //  SHR Vx
-    //VF = lsb(Vx) == 1
-    fn shift_right(&mut self, idx: usize) {
-        self.register.v[0xf] = ((self.register.v[idx] & 0x01) != 0) as u8;
-        self.register.v[idx] >>= 1;
+    //  ADD I, Vx
+    fn add_index(&mut self, idx: usize) {
+        let sum = u32::from(self.register.i) + u32::from(self.register.v[idx]);
+        self.register.i = sum as u16;
+        self.register.v[0xf] = (sum >= self.platform.memory_size() as u32) as u8;
+    }
+
+    //Moves I past the registers transferred by FX55/FX65 according to the quirks
+    fn increment_index(&mut self, end: usize) {
+        let step = match self.quirks.load_store {
+            IndexIncrement::Unchanged => return,
+            IndexIncrement::X => end as u16,
+            IndexIncrement::XPlusOne => end as u16 + 1,
+        };
+        self.register.i = self.register.i.wrapping_add(step);
     }
 
     //Stores registers from V0 through Vx in memory starting at index I
     //Instructions:
     //  LD  [i], Vx
-    fn save_register(&mut self, end: usize) {
+    fn save_register(&mut self, end: usize) -> Result<(), Fault> {
         for i in 0..(end+1) {
-            self.memory.set_u8(usize::from(self.register.i) + i, self.register.v[i]);
+            self.write_u8(usize::from(self.register.i) + i, self.register.v[i])?;
         }
+        self.increment_index(end);
+        Ok(())
     }
 
     //Loads registers from V0 through Vx from memory starting at index I
     //Instructions:
     //  LD  Vx, [i]
-    fn load_register(&mut self, end: usize) {
+    fn load_register(&mut self, end: usize) -> Result<(), Fault> {
         for i in 0..(end+1) {
-            self.register.v[i] = self.memory.get_u8(usize::from(self.register.i) + i);
+            self.register.v[i] = self.read_u8(usize::from(self.register.i) + i)?;
         }
+        self.increment_index(end);
+        Ok(())
     }
 
     //Saves the BCD representation of the register Vidx in Memory[I..I+2]
     //Instructions:
     //  LD  B,  Vx
-    fn save_bcd(&mut self, idx: usize) {
+    fn save_bcd(&mut self, idx: usize) -> Result<(), Fault> {
         let pos = self.register.i as usize;
-        self.memory.set_u8(pos, self.register.v[idx] / 100);
-        self.memory.set_u8(pos + 1, (self.register.v[idx] / 10) % 10);
-        self.memory.set_u8(pos + 2, self.register.v[idx] % 10);
+        self.write_u8(pos, self.register.v[idx] / 100)?;
+        self.write_u8(pos + 1, (self.register.v[idx] / 10) % 10)?;
+        self.write_u8(pos + 2, self.register.v[idx] % 10)
+    }
+
+    //Points I to the font sprite of the digit stored in Vx
+    //Instructions:
+    //  LD  F,  Vx
+    fn load_digit(&mut self, idx: usize) -> Result<(), Fault> {
+        let digit = self.register.v[idx];
+        self.register.i = *DIGIT_INDEX.get(usize::from(digit))
+            .ok_or(Fault::InvalidDigit(digit))?;
+        Ok(())
+    }
+
+    //Points I to the big font sprite of the digit stored in Vx
+    //Instructions:
+    //  LD  HF, Vx
+    fn load_big_digit(&mut self, idx: usize) -> Result<(), Fault> {
+        let digit = self.register.v[idx];
+        if usize::from(digit) * usize::from(BIG_DIGIT_SIZE) >= BIG_DIGITS.len() {
+            return Err(Fault::InvalidDigit(digit));
+        }
+        self.register.i = BIG_DIGITS_START + u16::from(digit) * BIG_DIGIT_SIZE;
+        Ok(())
+    }
+
+    //Draws the n bytes sprite stored at I in (Vx, Vy), with n = 0 a
+    //16x16 sprite is drawn on SUPER-CHIP
+    //Instructions:
+    //  DRW Vx, Vy, nibble
+    //VF = collision
+    fn draw(&mut self, x: usize, y: usize, n: usize) -> Result<(), Fault> {
+        let wide = n == 0 && self.platform.has_superchip();
+        let len = self.display.sprite_len(if wide { 32 } else { n });
+        let start = usize::from(self.register.i);
+        if start + len > self.memory.size() {
+            return Err(Fault::MemoryOutOfRange(start + len - 1));
+        }
+
+        let x = usize::from(self.register.v[x]);
+        let y = usize::from(self.register.v[y]);
+        let sprite = (start..start+len)
+            .map(|addr| self.read_u8(addr))
+            .collect::<Result<Vec<u8>, Fault>>()?;
+        let collision = if wide {
+            self.display.draw_wide(x, y, &sprite, self.quirks.clip_sprites)
+        } else {
+            self.display.draw(x, y, &sprite, self.quirks.clip_sprites)
+        };
+        self.register.v[0xf] = collision as u8;
+        Ok(())
+    }
+
+    //Stops the program, PC is moved back so EXIT keeps being executed
+    //Instructions:
+    //  EXIT
+    fn exit(&mut self) {
+        self.halted = true;
+        self.register.pc = self.register.pc.wrapping_sub(2);
+    }
+
+    //Stores Vx through Vy in memory starting at index I, in reverse order if x > y
+    //Instructions:
+    //  LD  [I], Vx-Vy
+    fn save_range(&mut self, x: usize, y: usize) -> Result<(), Fault> {
+        let start = usize::from(self.register.i);
+        for (offset, idx) in register_range(x, y).enumerate() {
+            self.write_u8(start + offset, self.register.v[idx])?;
+        }
+        Ok(())
+    }
+
+    //Loads Vx through Vy from memory starting at index I, in reverse order if x > y
+    //Instructions:
+    //  LD  Vx-Vy, [I]
+    fn load_range(&mut self, x: usize, y: usize) -> Result<(), Fault> {
+        let start = usize::from(self.register.i);
+        for (offset, idx) in register_range(x, y).enumerate() {
+            self.register.v[idx] = self.read_u8(start + offset)?;
+        }
+        Ok(())
+    }
+
+    //Loads the 16 bit address following the instruction in I
+    //Instructions:
+    //  LD  I,  LONG nnnn
+    fn load_long(&mut self) -> Result<(), Fault> {
+        self.register.i = self.get_next_u16()?;
+        Ok(())
+    }
+
+    //Selects the drawing planes, only two planes exist
+    //Instructions:
+    //  PLANE nibble
+    fn select_planes(&mut self, planes: u8) -> Result<(), Fault> {
+        if usize::from(planes) >= 1 << PLANES {
+            return Err(Fault::UnknownOpcode);
+        }
+        self.display.select_planes(planes);
+        Ok(())
+    }
+
+    //Loads the 16 bytes audio pattern stored at I
+    //Instructions:
+    //  AUDIO
+    fn load_audio(&mut self) -> Result<(), Fault> {
+        let start = usize::from(self.register.i);
+        for offset in 0..self.audio.pattern.len() {
+            self.audio.pattern[offset] = self.read_u8(start + offset)?;
+        }
+        Ok(())
+    }
+
+    //Stores V0 through Vx in the RPL user flags
+    //Instructions:
+    //  LD  R,  Vx
+    fn save_flags(&mut self, end: usize) {
+        self.register.flags[..=end].copy_from_slice(&self.register.v[..=end]);
+    }
+
+    //Loads V0 through Vx from the RPL user flags
+    //Instructions:
+    //  LD  Vx, R
+    fn load_flags(&mut self, end: usize) {
+        self.register.v[..=end].copy_from_slice(&self.register.flags[..=end]);
     }
 
     //Skip if key Vx is pressed
     //Instructions:
     //  SKP Vx
-    fn skip_if_pressed(&mut self, idx: usize) {
-        let key = usize::from(self.register.v[idx]);
+    fn skip_if_pressed(&mut self, idx: usize) -> Result<(), Fault> {
+        let key = self.key_index(idx)?;
         if self.keyboard.state[key] {
-            self.register.pc += 2;
+            self.skip();
         }
+        Ok(())
     }
 
     //Skip if key Vx is not pressed
     //Instructions:
     //  SKNP Vx
-    fn skip_if_not_pressed(&mut self, idx: usize) {
-        let key = usize::from(self.register.v[idx]);
+    fn skip_if_not_pressed(&mut self, idx: usize) -> Result<(), Fault> {
+        let key = self.key_index(idx)?;
         if !self.keyboard.state[key] {
-            self.register.pc += 2;
+            self.skip();
         }
+        Ok(())
     }
 
-    //Wait for a key press, store the value in Vx
+    //Wait for a key press, store the value in Vx. The instruction runs again
+    //until a key is pressed
     //Instruction:
     //  LD  Vx, K
     fn wait_key(&mut self, idx: usize) {
-        self.register.pc -= 2;
-        for i in 0..0x10 {
-            if self.keyboard.state[i] {
-                self.register.v[idx] = i as u8;
-                break;
-            }
+        match self.keyboard.state.iter().position(|&pressed| pressed) {
+            Some(key) => self.register.v[idx] = key as u8,
+            None => self.register.pc = self.register.pc.wrapping_sub(2),
         }
     }
 }
 
 impl Cpu {
-    pub fn next(&mut self) {
-        let opcode = self.get_next_u16();
-        let addr = opcode & 0x0fff;
-        let nibble = opcode & 0x000f;
-        let x = ((opcode & 0x0f00) >> 8) as usize;
-        let y = ((opcode & 0x00f0) >> 4) as usize;
-        let byte = (opcode & 0x00ff) as u8;
-
-        match opcode >> 12 {
-            0x0 => {
-                match byte {
-                    0xe0 => self.display.clear(),                                       //CLS
-                    0xee => self.register.pc = self.register                            //RET
-                                                .stack.pop()
-                                                .unwrap(),
-                    _ => println!("UNKNOWN {:x}", opcode),
-                }
-            },
-
-            0x8 => {
-                match nibble {
-                    0x0 => self.register.v[x] = self.register.v[y],                     //LD    Vx, Vy
-                    0x1 => self.register.v[x] |= self.register.v[y],                    //OR    Vx, Vy
-                    0x2 => self.register.v[x] &= self.register.v[y],                    //AND   Vx, Vy
-                    0x3 => self.register.v[x] ^= self.register.v[y],                    //XOR   Vx, Vy
-                    0x4 => self.register.v[x] =                                         //ADD   Vx, Vy
-                                    self.add(self.register.v[x], self.register.v[y]),
-                    0x5 => self.register.v[x] =                                         //SUB   Vx, Vy
-                                    self.sub(self.register.v[x], self.register.v[y]),
-                    0x6 => self.shift_right(x),                                         //SHR   Vx
-                    0x7 => self.register.v[x] =                                         //SUBN  Vx, Vy
-                                    self.sub(self.register.v[y], self.register.v[x]),
-                    0xe => self.shift_left(x),                                          //SHL   Vx
-                    _ => println!("UNKNOWN {:x}", opcode),
-                }
-            },
-            
-            0xe => {
-                match byte {
-                    0x9e => self.skip_if_pressed(x),                                    //SKP   Vx
-                    0xa1 => self.skip_if_not_pressed(x),                                //SKNP  Vx
-                    _ => println!("UNKNOWN {:x}", opcode),
-                }
-            },
-
-            0xf => {
-                match byte {
-                    0x07 => self.register.v[x] = self.register.delay,                   //LD    Vx, delay
-                    0x0a => self.wait_key(x),                                           //LD    Vx, K
-                    0x15 => self.register.delay = self.register.v[x],                   //LD    delay, Vx
-                    0x18 => self.register.sound = self.register.v[x],                   //LD    sound, Vx
-                    0x1e => {                                                           //ADD   I,  Vx
-                        self.register.v[0xf] = 
-                                    ((u16::from(self.register.v[x]) + self.register.i) > 0xfff) as u8;
-                        self.register.i = self.register.i
-                                    .wrapping_add(u16::from(self.register.v[x]));
-                    },                      
-                    0x29 => self.register.i =                                           //LD    F,  Vx
-                                    DIGIT_INDEX[usize::from(self.register.v[x])],
-                    0x33 => self.save_bcd(x),                                           //LD    V,  Vx
-                    0x55 => self.save_register(x),
-                    0x65 => self.load_register(x),
-                    _ => println!("UNKNOWN {:x}", opcode),
-                }
-            }
+    //Executes the instruction pointed by PC, on failure the error reports
+    //the address and the opcode of the faulting instruction
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<StepInfo, CpuError> {
+        let pc = self.register.pc;
+        let opcode = self.get_next_u16().map_err(|fault| fault.at(pc, 0))?;
+        self.current = StepInfo { pc, opcode };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(self.cycles, pc, opcode, &self.register);
+        }
+        self.execute(opcode).map_err(|fault| fault.at(pc, opcode))?;
+        self.cycles += 1;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.on_step(pc, opcode, self.register.pc);
+        }
+
+        Ok(StepInfo { pc, opcode })
+    }
 
-            0x1 => self.register.pc = addr,                                             //JMP   addr
-            0x2 => self.call(addr),                                                     //CALL  addr
-            0x3 => self.skip_if_equal(x, byte),                                         //SE    Vx, byte
-            0x4 => self.skip_if_not_equal(x as usize, byte),                            //SNE   Vx, byte
-            0x5 => self.skip_if_equal(x, self.register.v[y]),                           //SE    Vx, Vy
-            0x6 => self.register.v[x] = byte,                                           //LD    Vx, byte
-            0x7 => self.register.v[x] = self.register.v[x]                              //ADD   Vx, byte
-                                            .wrapping_add(byte),          
-            0x9 => self.skip_if_not_equal(x, self.register.v[y]),                       //SNE   Vx, Vy
-            0xa => self.register.i = addr,                                              //LD    I,  addr
-            0xb => self.register.pc = u16::from(self.register.v[0x0])                   //JP    V0, addr
-                                            .wrapping_add(addr),
-            0xc => self.random(x, byte),                                                //RND   Vx, byte
-            0xd => self.register.v[0xf] = self.display.draw(                            //DRW   Vx, Vy, nibble
-                self.register.v[x] as usize, 
-                self.register.v[y] as usize, 
-                &self.memory.data[usize::from(self.register.i)..usize::from(self.register.i+nibble)]) as u8,
-            _ => println!("UNKNOWN {:x}", opcode),
-        }
-    }
-}
\ No newline at end of file
+    fn execute(&mut self, opcode: u16) -> Result<(), Fault> {
+        let instruction = decode(opcode).map_err(|_| Fault::UnknownOpcode)?;
+        let v = |idx: u8| usize::from(idx);
+
+        if is_superchip(instruction) && !self.platform.has_superchip()
+            || is_xochip(instruction) && !self.platform.has_xochip() {
+            return Err(Fault::UnknownOpcode);
+        }
+
+        match instruction {
+            Instruction::Sys(_) => return Err(Fault::UnknownOpcode),
+            Instruction::Cls => self.display.clear(),
+            Instruction::Ret => self.ret()?,
+            Instruction::Jp(addr) => self.register.pc = addr,
+            Instruction::Call(addr) => self.call(addr)?,
+            Instruction::SeByte(x, byte) => self.skip_if_equal(v(x), byte),
+            Instruction::SneByte(x, byte) => self.skip_if_not_equal(v(x), byte),
+            Instruction::SeReg(x, y) => self.skip_if_equal(v(x), self.register.v[v(y)]),
+            Instruction::LdByte(x, byte) => self.register.v[v(x)] = byte,
+            Instruction::AddByte(x, byte) => self.register.v[v(x)] = self.register.v[v(x)]
+                                            .wrapping_add(byte),
+            Instruction::LdReg(x, y) => self.register.v[v(x)] = self.register.v[v(y)],
+            Instruction::Or(x, y) => self.logic(v(x), v(y), |a, b| a | b),
+            Instruction::And(x, y) => self.logic(v(x), v(y), |a, b| a & b),
+            Instruction::Xor(x, y) => self.logic(v(x), v(y), |a, b| a ^ b),
+            Instruction::AddReg(x, y) => self.add(v(x), self.register.v[v(x)], self.register.v[v(y)]),
+            Instruction::Sub(x, y) => self.sub(v(x), self.register.v[v(x)], self.register.v[v(y)]),
+            Instruction::Shr(x, y) => self.shift_right(v(x), v(y)),
+            Instruction::Subn(x, y) => self.sub(v(x), self.register.v[v(y)], self.register.v[v(x)]),
+            Instruction::Shl(x, y) => self.shift_left(v(x), v(y)),
+            Instruction::SneReg(x, y) => self.skip_if_not_equal(v(x), self.register.v[v(y)]),
+            Instruction::LdI(addr) => self.register.i = addr,
+            Instruction::JpV0(addr) => self.jump_offset(addr),
+            Instruction::Rnd(x, byte) => self.random(v(x), byte),
+            Instruction::Drw(x, y, n) => self.draw(v(x), v(y), v(n))?,
+            Instruction::Skp(x) => self.skip_if_pressed(v(x))?,
+            Instruction::Sknp(x) => self.skip_if_not_pressed(v(x))?,
+            Instruction::LdVxDt(x) => self.register.v[v(x)] = self.register.delay,
+            Instruction::LdVxK(x) => self.wait_key(v(x)),
+            Instruction::LdDtVx(x) => self.register.delay = self.register.v[v(x)],
+            Instruction::LdStVx(x) => self.register.sound = self.register.v[v(x)],
+            Instruction::AddI(x) => self.add_index(v(x)),
+            Instruction::LdF(x) => self.load_digit(v(x))?,
+            Instruction::LdB(x) => self.save_bcd(v(x))?,
+            Instruction::LdMemVx(x) => self.save_register(v(x))?,
+            Instruction::LdVxMem(x) => self.load_register(v(x))?,
+            Instruction::ScrollDown(n) => self.display.scroll_down(v(n)),
+            Instruction::ScrollRight => self.display.scroll_right(4),
+            Instruction::ScrollLeft => self.display.scroll_left(4),
+            Instruction::Exit => self.exit(),
+            Instruction::Low => self.display.set_hires(false),
+            Instruction::High => self.display.set_hires(true),
+            Instruction::LdHf(x) => self.load_big_digit(v(x))?,
+            Instruction::LdRVx(x) => self.save_flags(v(x)),
+            Instruction::LdVxR(x) => self.load_flags(v(x)),
+            Instruction::ScrollUp(n) => self.display.scroll_up(v(n)),
+            Instruction::SaveRange(x, y) => self.save_range(v(x), v(y))?,
+            Instruction::LoadRange(x, y) => self.load_range(v(x), v(y))?,
+            Instruction::LdILong => self.load_long()?,
+            Instruction::Plane(n) => self.select_planes(n)?,
+            Instruction::Audio => self.load_audio()?,
+            Instruction::Pitch(x) => self.audio.pitch = self.register.v[v(x)],
+        }
+
+        Ok(())
+    }
+}
+
+fn is_superchip(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::ScrollDown(_)
+        | Instruction::ScrollRight
+        | Instruction::ScrollLeft
+        | Instruction::Exit
+        | Instruction::Low
+        | Instruction::High
+        | Instruction::LdHf(_)
+        | Instruction::LdRVx(_)
+        | Instruction::LdVxR(_))
+}
+
+fn is_xochip(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::ScrollUp(_)
+        | Instruction::SaveRange(_, _)
+        | Instruction::LoadRange(_, _)
+        | Instruction::LdILong
+        | Instruction::Plane(_)
+        | Instruction::Audio
+        | Instruction::Pitch(_))
+}
+
+//Register indexes from x to y, both included, walking backwards if x > y
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Runs every opcode of program once and returns the resulting cpu
+    fn run(program: &[u16], quirks: Quirks) -> Cpu {
+        let rom: Vec<u8> = program.iter().flat_map(|op| op.to_be_bytes().to_vec()).collect();
+        let mut cpu = Cpu::with_quirks(&rom, quirks);
+        for _ in program {
+            cpu.next().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn flag_wins_when_the_destination_is_vf() {
+        //ADD VF, VE: 0xff + 0x02 carries
+        let cpu = run(&[0x6fff, 0x6e02, 0x8fe4], Quirks::default());
+        assert_eq!(cpu.register.v[0xf], 1);
+        //SUB VF, VE: equal operands do not borrow
+        let cpu = run(&[0x6f05, 0x6e05, 0x8fe5], Quirks::default());
+        assert_eq!(cpu.register.v[0xf], 1);
+        //SUBN VF, VE: 0x03 - 0x05 borrows
+        let cpu = run(&[0x6f05, 0x6e03, 0x8fe7], Quirks::default());
+        assert_eq!(cpu.register.v[0xf], 0);
+        //SHL VF: the msb of 0x81 is shifted out
+        let cpu = run(&[0x6f81, 0x8fee], Quirks::default());
+        assert_eq!(cpu.register.v[0xf], 1);
+        //SHL VF, VE with the shift quirk: the msb of VE is clear
+        let quirks = Quirks { shift_uses_vy: true, ..Quirks::default() };
+        let cpu = run(&[0x6f81, 0x6e01, 0x8fee], quirks);
+        assert_eq!(cpu.register.v[0xf], 0);
+    }
+
+    #[test]
+    fn shift_quirk_selects_the_source_register() {
+        let program = [0x6010, 0x6103, 0x8016];
+        let cpu = run(&program, Quirks::default());
+        assert_eq!((cpu.register.v[0x0], cpu.register.v[0xf]), (0x08, 0));
+        let cpu = run(&program, Quirks { shift_uses_vy: true, ..Quirks::default() });
+        assert_eq!((cpu.register.v[0x0], cpu.register.v[0xf]), (0x01, 1));
+    }
+
+    #[test]
+    fn load_store_quirk_moves_the_index() {
+        let program = [0xa300, 0x6007, 0x6109, 0xf155];
+        for &(increment, i) in &[
+            (IndexIncrement::Unchanged, 0x300),
+            (IndexIncrement::X, 0x301),
+            (IndexIncrement::XPlusOne, 0x302),
+        ] {
+            let cpu = run(&program, Quirks { load_store: increment, ..Quirks::default() });
+            assert_eq!(cpu.register.i, i);
+            assert_eq!(&cpu.memory.data[0x300..0x302], &[0x07, 0x09]);
+        }
+    }
+
+    #[test]
+    fn jump_quirk_selects_the_offset_register() {
+        let program = [0x6001, 0x6204, 0xb210];
+        let cpu = run(&program, Quirks::default());
+        assert_eq!(cpu.register.pc, 0x211);
+        let cpu = run(&program, Quirks { jump_uses_vx: true, ..Quirks::default() });
+        assert_eq!(cpu.register.pc, 0x214);
+    }
+
+    #[test]
+    fn logic_quirk_resets_vf() {
+        let program = [0x6f05, 0x6003, 0x8011];
+        let cpu = run(&program, Quirks::default());
+        assert_eq!(cpu.register.v[0xf], 5);
+        let cpu = run(&program, Quirks { logic_resets_vf: true, ..Quirks::default() });
+        assert_eq!(cpu.register.v[0xf], 0);
+    }
+
+    #[test]
+    fn clip_quirk_stops_sprites_at_the_edge() {
+        //Draws the top row of the "0" glyph at x = 62
+        let program = [0xa000, 0x603e, 0x6100, 0xd011];
+        let cpu = run(&program, Quirks::default());
+        assert!(cpu.display.get_pixel(63, 0));
+        assert!(cpu.display.get_pixel(0, 0));
+        let cpu = run(&program, Quirks { clip_sprites: true, ..Quirks::default() });
+        assert!(cpu.display.get_pixel(63, 0));
+        assert!(!cpu.display.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn run_frame_until_stops_before_the_address() {
+        //LD V0, 0; ADD V0, 1; JP 202
+        let mut cpu = Cpu::new(&[0x60, 0x00, 0x70, 0x01, 0x12, 0x02]);
+        cpu.register.delay = 5;
+        let info = cpu.run_frame_until(10, Some(0x204)).unwrap();
+        assert_eq!((info.instructions, info.reached), (2, true));
+        assert_eq!((cpu.register.pc, cpu.register.v[0], cpu.register.delay), (0x204, 1, 5));
+
+        //Already there: nothing runs
+        let info = cpu.run_frame_until(10, Some(0x204)).unwrap();
+        assert_eq!((info.instructions, info.reached), (0, true));
+
+        let info = cpu.run_frame_until(10, Some(0x300)).unwrap();
+        assert_eq!((info.instructions, info.reached), (10, false));
+        assert_eq!(cpu.register.delay, 4);
+    }
+
+    #[test]
+    fn roms_that_do_not_fit_are_rejected() {
+        let capacity = Platform::Chip8.memory_size() - PROGRAM_START;
+        assert!(Cpu::try_with_config(&vec![0; capacity], Config::default()).is_ok());
+        let err = Cpu::try_with_config(&vec![0; capacity + 1], Config::default()).err();
+        assert_eq!(err, Some(LoadError::RomTooLarge { size: capacity + 1, capacity }));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit")]
+    fn new_panics_on_a_rom_that_does_not_fit() {
+        Cpu::new(&vec![0; 0x1000]);
+    }
+}
@@ -0,0 +1,568 @@
+use super::cpu::{Cpu, StepInfo};
+use super::disassembler::disassemble_rom;
+use super::error::CpuError;
+use super::instruction::{decode, Instruction};
+use super::memory::Memory;
+use super::register::Register;
+use super::rewind::Rewind;
+use super::watch::{WatchHit, WatchKind};
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+//Opcode pattern such as DXYN: hex digits must match, any other character is a wildcard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    mask: u16,
+    value: u16,
+    text: String,
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Option<Pattern> {
+        if text.chars().count() != 4 {
+            return None;
+        }
+
+        let mut mask = 0;
+        let mut value = 0;
+        for c in text.chars() {
+            mask <<= 4;
+            value <<= 4;
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xf;
+                value |= digit as u16;
+            } else if !c.is_ascii_alphabetic() {
+                return None;
+            }
+        }
+
+        Some(Pattern {
+            mask,
+            value,
+            text: text.to_uppercase(),
+        })
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+//Why the debugger gave control back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Step,                           //the requested steps completed
+    Breakpoint(u16),                //PC reached a breakpoint
+    Pattern(u16, Pattern),          //the instruction at PC matches an opcode pattern
+    Reached(u16),                   //PC reached the continue-until address
+    Returned,                       //the current subroutine returned
+    Limit,                          //the instruction limit was hit
+    Halted,                         //the rom executed EXIT
+    Watch(Vec<WatchHit>),           //the last instruction triggered watchpoints
+    Error(CpuError),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Step => write!(f, "step"),
+            Stop::Breakpoint(pc) => write!(f, "breakpoint at {:04x}", pc),
+            Stop::Pattern(pc, pattern) => write!(f, "opcode {} at {:04x}", pattern, pc),
+            Stop::Reached(pc) => write!(f, "reached {:04x}", pc),
+            Stop::Returned => write!(f, "returned"),
+            Stop::Limit => write!(f, "instruction limit reached"),
+            Stop::Halted => write!(f, "halted"),
+            Stop::Watch(hits) => {
+                let hits: Vec<String> = hits.iter().map(format_hit).collect();
+                write!(f, "{}", hits.join("\n"))
+            },
+            Stop::Error(err) => write!(f, "error: {}", err),
+        }
+    }
+}
+
+//Runs a cpu instruction by instruction, the timers are decremented every
+//instructions_per_frame instructions so timing matches Cpu::run_frame
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub patterns: Vec<Pattern>,
+    pub instructions_per_frame: usize,
+    pub limit: u64,                 //maximum instructions executed by a single command
+    pub rewind: Option<Rewind>,     //history for back and rewind, disabled if None
+    cycles: usize,                  //instructions executed in the current frame
+}
+
+impl Debugger {
+    pub fn new(instructions_per_frame: usize) -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            patterns: Vec::new(),
+            instructions_per_frame,
+            limit: 10_000_000,
+            rewind: None,
+            cycles: 0,
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut Cpu) -> Result<StepInfo, CpuError> {
+        let info = cpu.next()?;
+        self.cycles += 1;
+        if self.cycles >= self.instructions_per_frame {
+            self.cycles = 0;
+            cpu.decrement_timers();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.record(cpu);
+            }
+        }
+        Ok(info)
+    }
+
+    //Breakpoint or pattern matching the instruction at PC
+    fn check(&self, cpu: &Cpu) -> Option<Stop> {
+        let pc = cpu.register.pc;
+        if self.breakpoints.contains(&pc) {
+            return Some(Stop::Breakpoint(pc));
+        }
+
+        let opcode = opcode_at(cpu, pc)?;
+        self.patterns.iter()
+            .find(|pattern| pattern.matches(opcode))
+            .map(|pattern| Stop::Pattern(pc, pattern.clone()))
+    }
+
+    //Executes instructions until done returns true, the first instruction
+    //always runs so that resuming from a breakpoint makes progress
+    fn run_while<F>(&mut self, cpu: &mut Cpu, mut done: F) -> Stop
+        where F: FnMut(&Cpu) -> Option<Stop>
+    {
+        cpu.watchpoints.take_hits();
+        if let Some(rewind) = self.rewind.as_mut() {
+            if rewind.is_empty() && self.cycles == 0 {
+                rewind.record(cpu);
+            }
+        }
+
+        for count in 0..self.limit {
+            if count > 0 {
+                if let Some(stop) = self.check(cpu) {
+                    return stop;
+                }
+            }
+            if cpu.halted {
+                return Stop::Halted;
+            }
+            if let Err(err) = self.execute(cpu) {
+                return Stop::Error(err);
+            }
+            if cpu.watchpoints.has_hits() {
+                return Stop::Watch(cpu.watchpoints.take_hits());
+            }
+            if let Some(stop) = done(cpu) {
+                return stop;
+            }
+        }
+
+        Stop::Limit
+    }
+
+    pub fn step(&mut self, cpu: &mut Cpu, count: u64) -> Stop {
+        if count == 0 {
+            return Stop::Step;
+        }
+        let mut remaining = count;
+        self.run_while(cpu, |_| {
+            remaining -= 1;
+            if remaining == 0 { Some(Stop::Step) } else { None }
+        })
+    }
+
+    //Steps over CALL: the whole subroutine runs and the cpu stops after it
+    pub fn step_over(&mut self, cpu: &mut Cpu) -> Stop {
+        let pc = cpu.register.pc;
+        match opcode_at(cpu, pc).map(decode) {
+            Some(Ok(Instruction::Call(_))) => {
+                let depth = cpu.register.stack.len();
+                let ret = pc.wrapping_add(2);
+                self.run_while(cpu, |cpu| {
+                    if cpu.register.pc == ret && cpu.register.stack.len() == depth {
+                        Some(Stop::Step)
+                    } else {
+                        None
+                    }
+                })
+            },
+            _ => self.step(cpu, 1),
+        }
+    }
+
+    //Runs until the current subroutine returns
+    pub fn step_out(&mut self, cpu: &mut Cpu) -> Stop {
+        let depth = cpu.register.stack.len();
+        self.run_while(cpu, |cpu| {
+            if cpu.register.stack.len() < depth { Some(Stop::Returned) } else { None }
+        })
+    }
+
+    //Runs until a breakpoint, a fault or the instruction limit
+    pub fn resume(&mut self, cpu: &mut Cpu) -> Stop {
+        self.run_while(cpu, |_| None)
+    }
+
+    //Undoes count instructions, returns the number actually undone
+    pub fn step_back(&mut self, cpu: &mut Cpu, count: u64) -> u64 {
+        let rewind = match self.rewind.as_mut() {
+            Some(rewind) => rewind,
+            None => return 0,
+        };
+        for done in 0..count {
+            match rewind.step_back(cpu, self.instructions_per_frame) {
+                Some(cycles) => self.cycles = cycles,
+                None => return done,
+            }
+        }
+        count
+    }
+
+    //Goes back at least frames frames, returns the number actually rewound
+    pub fn rewind(&mut self, cpu: &mut Cpu, frames: u64) -> u64 {
+        let rewound = self.rewind.as_mut().and_then(|rewind| rewind.rewind(cpu, frames));
+        match rewound {
+            Some(frames) => {
+                self.cycles = 0;
+                frames
+            },
+            None => 0,
+        }
+    }
+
+    //Runs until PC reaches addr
+    pub fn run_until(&mut self, cpu: &mut Cpu, addr: u16) -> Stop {
+        self.run_while(cpu, |cpu| {
+            if cpu.register.pc == addr { Some(Stop::Reached(addr)) } else { None }
+        })
+    }
+}
+
+fn opcode_at(cpu: &Cpu, addr: u16) -> Option<u16> {
+    let addr = usize::from(addr);
+    if addr + 1 < cpu.memory.size() {
+        Some(cpu.memory.get_u16(addr))
+    } else {
+        None
+    }
+}
+
+pub fn format_hit(hit: &WatchHit) -> String {
+    let access = match hit.kind {
+        WatchKind::Read => format!("read {:02x}", hit.new),
+        WatchKind::Write | WatchKind::Change => format!("write {:02x} -> {:02x}", hit.old, hit.new),
+    };
+    format!("watch {:04x}: {} by {:04x} at {:04x}", hit.addr, access, hit.opcode, hit.pc)
+}
+
+pub fn format_registers(register: &Register) -> String {
+    let mut out = String::new();
+    for (idx, value) in register.v.iter().enumerate() {
+        out.push_str(&format!("V{:X}={:02x}", idx, value));
+        out.push(if idx % 8 == 7 { '\n' } else { ' ' });
+    }
+    out.push_str(&format!("I={:04x} PC={:04x} SP={:x} DT={:02x} ST={:02x}",
+        register.i, register.pc, register.stack.len(), register.delay, register.sound));
+    out
+}
+
+//Innermost call first, the return addresses are read from memory when the
+//stack lives there
+pub fn format_stack(cpu: &Cpu) -> String {
+    if cpu.register.stack.is_empty() {
+        return format!("empty stack ({} levels)", cpu.register.stack.depth());
+    }
+
+    let lines: Vec<String> = cpu.backtrace().iter()
+        .map(|frame| match frame.subroutine {
+            Some(subroutine) => format!("#{:<2} {:04x} called from {:04x}, return to {:04x}", frame.level, subroutine, frame.call, frame.ret),
+            None => format!("#{:<2} return to {:04x}", frame.level, frame.ret),
+        })
+        .collect();
+    lines.join("\n")
+}
+
+//Hex dump, 16 bytes per line
+pub fn format_memory(memory: &[u8], start: usize, len: usize) -> String {
+    let end = start.saturating_add(len).min(memory.len());
+    let start = start.min(end);
+    let lines: Vec<String> = memory[start..end]
+        .chunks(16)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{:04x}: {}", start + idx * 16, bytes.join(" "))
+        })
+        .collect();
+    lines.join("\n")
+}
+
+const HELP: &str = "\
+step [n]            execute n instructions (s)
+next                step over CALL (n)
+out                 run until the current subroutine returns
+continue            run until a breakpoint (c)
+until ADDR          run until PC reaches ADDR
+back [n]            undo n instructions
+rewind N            go back N frames
+break ADDR          toggle a breakpoint on ADDR (b)
+breakop PATTERN     toggle an opcode breakpoint, e.g. DXYN
+breakpoints         list the breakpoints
+watch r|w|c ADDR [LEN]
+                    watch reads, writes or value changes of LEN bytes
+unwatch ADDR        remove the watchpoints covering ADDR
+watches             list the watchpoints
+regs                dump the registers (r)
+stack               dump the call stack (bt)
+mem ADDR [LEN]      dump LEN bytes of memory (x)
+dis [ADDR] [N]      disassemble N instructions (d)
+key K up|down       release or press key K
+help                this text";
+
+//Addresses are always hex, the 0x prefix is optional
+fn parse_addr(text: &str) -> Option<usize> {
+    let hex = text.trim_start_matches("0x");
+    usize::from_str_radix(hex, 16).ok()
+}
+
+//Counts are decimal unless prefixed by 0x
+fn parse_count(text: &str) -> Option<usize> {
+    if text.starts_with("0x") {
+        parse_addr(text)
+    } else {
+        text.parse().ok()
+    }
+}
+
+impl Debugger {
+    fn describe(&self, cpu: &Cpu, stop: &Stop) -> String {
+        format!("{}\n{}", stop, self.disassemble(cpu, usize::from(cpu.register.pc), 1))
+    }
+
+    fn disassemble(&self, cpu: &Cpu, addr: usize, count: usize) -> String {
+        let addr = addr.min(cpu.memory.size());
+        let end = addr.saturating_add(count.saturating_mul(4)).min(cpu.memory.size());
+        let lines: Vec<String> = disassemble_rom(&cpu.memory.data[addr..end], addr as u16)
+            .iter()
+            .take(count)
+            .map(|line| format!("{} {}", if usize::from(line.addr) == usize::from(cpu.register.pc) { '>' } else { ' ' }, line))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn toggle_breakpoint(&mut self, addr: u16) -> String {
+        if self.breakpoints.remove(&addr) {
+            format!("breakpoint {:04x} removed", addr)
+        } else {
+            self.breakpoints.insert(addr);
+            format!("breakpoint {:04x} set", addr)
+        }
+    }
+
+    fn toggle_pattern(&mut self, pattern: Pattern) -> String {
+        if let Some(idx) = self.patterns.iter().position(|p| *p == pattern) {
+            self.patterns.remove(idx);
+            format!("opcode breakpoint {} removed", pattern)
+        } else {
+            let text = format!("opcode breakpoint {} set", pattern);
+            self.patterns.push(pattern);
+            text
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let mut lines: Vec<String> = self.breakpoints.iter()
+            .map(|addr| format!("{:04x}", addr))
+            .collect();
+        lines.extend(self.patterns.iter().map(|pattern| pattern.to_string()));
+        if lines.is_empty() {
+            "no breakpoints".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+
+    //Executes a REPL command and returns the text to show
+    pub fn command(&mut self, cpu: &mut Cpu, line: &str) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let arg = |idx: usize| args.get(idx).and_then(|arg| parse_addr(arg));
+        let count = |idx: usize| args.get(idx).and_then(|arg| parse_count(arg));
+        //Addresses past the end of memory are rejected, memory is at most 64 KiB so
+        //the ones inside fit in a u16
+        let size = cpu.memory.size();
+        let outside = |addr: usize| format!("address {:x} is outside memory ({:#x} bytes)", addr, size);
+
+        match args.first().copied().unwrap_or("") {
+            "" => String::new(),
+            "step" | "s" => {
+                let stop = self.step(cpu, count(1).unwrap_or(1) as u64);
+                self.describe(cpu, &stop)
+            },
+            "next" | "n" => {
+                let stop = self.step_over(cpu);
+                self.describe(cpu, &stop)
+            },
+            "out" | "finish" => {
+                let stop = self.step_out(cpu);
+                self.describe(cpu, &stop)
+            },
+            "continue" | "c" => {
+                let stop = self.resume(cpu);
+                self.describe(cpu, &stop)
+            },
+            "until" | "u" => match arg(1) {
+                Some(addr) if addr >= size => outside(addr),
+                Some(addr) => {
+                    let stop = self.run_until(cpu, addr as u16);
+                    self.describe(cpu, &stop)
+                },
+                None => "usage: until ADDR".to_string(),
+            },
+            "back" => {
+                if self.rewind.is_none() {
+                    return "rewind is disabled".to_string();
+                }
+                let count = count(1).unwrap_or(1) as u64;
+                let done = self.step_back(cpu, count);
+                format!("{} of {} instructions undone\n{}", done, count, self.disassemble(cpu, usize::from(cpu.register.pc), 1))
+            },
+            "rewind" => match (self.rewind.is_some(), count(1)) {
+                (false, _) => "rewind is disabled".to_string(),
+                (true, Some(frames)) => {
+                    let done = self.rewind(cpu, frames as u64);
+                    format!("rewound {} frames\n{}", done, self.disassemble(cpu, usize::from(cpu.register.pc), 1))
+                },
+                (true, None) => "usage: rewind N".to_string(),
+            },
+            "break" | "b" => match arg(1) {
+                Some(addr) if addr >= size => outside(addr),
+                Some(addr) => self.toggle_breakpoint(addr as u16),
+                None => "usage: break ADDR".to_string(),
+            },
+            "breakop" => match args.get(1).and_then(|text| Pattern::parse(text)) {
+                Some(pattern) => self.toggle_pattern(pattern),
+                None => "usage: breakop PATTERN, e.g. DXYN".to_string(),
+            },
+            "breakpoints" => self.list_breakpoints(),
+            "watch" | "w" => {
+                let kind = match args.get(1).copied() {
+                    Some("r") => Some(WatchKind::Read),
+                    Some("w") => Some(WatchKind::Write),
+                    Some("c") => Some(WatchKind::Change),
+                    _ => None,
+                };
+                match (kind, arg(2), count(3).unwrap_or(1)) {
+                    (Some(_), Some(_), 0) => "watch length must be at least 1".to_string(),
+                    (Some(_), Some(addr), len) if addr.checked_add(len).is_none_or(|end| end > size) => outside(addr.saturating_add(len - 1)),
+                    (Some(kind), Some(addr), len) => {
+                        cpu.watchpoints.add(addr..=addr + len - 1, kind);
+                        format!("watching {:04x}..={:04x}", addr, addr + len - 1)
+                    },
+                    _ => "usage: watch r|w|c ADDR [LEN]".to_string(),
+                }
+            },
+            "unwatch" => match arg(1) {
+                Some(addr) => format!("{} watchpoints removed", cpu.watchpoints.remove(addr)),
+                None => "usage: unwatch ADDR".to_string(),
+            },
+            "watches" => {
+                let lines: Vec<String> = cpu.watchpoints.list().iter()
+                    .map(|watch| format!("{:04x}..={:04x} {:?}", watch.range.start(), watch.range.end(), watch.kind))
+                    .collect();
+                if lines.is_empty() { "no watchpoints".to_string() } else { lines.join("\n") }
+            },
+            "regs" | "r" => format_registers(&cpu.register),
+            "stack" | "bt" => format_stack(cpu),
+            "mem" | "x" => match arg(1) {
+                Some(addr) if addr >= size => outside(addr),
+                Some(addr) => format_memory(&cpu.memory.data, addr, count(2).unwrap_or(0x40)),
+                None => "usage: mem ADDR [LEN]".to_string(),
+            },
+            "dis" | "d" => match arg(1).unwrap_or_else(|| usize::from(cpu.register.pc)) {
+                addr if addr >= size => outside(addr),
+                addr => self.disassemble(cpu, addr, count(2).unwrap_or(10)),
+            },
+            "key" => match (arg(1), args.get(2).copied()) {
+                (Some(key), Some(state)) if key < cpu.keyboard.state.len() && (state == "up" || state == "down") => {
+                    cpu.keyboard.set_key(key, state == "down");
+                    format!("key {:x} {}", key, state)
+                },
+                _ => "usage: key K up|down".to_string(),
+            },
+            "help" | "h" | "?" => HELP.to_string(),
+            other => format!("unknown command '{}', type help", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //LD V0, 1; CALL 208; LD V1, 2; JP 206; LD V2, 3; RET
+    const PROGRAM: &[u8] = &[0x60, 0x01, 0x22, 0x08, 0x61, 0x02, 0x12, 0x06, 0x62, 0x03, 0x00, 0xee];
+
+    fn limited() -> Debugger {
+        let mut debugger = Debugger::new(10);
+        debugger.limit = 100;
+        debugger
+    }
+
+    #[test]
+    fn breakpoints_stop_execution() {
+        let mut cpu = Cpu::new(PROGRAM);
+        let mut debugger = limited();
+        debugger.breakpoints.insert(0x204);
+        assert_eq!(debugger.resume(&mut cpu), Stop::Breakpoint(0x204));
+        assert_eq!(cpu.register.v[0x2], 3);
+        assert_eq!(cpu.register.v[0x1], 0);
+
+        //Resuming from the breakpoint makes progress
+        assert_eq!(debugger.resume(&mut cpu), Stop::Limit);
+        assert_eq!(cpu.register.v[0x1], 2);
+
+        let mut cpu = Cpu::new(PROGRAM);
+        let mut debugger = limited();
+        assert_eq!(debugger.command(&mut cpu, "break 204"), "breakpoint 0204 set");
+        assert_eq!(debugger.command(&mut cpu, "break 204"), "breakpoint 0204 removed");
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn patterns_stop_before_matching_opcodes() {
+        let mut cpu = Cpu::new(PROGRAM);
+        let mut debugger = limited();
+        let pattern = Pattern::parse("2NNN").unwrap();
+        debugger.patterns.push(pattern.clone());
+        assert_eq!(debugger.resume(&mut cpu), Stop::Pattern(0x202, pattern));
+        assert_eq!(Pattern::parse("2NN"), None);
+    }
+
+    #[test]
+    fn stepping_follows_subroutines() {
+        let mut cpu = Cpu::new(PROGRAM);
+        let mut debugger = limited();
+        assert_eq!(debugger.step(&mut cpu, 1), Stop::Step);
+        assert_eq!(debugger.step_over(&mut cpu), Stop::Step);
+        assert_eq!((cpu.register.pc, cpu.register.v[0x2]), (0x204, 3));
+
+        let mut cpu = Cpu::new(PROGRAM);
+        assert_eq!(debugger.step(&mut cpu, 2), Stop::Step);
+        assert_eq!(cpu.register.pc, 0x208);
+        assert_eq!(debugger.step_out(&mut cpu), Stop::Returned);
+        assert_eq!(cpu.register.pc, 0x204);
+        assert_eq!(debugger.run_until(&mut cpu, 0x206), Stop::Reached(0x206));
+    }
+}
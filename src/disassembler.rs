@@ -1,70 +1,379 @@
-pub fn disassemble(opcode: u16) {
-    print!("{:x}\t", opcode);
-    let x = ((opcode & 0x0f00) >> 8) as usize;
-    let y = ((opcode & 0x00f0) >> 4) as usize;
-    let addr = opcode & 0x0fff;
-    let byte = (opcode & 0x00ff) as u8;
-    let nibble = opcode & 0x000f;
-
-    match opcode >> 12 {
-        0x0 => {
-            match byte {
-                0xe0 => println!("CLS"),                                       //CLS
-                0xee => println!("RET"),
-                _ => println!("UNKNOWN {:x}", opcode),
-            }
-        },
-
-        0x8 => {
-            match nibble {
-                0x0 => println!("LD\tV{}, V{}", x, y),                     //LD    Vx, Vy
-                0x1 => println!("OR\tV{}, V{}", x, y),                    //OR    Vx, Vy
-                0x2 => println!("AND\tV{}, V{}", x, y),                    //AND   Vx, Vy
-                0x3 => println!("XOR\tV{}, V{}", x, y),                    //XOR   Vx, Vy
-                0x4 => println!("ADD\tV{}, V{}", x, y),
-                0x5 => println!("SUB\tV{}, V{}", x, y),
-                0x6 => println!("SHR\tV{}", x),                                         //SHR   Vx
-                0x7 => println!("SUBN\tV{}, V{}", x, y),
-                0xe => println!("SHR\tV{}", x),                                          //SHL   Vx
-                _ => println!("UNKNOWN {:x}", opcode),
-            }
-        },
-        
-        0xe => {
-            match byte {
-                0x9e => println!("SKP\tV{}", x),                                    //SKP   Vx
-                0xa1 => println!("SKNP\tV{}", x),                                //SKNP  Vx
-                _ => println!("UNKNOWN {:x}", opcode),
-            }
-        },
-
-        0xf => {
-            match byte {
-                0x07 => println!("LD\tV{}, delay", x),                   //LD    Vx, delay
-                0x0a => println!("LD\tV{}, K", x),                                           //LD    Vx, K
-                0x15 => println!("LD\tdelay, V{}", x),                   //LD    delay, Vx
-                0x18 => println!("LD\tsound, V{}", x),                   //LD    sound, Vx
-                0x1e => println!("ADD\tI, V{}", x),
-                0x29 => println!("LD\tF, V{}", x),
-                0x33 => println!("LD\tB, V{}", x),                                           //LD    B,  Vx
-                0x55 => println!("LD\t[I], V{}", x),
-                0x65 => println!("LD\tV{}, [I]", x),
-                _ => println!("UNKNOWN {:x}", opcode),
-            }
+use super::instruction::{decode, Instruction};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+//Textual form of a single instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub opcode: u16,
+    pub mnemonic: &'static str,
+    pub operands: Vec<String>,
+}
+
+impl Disassembly {
+    pub(crate) fn new(opcode: u16, mnemonic: &'static str, operands: &[String]) -> Self {
+        Disassembly {
+            opcode,
+            mnemonic,
+            operands: operands.to_vec(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{:<4} {}", self.mnemonic, self.operands.join(", "))
         }
+    }
+}
+
+//Disassembled instruction labelled with its address and raw bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub disassembly: Disassembly,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: String = self.bytes.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        write!(f, "{:04x}: {:<8}  {}", self.addr, bytes, self.disassembly)
+    }
+}
+
+fn reg(idx: u8) -> String {
+    format!("V{:X}", idx)
+}
+
+fn range(x: u8, y: u8) -> String {
+    format!("V{:X}-V{:X}", x, y)
+}
+
+fn addr(addr: u16) -> String {
+    format!("{:#05x}", addr)
+}
 
-        0x1 => println!("JMP\t{}", addr),                                             //JMP   addr
-        0x2 => println!("CALL\t{}", addr),                                                     //CALL  addr
-        0x3 => println!("SE\tV{}, {}", x, byte),                                         //SE    Vx, byte
-        0x4 => println!("SNE\tV{}, {}", x, byte),                            //SNE   Vx, byte
-        0x5 => println!("SE\tV{}, V{}", x, y),                           //SE    Vx, Vy
-        0x6 => println!("LD\tV{}, {}", x, byte),                                           //LD    Vx, byte
-        0x7 => println!("ADD\tV{}, {}", x, byte),          
-        0x9 => println!("SNE\tV{}, V{}", x, y),                           //SNE   Vx, Vy
-        0xa => println!("LD\tI, {}", addr),                                              //LD    I,  addr
-        0xb => println!("JP\tV0, {}", addr),
-        0xc => println!("RND\tV{}, {}", x, byte),                                                //RND   Vx, byte
-        0xd => println!("DRW\t{}, {}, {}", x, y, nibble),
-        _ => println!("UNKNOWN {:x}", opcode),
+fn byte(byte: u8) -> String {
+    byte.to_string()
+}
+
+fn text(value: &str) -> String {
+    value.to_string()
+}
+
+pub fn disassemble(opcode: u16) -> Disassembly {
+    match decode(opcode) {
+        Ok(instruction) => disassemble_instruction(opcode, instruction),
+        Err(_) => Disassembly::new(opcode, "DW", &[format!("{:#06x}", opcode)]),
+    }
+}
+
+fn disassemble_instruction(opcode: u16, instruction: Instruction) -> Disassembly {
+    let d = |mnemonic: &'static str, operands: &[String]| Disassembly::new(opcode, mnemonic, operands);
+
+    match instruction {
+        Instruction::Sys(a) => d("SYS", &[addr(a)]),
+        Instruction::Cls => d("CLS", &[]),
+        Instruction::Ret => d("RET", &[]),
+        Instruction::Jp(a) => d("JP", &[addr(a)]),
+        Instruction::Call(a) => d("CALL", &[addr(a)]),
+        Instruction::SeByte(x, kk) => d("SE", &[reg(x), byte(kk)]),
+        Instruction::SneByte(x, kk) => d("SNE", &[reg(x), byte(kk)]),
+        Instruction::SeReg(x, y) => d("SE", &[reg(x), reg(y)]),
+        Instruction::LdByte(x, kk) => d("LD", &[reg(x), byte(kk)]),
+        Instruction::AddByte(x, kk) => d("ADD", &[reg(x), byte(kk)]),
+        Instruction::LdReg(x, y) => d("LD", &[reg(x), reg(y)]),
+        Instruction::Or(x, y) => d("OR", &[reg(x), reg(y)]),
+        Instruction::And(x, y) => d("AND", &[reg(x), reg(y)]),
+        Instruction::Xor(x, y) => d("XOR", &[reg(x), reg(y)]),
+        Instruction::AddReg(x, y) => d("ADD", &[reg(x), reg(y)]),
+        Instruction::Sub(x, y) => d("SUB", &[reg(x), reg(y)]),
+        Instruction::Shr(x, y) => d("SHR", &[reg(x), reg(y)]),
+        Instruction::Subn(x, y) => d("SUBN", &[reg(x), reg(y)]),
+        Instruction::Shl(x, y) => d("SHL", &[reg(x), reg(y)]),
+        Instruction::SneReg(x, y) => d("SNE", &[reg(x), reg(y)]),
+        Instruction::LdI(a) => d("LD", &[text("I"), addr(a)]),
+        Instruction::JpV0(a) => d("JP", &[reg(0), addr(a)]),
+        Instruction::Rnd(x, kk) => d("RND", &[reg(x), byte(kk)]),
+        Instruction::Drw(x, y, n) => d("DRW", &[reg(x), reg(y), byte(n)]),
+        Instruction::Skp(x) => d("SKP", &[reg(x)]),
+        Instruction::Sknp(x) => d("SKNP", &[reg(x)]),
+        Instruction::LdVxDt(x) => d("LD", &[reg(x), text("DT")]),
+        Instruction::LdVxK(x) => d("LD", &[reg(x), text("K")]),
+        Instruction::LdDtVx(x) => d("LD", &[text("DT"), reg(x)]),
+        Instruction::LdStVx(x) => d("LD", &[text("ST"), reg(x)]),
+        Instruction::AddI(x) => d("ADD", &[text("I"), reg(x)]),
+        Instruction::LdF(x) => d("LD", &[text("F"), reg(x)]),
+        Instruction::LdB(x) => d("LD", &[text("B"), reg(x)]),
+        Instruction::LdMemVx(x) => d("LD", &[text("[I]"), reg(x)]),
+        Instruction::LdVxMem(x) => d("LD", &[reg(x), text("[I]")]),
+        Instruction::ScrollDown(n) => d("SCD", &[byte(n)]),
+        Instruction::ScrollRight => d("SCR", &[]),
+        Instruction::ScrollLeft => d("SCL", &[]),
+        Instruction::Exit => d("EXIT", &[]),
+        Instruction::Low => d("LOW", &[]),
+        Instruction::High => d("HIGH", &[]),
+        Instruction::LdHf(x) => d("LD", &[text("HF"), reg(x)]),
+        Instruction::LdRVx(x) => d("LD", &[text("R"), reg(x)]),
+        Instruction::LdVxR(x) => d("LD", &[reg(x), text("R")]),
+        Instruction::ScrollUp(n) => d("SCU", &[byte(n)]),
+        Instruction::SaveRange(x, y) => d("LD", &[text("[I]"), range(x, y)]),
+        Instruction::LoadRange(x, y) => d("LD", &[range(x, y), text("[I]")]),
+        Instruction::LdILong => d("LD", &[text("I"), text("LONG")]),
+        Instruction::Plane(n) => d("PLANE", &[byte(n)]),
+        Instruction::Audio => d("AUDIO", &[]),
+        Instruction::Pitch(x) => d("LD", &[text("PITCH"), reg(x)]),
+    }
+}
+
+//Linear disassembly of a rom loaded at base, a trailing odd byte is emitted as data
+//The address of LD I, LONG is merged in the same line
+pub fn disassemble_rom(rom: &[u8], base: u16) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(rom.len() / 2 + 1);
+    let mut pos = 0;
+
+    while pos < rom.len() {
+        let addr = base.wrapping_add(pos as u16);
+        let (len, disassembly) = match rom[pos..] {
+            [0xf0, 0x00, high, low, ..] => {
+                let target = u16::from(high) << 8 | u16::from(low);
+                (4, Disassembly::new(0xf000, "LD", &[text("I"), format!("LONG {:#06x}", target)]))
+            },
+            [high, low, ..] => (2, disassemble(u16::from(high) << 8 | u16::from(low))),
+            [value] => (1, Disassembly::new(u16::from(value), "DB", &[format!("{:#04x}", value)])),
+            [] => unreachable!(),
+        };
+
+        lines.push(Line {
+            addr,
+            bytes: rom[pos..pos+len].to_vec(),
+            disassembly,
+        });
+        pos += len;
+    }
+
+    lines
+}
+
+//Line of a recursive disassembly, with the label of its address if it is a
+//branch or call target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub label: Option<String>,
+    pub line: Line,
+    pub code: bool,                     //reached by the control flow, data otherwise
+    pub comment: Option<&'static str>,
+}
+
+impl fmt::Display for ListingLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(label) = &self.label {
+            writeln!(f, "{}:", label)?;
+        }
+        match self.comment {
+            Some(comment) => write!(f, "{:<40} ; {}", self.line.to_string(), comment),
+            None => write!(f, "{}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub labels: BTreeMap<u16, String>,
+    pub unresolved: Vec<u16>,           //addresses of BNNN jumps, their target is only known at run time
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines.iter() {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+//Where execution can continue after an instruction
+enum Flow {
+    Next,                               //falls through
+    Skip,                               //falls through or skips the next instruction
+    Jump(u16),
+    Call(u16),
+    Stop,                               //RET, EXIT, unknown opcodes
+    Unresolved,                         //BNNN
+}
+
+fn flow(instruction: Instruction) -> Flow {
+    match instruction {
+        Instruction::Jp(a) => Flow::Jump(a),
+        Instruction::Call(a) => Flow::Call(a),
+        Instruction::Ret | Instruction::Exit => Flow::Stop,
+        Instruction::JpV0(_) => Flow::Unresolved,
+        Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..) | Instruction::SneReg(..)
+            | Instruction::Skp(_) | Instruction::Sknp(_) => Flow::Skip,
+        _ => Flow::Next,
+    }
+}
+
+fn data_line(rom: &[u8], base: u16, pos: usize, len: usize) -> Line {
+    let bytes = &rom[pos..pos+len];
+    let operands: Vec<String> = bytes.iter().map(|value| format!("{:#04x}", value)).collect();
+    Line {
+        addr: base.wrapping_add(pos as u16),
+        bytes: bytes.to_vec(),
+        disassembly: Disassembly::new(u16::from(bytes[0]), "DB", &operands),
+    }
+}
+
+//Recursive descent disassembly of a rom loaded at base: starting from base it
+//follows jumps, calls and both sides of skips. Bytes never reached are data,
+//listed 4 per line. Jumps to rom addresses use loc_ labels, calls sub_ labels
+pub fn disassemble_recursive(rom: &[u8], base: u16) -> Listing {
+    let end = usize::from(base) + rom.len();
+    let in_rom = |addr: u16| usize::from(addr) >= usize::from(base) && usize::from(addr) < end;
+    let mut code: BTreeMap<usize, usize> = BTreeMap::new();      //rom offset of every instruction, length
+    let mut claimed: BTreeSet<usize> = BTreeSet::new();
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+    let mut unresolved = Vec::new();
+    let mut pending = vec![base];
+
+    while let Some(addr) = pending.pop() {
+        let pos = usize::from(addr.wrapping_sub(base));
+        if !in_rom(addr) || pos + 1 >= rom.len() || claimed.contains(&pos) || claimed.contains(&(pos + 1)) {
+            continue;
+        }
+
+        let opcode = u16::from(rom[pos]) << 8 | u16::from(rom[pos + 1]);
+        let instruction = match decode(opcode) {
+            Ok(instruction) => instruction,
+            Err(_) => continue,
+        };
+        let len = if opcode == 0xf000 && pos + 3 < rom.len() { 4 } else { 2 };
+        if (pos..pos+len).any(|pos| claimed.contains(&pos)) {
+            continue;
+        }
+        code.insert(pos, len);
+        claimed.extend(pos..pos+len);
+
+        let next = addr.wrapping_add(len as u16);
+        match flow(instruction) {
+            Flow::Next => pending.push(next),
+            Flow::Skip => {
+                //XO-CHIP skips the whole 4 bytes of F000 NNNN
+                let skipped = match rom.get(usize::from(next.wrapping_sub(base))..) {
+                    Some([0xf0, 0x00, ..]) => 4,
+                    _ => 2,
+                };
+                pending.push(next.wrapping_add(skipped));
+                pending.push(next);
+            },
+            Flow::Jump(target) => {
+                if in_rom(target) {
+                    labels.entry(target).or_insert_with(|| format!("loc_{:04x}", target));
+                }
+                pending.push(target);
+            },
+            Flow::Call(target) => {
+                if in_rom(target) {
+                    labels.insert(target, format!("sub_{:04x}", target));
+                }
+                pending.push(target);
+                pending.push(next);
+            },
+            Flow::Stop => {},
+            Flow::Unresolved => unresolved.push(addr),
+        }
+    }
+
+    //A target inside an instruction has no line to put its label on, the
+    //branches to it keep their numeric operand
+    labels.retain(|&addr, _| {
+        let pos = usize::from(addr.wrapping_sub(base));
+        code.range(..pos).next_back().is_none_or(|(&start, &len)| start + len <= pos)
+    });
+
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let addr = base.wrapping_add(pos as u16);
+        let (line, is_code) = match code.get(&pos) {
+            Some(&len) => {
+                let mut line = disassemble_rom(&rom[pos..pos+len], addr).remove(0);
+                let operand = match decode(line.disassembly.opcode) {
+                    Ok(Instruction::Jp(target)) | Ok(Instruction::Call(target)) => labels.get(&target),
+                    _ => None,
+                };
+                if let Some(label) = operand {
+                    line.disassembly.operands = vec![label.clone()];
+                }
+                (line, true)
+            },
+            None => {
+                //Data lines end before code and labelled addresses
+                let len = (pos..rom.len().min(pos + 4))
+                    .take_while(|&next| !claimed.contains(&next)
+                        && (next == pos || !labels.contains_key(&base.wrapping_add(next as u16))))
+                    .count();
+                (data_line(rom, base, pos, len), false)
+            },
+        };
+
+        pos += line.bytes.len();
+        lines.push(ListingLine {
+            label: labels.get(&addr).cloned(),
+            comment: if unresolved.contains(&addr) { Some("unresolved computed jump") } else { None },
+            code: is_code,
+            line,
+        });
+    }
+
+    unresolved.sort_unstable();
+    Listing { lines, labels, unresolved }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn every_label_is_emitted_or_replaced_by_its_address() {
+        //JP into data that does not decode
+        let rom = [0x12, 0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0xe0];
+        let listing = disassemble_recursive(&rom, 0x200);
+        assert_eq!(listing.lines[0].line.disassembly.operands, vec!["loc_0203".to_string()]);
+        let labelled: Vec<(u16, usize)> = listing.lines.iter()
+            .filter(|line| line.label.is_some())
+            .map(|line| (line.line.addr, line.line.bytes.len()))
+            .collect();
+        assert_eq!(labelled, vec![(0x203, 4)]);
+        assert_eq!(assemble(&listing.to_string()).unwrap(), rom.to_vec());
+
+        //JP into the middle of the jump itself
+        let rom = [0x12, 0x01, 0x00, 0xe0];
+        let listing = disassemble_recursive(&rom, 0x200);
+        assert!(listing.labels.is_empty());
+        assert_eq!(listing.lines[0].line.disassembly.operands, vec!["0x201".to_string()]);
+        assert_eq!(assemble(&listing.to_string()).unwrap(), rom.to_vec());
+    }
+
+    #[test]
+    fn bundled_roms_round_trip_through_the_recursive_listing() {
+        for name in ["IBM", "INVADERS", "MAZE", "MISSILE"].iter() {
+            let rom = std::fs::read(format!("{}/rom/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+            let listing = disassemble_recursive(&rom, 0x200);
+            assert_eq!(assemble(&listing.to_string()).unwrap(), rom, "{}", name);
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,52 +1,185 @@
 #![allow(dead_code)]
 
-use super::bit;
-
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+pub const PLANES: usize = 2;
 const WHITE: u32 = 0xffffff;
 const BLACK: u32 = 0x000000;
+const LIGHT_GRAY: u32 = 0xaaaaaa;
+const DARK_GRAY: u32 = 0x555555;
 
+//Every pixel is a bitmask of the planes it is lit in, the two XO-CHIP
+//planes compose into 4 colors
 pub struct Display {
-    memory: Box<Vec<bool>>,
+    memory: Vec<u8>,
+    width: usize,
+    height: usize,
+    planes: u8,                 //bitmask of the planes affected by drawing, clearing and scrolling
+    pub palette: [u32; 4],      //color of each plane combination, indexed by pixel value
+    dirty: bool,                //pixels changed since the last take_dirty
+}
+
+fn dimensions(hires: bool) -> (usize, usize) {
+    if hires {
+        (HIRES_WIDTH, HIRES_HEIGHT)
+    } else {
+        (LORES_WIDTH, LORES_HEIGHT)
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
 }
 
 impl Display {
     pub fn new() -> Self {
         Display {
-            memory: Box::new(vec![false; WIDTH * HEIGHT])
+            memory: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            planes: 0x1,
+            palette: [BLACK, WHITE, LIGHT_GRAY, DARK_GRAY],
+            dirty: true,
         }
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    //Switch between the 64x32 and the 128x64 mode, every plane is cleared
+    //Instructions:
+    //  LOW
+    //  HIGH
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = dimensions(hires);
+        self.width = width;
+        self.height = height;
+        self.memory = vec![0; width * height];
+        self.dirty = true;
+    }
+
+    //True if the pixels changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    //Select the planes affected by the following operations
+    //Instructions:
+    //  PLANE nibble
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ((1 << PLANES) - 1);
+    }
+
+    //True if the pixel is lit in any plane
     pub fn get_pixel(&self, x: usize, y: usize) -> bool{
-        self.memory[y * WIDTH + x]
+        self.memory[y * self.width + x] != 0
     }
 
+    //Lights or clears the pixel in the selected planes
     pub fn set_pixel(&mut self, x: usize, y: usize, value: bool) {
-        self.memory[y * WIDTH + x] = value
+        self.dirty = true;
+        let pixel = &mut self.memory[y * self.width + x];
+        if value {
+            *pixel |= self.planes;
+        } else {
+            *pixel &= !self.planes;
+        }
+    }
+
+    //Bitmask of the planes the pixel is lit in
+    pub fn get_planes(&self, x: usize, y: usize) -> u8 {
+        self.memory[y * self.width + x]
     }
 
     pub fn clear(&mut self) {
-        for i in 0..WIDTH*HEIGHT {
-            self.memory[i] = false;
+        let planes = self.planes;
+        for pixel in self.memory.iter_mut() {
+            *pixel &= !planes;
         }
+        self.dirty = true;
     }
-    
-    //Draw a sprite of n bytes, if there is a collision VF is set to 1
+
+    //Number of bytes a sprite of the given size reads from memory: one copy
+    //of the sprite for every selected plane
+    pub fn sprite_len(&self, size: usize) -> usize {
+        size * self.planes.count_ones() as usize
+    }
+
+    //Draw a sprite of n bytes per selected plane, if there is a collision VF is set to 1
+    //The origin always wraps, the sprite is clipped at the edges when clip is set
     //Instructions:
     //  DRW Vx, Vy, nibble
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        let rows: Vec<u16> = sprite.iter()
+            .map(|&byte| u16::from(byte) << 8)
+            .collect();
+        self.draw_planes(x, y, &rows, 8, clip)
+    }
+
+    //Draw a 16x16 sprite stored as 32 bytes per selected plane, two bytes per row
+    //Instructions:
+    //  DRW Vx, Vy, 0
+    pub fn draw_wide(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        let rows: Vec<u16> = sprite.chunks(2)
+            .map(|row| u16::from(row[0]) << 8 | u16::from(*row.get(1).unwrap_or(&0)))
+            .collect();
+        self.draw_planes(x, y, &rows, 16, clip)
+    }
+
+    //The rows are split evenly between the selected planes, lowest plane first
+    fn draw_planes(&mut self, x: usize, y: usize, rows: &[u16], width: usize, clip: bool) -> bool {
+        let planes = self.planes;
+        let count = planes.count_ones() as usize;
+        if count == 0 {
+            return false;
+        }
+
+        self.dirty = true;
+        let size = (rows.len() / count).max(1);
         let mut collision = false;
-        for i in 0..sprite.len() {
-            let byte = sprite[i];
-            for j in 0..8 {
-                let value = bit::get(byte, 7 - j);
-                if value {
-                    let posx = (j + x) % WIDTH;
-                    let posy = (i + y) % HEIGHT;
-                    let old_value = self.get_pixel(posx, posy);
-                    collision |= old_value & value;
-                    self.set_pixel(posx, posy, value ^ old_value);
+        let selected = (0..PLANES).map(|p| 1 << p).filter(|plane| planes & plane != 0);
+        for (plane, rows) in selected.zip(rows.chunks(size)) {
+            collision |= self.draw_rows(x, y, rows, width, plane, clip);
+        }
+
+        collision
+    }
+
+    //Rows are left aligned, only the first width bits of each row are drawn
+    fn draw_rows(&mut self, x: usize, y: usize, rows: &[u16], width: usize, plane: u8, clip: bool) -> bool {
+        let (x, y) = (x % self.width, y % self.height);
+        let mut collision = false;
+        for (i, &row) in rows.iter().enumerate() {
+            if clip && y + i >= self.height {
+                break;
+            }
+            for j in 0..width {
+                if clip && x + j >= self.width {
+                    break;
+                }
+                if (row & (0x8000 >> j)) != 0 {
+                    let posx = (j + x) % self.width;
+                    let posy = (i + y) % self.height;
+                    let pixel = &mut self.memory[posy * self.width + posx];
+                    collision |= *pixel & plane != 0;
+                    *pixel ^= plane;
                 }
             }
         }
@@ -54,13 +187,84 @@ impl Display {
         collision
     }
 
+    //Moves the selected planes by (dx, dy) pixels, uncovered pixels are cleared
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width as isize, self.height as isize);
+        let planes = self.planes;
+        let old = self.memory.clone();
+        self.dirty = true;
+
+        for y in 0..height {
+            for x in 0..width {
+                let (srcx, srcy) = (x - dx, y - dy);
+                let moved = if srcx >= 0 && srcx < width && srcy >= 0 && srcy < height {
+                    old[(srcy * width + srcx) as usize] & planes
+                } else {
+                    0
+                };
+                let pixel = &mut self.memory[(y * width + x) as usize];
+                *pixel = (*pixel & !planes) | moved;
+            }
+        }
+    }
+
+    //Scroll the screen down by n pixels
+    //Instructions:
+    //  SCD nibble
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    //Scroll the screen up by n pixels
+    //Instructions:
+    //  SCU nibble
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    //Scroll the screen right by n pixels
+    //Instructions:
+    //  SCR
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    //Scroll the screen left by n pixels
+    //Instructions:
+    //  SCL
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    //Raw pixels, one plane bitmask per pixel in row major order
+    pub fn pixels(&self) -> &[u8] {
+        &self.memory
+    }
+
+    //Restores a mode, the selected planes and the raw pixels, fails if the
+    //number of pixels does not match the mode
+    pub(crate) fn restore(&mut self, hires: bool, planes: u8, pixels: Vec<u8>) -> bool {
+        let (width, height) = dimensions(hires);
+        if pixels.len() != width * height {
+            return false;
+        }
+
+        self.set_hires(hires);
+        self.select_planes(planes);
+        self.memory = pixels.into_iter()
+            .map(|pixel| pixel & ((1 << PLANES) - 1))
+            .collect();
+        self.dirty = true;
+        true
+    }
+
     pub fn get_buffer(&self) -> Vec<u32> {
         let mut buffer = vec![BLACK; self.memory.len()];
 
         for (i, value) in self.memory.iter().enumerate() {
-            buffer[i] = if *value { WHITE } else { BLACK };
+            buffer[i] = self.palette[usize::from(*value)];
         }
 
         buffer
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,96 @@
+use std::error::Error;
+use std::fmt;
+
+//Error raised while executing an instruction, every variant carries the address
+//of the faulting instruction and its opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16, opcode: u16 },
+    MemoryOutOfRange { pc: u16, opcode: u16, addr: usize },
+    InvalidKey { pc: u16, opcode: u16, key: u8 },
+    InvalidDigit { pc: u16, opcode: u16, digit: u8 },
+}
+
+impl CpuError {
+    pub fn pc(&self) -> u16 {
+        match *self {
+            CpuError::UnknownOpcode { pc, .. }
+            | CpuError::StackUnderflow { pc, .. }
+            | CpuError::StackOverflow { pc, .. }
+            | CpuError::MemoryOutOfRange { pc, .. }
+            | CpuError::InvalidKey { pc, .. }
+            | CpuError::InvalidDigit { pc, .. } => pc,
+        }
+    }
+
+    pub fn opcode(&self) -> u16 {
+        match *self {
+            CpuError::UnknownOpcode { opcode, .. }
+            | CpuError::StackUnderflow { opcode, .. }
+            | CpuError::StackOverflow { opcode, .. }
+            | CpuError::MemoryOutOfRange { opcode, .. }
+            | CpuError::InvalidKey { opcode, .. }
+            | CpuError::InvalidDigit { opcode, .. } => opcode,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownOpcode { .. } => write!(f, "unknown opcode"),
+            CpuError::StackUnderflow { .. } => write!(f, "return with an empty stack"),
+            CpuError::StackOverflow { .. } => write!(f, "call with a full stack"),
+            CpuError::MemoryOutOfRange { addr, .. } => write!(f, "memory access out of range at {:#05x}", addr),
+            CpuError::InvalidKey { key, .. } => write!(f, "invalid key {:#x}", key),
+            CpuError::InvalidDigit { digit, .. } => write!(f, "invalid font digit {:#x}", digit),
+        }?;
+        write!(f, " (pc: {:#05x}, opcode: {:04x})", self.pc(), self.opcode())
+    }
+}
+
+impl Error for CpuError {}
+
+//Error raised while loading a rom, before anything runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    RomTooLarge { size: usize, capacity: usize },      //bytes of the rom and bytes free from 0x200
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::RomTooLarge { size, capacity } =>
+                write!(f, "rom of {} bytes does not fit in the {} bytes of program memory", size, capacity),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+//Fault raised by an instruction helper, it becomes a CpuError once the cpu
+//attaches the address and the opcode of the instruction being executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    UnknownOpcode,
+    StackUnderflow,
+    StackOverflow,
+    MemoryOutOfRange(usize),
+    InvalidKey(u8),
+    InvalidDigit(u8),
+}
+
+impl Fault {
+    pub(crate) fn at(self, pc: u16, opcode: u16) -> CpuError {
+        match self {
+            Fault::UnknownOpcode => CpuError::UnknownOpcode { pc, opcode },
+            Fault::StackUnderflow => CpuError::StackUnderflow { pc, opcode },
+            Fault::StackOverflow => CpuError::StackOverflow { pc, opcode },
+            Fault::MemoryOutOfRange(addr) => CpuError::MemoryOutOfRange { pc, opcode, addr },
+            Fault::InvalidKey(key) => CpuError::InvalidKey { pc, opcode, key },
+            Fault::InvalidDigit(digit) => CpuError::InvalidDigit { pc, opcode, digit },
+        }
+    }
+}
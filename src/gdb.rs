@@ -0,0 +1,636 @@
+//GDB Remote Serial Protocol stub
+//
+//Registers, in the order of the g packet, are sent big endian like the
+//CHIP-8 memory:
+//  0-15    V0-VF   8 bit
+//  16      I       16 bit
+//  17      PC      16 bit
+//  18      SP      8 bit, number of entries in the stack
+//  19      DT      8 bit
+//  20      ST      8 bit
+//
+//Supported packets: ? g G p P m M c s vCont Z0 z0 k D qSupported
+//QStartNoAckMode qXfer:features:read qXfer:memory-map:read qAttached H
+
+use super::cpu::Cpu;
+use super::memory::Memory;
+use super::register::Register;
+
+use std::collections::{BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+const REGISTERS: usize = 21;
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>chip8</architecture>
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+//What the connection loop has to do after a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    pub breakpoints: BTreeSet<u16>,
+    pub instructions_per_frame: usize,
+    cycles: usize,                  //instructions executed in the current frame
+    no_ack: bool,
+    pending: VecDeque<u8>,          //bytes received but not consumed yet
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(text.get(idx..idx+2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+//Escapes the characters the protocol reserves
+fn escape(data: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data.as_bytes() {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(byte ^ 0x20);
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+//Answer to a qXfer read of annex starting at offset, None if the range
+//overflows
+fn xfer(annex: &str, offset: usize, length: usize) -> Option<String> {
+    let start = offset.min(annex.len());
+    let end = offset.checked_add(length)?.min(annex.len());
+    let prefix = if end == annex.len() { 'l' } else { 'm' };
+    Some(format!("{}{}", prefix, &annex[start..end]))
+}
+
+//End of the memory range of len bytes at addr, None if it is not inside memory
+fn memory_range(cpu: &Cpu, addr: usize, len: usize) -> Option<usize> {
+    addr.checked_add(len).filter(|&end| end <= cpu.memory.size())
+}
+
+impl GdbStub {
+    pub fn new(instructions_per_frame: usize) -> Self {
+        GdbStub {
+            breakpoints: BTreeSet::new(),
+            instructions_per_frame,
+            cycles: 0,
+            no_ack: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn read_registers(&self, cpu: &Cpu) -> Vec<u8> {
+        let register = &cpu.register;
+        let mut data = register.v.clone();
+        data.extend_from_slice(&register.i.to_be_bytes());
+        data.extend_from_slice(&register.pc.to_be_bytes());
+        data.push(register.stack.len() as u8);
+        data.push(register.delay);
+        data.push(register.sound);
+        data
+    }
+
+    fn register_size(idx: usize) -> usize {
+        if idx == 16 || idx == 17 { 2 } else { 1 }
+    }
+
+    fn write_register(register: &mut Register, idx: usize, value: &[u8]) -> bool {
+        match (idx, value) {
+            (0..=15, &[value]) => register.v[idx] = value,
+            (16, &[high, low]) => register.i = u16::from_be_bytes([high, low]),
+            (17, &[high, low]) => register.pc = u16::from_be_bytes([high, low]),
+            (18, &[len]) => return register.stack.resize(usize::from(len)),
+            (19, &[value]) => register.delay = value,
+            (20, &[value]) => register.sound = value,
+            _ => return false,
+        }
+        true
+    }
+
+    fn memory_map(cpu: &Cpu) -> String {
+        format!(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n",
+            "<memory-map><memory type=\"ram\" start=\"0x0\" length=\"{:#x}\"/></memory-map>\n"),
+            cpu.memory.size())
+    }
+
+    //Handles a packet that does not need the connection, execution
+    //requests are returned to the caller
+    pub fn handle_packet(&mut self, cpu: &mut Cpu, packet: &str) -> Action {
+        let reply = |text: &str| Action::Reply(text.to_string());
+        let error = || reply("E01");
+
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => Action::Reply(format!("S{:02x}", SIGTRAP)),
+            "g" => Action::Reply(to_hex(&self.read_registers(cpu))),
+            "G" => match from_hex(args) {
+                //All the registers are written or none
+                Some(ref data) if data.len() == self.read_registers(cpu).len() => {
+                    let mut register = cpu.register.clone();
+                    let mut pos = 0;
+                    for idx in 0..REGISTERS {
+                        let size = GdbStub::register_size(idx);
+                        if !GdbStub::write_register(&mut register, idx, &data[pos..pos+size]) {
+                            return error();
+                        }
+                        pos += size;
+                    }
+                    cpu.register = register;
+                    reply("OK")
+                },
+                _ => error(),
+            },
+            "p" => match parse_hex(args) {
+                Some(idx) if idx < REGISTERS => {
+                    let data = self.read_registers(cpu);
+                    let pos: usize = (0..idx).map(GdbStub::register_size).sum();
+                    Action::Reply(to_hex(&data[pos..pos+GdbStub::register_size(idx)]))
+                },
+                _ => error(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let idx = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(from_hex);
+                match (idx, value) {
+                    (Some(idx), Some(value)) if GdbStub::write_register(&mut cpu.register, idx, &value) => reply("OK"),
+                    _ => error(),
+                }
+            },
+            "m" => {
+                let mut parts = args.splitn(2, ',');
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex);
+                match (addr, len) {
+                    (Some(addr), Some(len)) => match memory_range(cpu, addr, len) {
+                        Some(end) => {
+                            let data: Vec<u8> = (addr..end).map(|idx| cpu.memory.get_u8(idx)).collect();
+                            Action::Reply(to_hex(&data))
+                        },
+                        None => error(),
+                    },
+                    _ => error(),
+                }
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let mut range = parts.next().unwrap_or("").splitn(2, ',');
+                let addr = range.next().and_then(parse_hex);
+                let len = range.next().and_then(parse_hex);
+                let data = parts.next().and_then(from_hex);
+                match (addr, len, data) {
+                    //Written straight to memory, watchpoints do not see them
+                    (Some(addr), Some(len), Some(data)) if data.len() == len && memory_range(cpu, addr, len).is_some() => {
+                        for (offset, &byte) in data.iter().enumerate() {
+                            cpu.memory.set_u8(addr + offset, byte);
+                        }
+                        reply("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args).and_then(|addr| u16::try_from(addr).ok()) {
+                        Some(addr) => cpu.register.pc = addr,
+                        None => return error(),
+                    }
+                }
+                if command == "c" { Action::Continue } else { Action::Step }
+            },
+            "Z" | "z" => {
+                let parts: Vec<&str> = args.split(',').collect();
+                match (parts.first().copied(), parts.get(1).and_then(|addr| parse_hex(addr))) {
+                    (Some("0"), Some(addr)) => match u16::try_from(addr) {
+                        Ok(addr) => {
+                            if command == "Z" {
+                                self.breakpoints.insert(addr);
+                            } else {
+                                self.breakpoints.remove(&addr);
+                            }
+                            reply("OK")
+                        },
+                        Err(_) => error(),
+                    },
+                    _ => reply(""),
+                }
+            },
+            "k" => Action::Kill,
+            "D" => Action::Detach,
+            "H" => reply("OK"),
+            _ => self.handle_query(cpu, packet),
+        }
+    }
+
+    fn handle_query(&mut self, cpu: &Cpu, packet: &str) -> Action {
+        let reply = |text: &str| Action::Reply(text.to_string());
+
+        if packet.starts_with("qSupported") {
+            return Action::Reply(format!(
+                "PacketSize={:x};qXfer:features:read+;qXfer:memory-map:read+;QStartNoAckMode+;swbreak+",
+                PACKET_SIZE));
+        }
+        if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            return reply("OK");
+        }
+        if packet == "vCont?" {
+            return reply("vCont;c;s");
+        }
+        if let Some(action) = packet.strip_prefix("vCont;") {
+            return match action.chars().next() {
+                Some('c') => Action::Continue,
+                Some('s') => Action::Step,
+                _ => reply(""),
+            };
+        }
+        if let Some(request) = packet.strip_prefix("qXfer:") {
+            let parts: Vec<&str> = request.split(':').collect();
+            let range: Vec<usize> = parts.get(3)
+                .map(|range| range.split(',').filter_map(parse_hex).collect())
+                .unwrap_or_default();
+            let annex = match (parts.first().copied(), parts.get(2).copied()) {
+                (Some("features"), Some("target.xml")) => TARGET_XML.to_string(),
+                (Some("memory-map"), _) => GdbStub::memory_map(cpu),
+                _ => return reply("E00"),
+            };
+            return match *range.as_slice() {
+                [offset, length] => match xfer(&annex, offset, length) {
+                    Some(data) => Action::Reply(data),
+                    None => reply("E01"),
+                },
+                _ => reply("E00"),
+            };
+        }
+
+        match packet {
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+}
+
+impl GdbStub {
+    fn read_byte(&mut self, stream: &mut TcpStream) -> io::Result<u8> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(byte);
+        }
+        let mut buffer = [0; 1024];
+        let len = stream.read(&mut buffer)?;
+        if len == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        self.pending.extend(&buffer[..len]);
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    //Next packet, interrupt requests outside packets are returned as "\x03".
+    //Packets longer than the advertised PacketSize are read to the end but
+    //their data is dropped and None is returned
+    fn read_packet(&mut self, stream: &mut TcpStream) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte(stream)? {
+                b'$' => break,
+                0x03 => return Ok(Some("\x03".to_string())),
+                _ => continue,
+            }
+        }
+
+        //The checksum covers the bytes as sent, escapes included
+        let mut data = Vec::new();
+        let mut sum: u8 = 0;
+        let mut too_long = false;
+        loop {
+            let byte = match self.read_byte(stream)? {
+                b'#' => break,
+                b'}' => {
+                    let escaped = self.read_byte(stream)?;
+                    sum = sum.wrapping_add(b'}').wrapping_add(escaped);
+                    escaped ^ 0x20
+                },
+                byte => {
+                    sum = sum.wrapping_add(byte);
+                    byte
+                },
+            };
+            if data.len() < PACKET_SIZE {
+                data.push(byte);
+            } else {
+                too_long = true;
+            }
+        }
+        let high = self.read_byte(stream)?;
+        let low = self.read_byte(stream)?;
+        let expected = std::str::from_utf8(&[high, low]).ok()
+            .and_then(|text| u8::from_str_radix(text, 16).ok());
+
+        if !self.no_ack {
+            let ack: &[u8] = if expected == Some(sum) { b"+" } else { b"-" };
+            stream.write_all(ack)?;
+        }
+        if too_long {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        let data = escape(data);
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(&data)).as_bytes());
+        stream.write_all(&packet)?;
+
+        if !self.no_ack {
+            loop {
+                match self.read_byte(stream)? {
+                    b'+' => break,
+                    b'-' => stream.write_all(&packet)?,
+                    _ => continue,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //True if the client sent an interrupt while the cpu was running
+    fn interrupted(&mut self, stream: &mut TcpStream) -> io::Result<bool> {
+        stream.set_nonblocking(true)?;
+        let mut buffer = [0; 64];
+        let result = stream.read(&mut buffer);
+        stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+            Ok(len) => {
+                let interrupt = buffer[..len].contains(&0x03);
+                self.pending.extend(buffer[..len].iter().filter(|&&byte| byte != 0x03));
+                Ok(interrupt)
+            },
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    //Executes until a breakpoint, a fault, EXIT or an interrupt, returns the stop reply
+    fn run(&mut self, cpu: &mut Cpu, stream: &mut TcpStream, step: bool) -> io::Result<String> {
+        let mut count: u64 = 0;
+        loop {
+            if count > 0 && self.breakpoints.contains(&cpu.register.pc) {
+                return Ok(format!("T{:02x}swbreak:;", SIGTRAP));
+            }
+            if cpu.halted {
+                return Ok("W00".to_string());
+            }
+            if cpu.next().is_err() {
+                return Ok(format!("S{:02x}", SIGSEGV));
+            }
+            self.cycles += 1;
+            if self.cycles >= self.instructions_per_frame {
+                self.cycles = 0;
+                cpu.decrement_timers();
+            }
+
+            count += 1;
+            if step {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            if count.is_multiple_of(1024) && self.interrupted(stream)? {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+        }
+    }
+
+    //Serves a single client until it detaches or kills the target
+    pub fn serve(&mut self, cpu: &mut Cpu, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        self.no_ack = false;
+        self.pending.clear();
+
+        loop {
+            let packet = match self.read_packet(&mut stream)? {
+                Some(packet) => packet,
+                None => {
+                    self.send(&mut stream, "E01")?;
+                    continue;
+                },
+            };
+            if packet == "\x03" {
+                self.send(&mut stream, &format!("S{:02x}", SIGTRAP))?;
+                continue;
+            }
+
+            match self.handle_packet(cpu, &packet) {
+                Action::Reply(reply) => self.send(&mut stream, &reply)?,
+                Action::Continue => {
+                    let reply = self.run(cpu, &mut stream, false)?;
+                    self.send(&mut stream, &reply)?;
+                },
+                Action::Step => {
+                    let reply = self.run(cpu, &mut stream, true)?;
+                    self.send(&mut stream, &reply)?;
+                },
+                Action::Detach => {
+                    self.send(&mut stream, "OK")?;
+                    return Ok(());
+                },
+                Action::Kill => return Ok(()),
+            }
+        }
+    }
+
+    //Waits for one client on addr and serves it
+    pub fn listen<A: ToSocketAddrs>(&mut self, cpu: &mut Cpu, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        self.serve(cpu, stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    //Reads one reply packet and acknowledges it
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut byte = [0; 1];
+        let mut data = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'$' => break,
+                b'+' => continue,
+                other => panic!("unexpected byte {:#x} before a packet", other),
+            }
+        }
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'#' => break,
+                b'}' => {
+                    stream.read_exact(&mut byte).unwrap();
+                    data.push(byte[0] ^ 0x20);
+                },
+                other => data.push(other),
+            }
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    //Scripted client: sends every packet, then detaches, and returns the replies
+    fn session(cpu: &mut Cpu, stub: &mut GdbStub, packets: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let packets: Vec<String> = packets.iter().map(|packet| packet.to_string()).collect();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets.iter().map(String::as_str).chain(std::iter::once("D")) {
+                let frame = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+                stream.write_all(frame.as_bytes()).unwrap();
+                replies.push(read_reply(&mut stream));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stub.serve(cpu, stream).unwrap();
+        let mut replies = client.join().unwrap();
+        assert_eq!(replies.pop().as_deref(), Some("OK"));
+        replies
+    }
+
+    fn registers(sp: &str) -> String {
+        format!("{}00000200{}0000", "00".repeat(16), sp)
+    }
+
+    #[test]
+    fn scripted_session() {
+        //LD V0, 5; LD V1, 6; JP 204
+        let mut cpu = Cpu::new(&[0x60, 0x05, 0x61, 0x06, 0x12, 0x04]);
+        let mut stub = GdbStub::new(10);
+        let replies = session(&mut cpu, &mut stub, &[
+            "?",
+            "g",
+            "m200,4",
+            "M300,2:abcd",
+            "m300,2",
+            "Z0,202",
+            "c",
+            "p0",
+            "p11",
+        ]);
+        assert_eq!(replies, vec![
+            "S05".to_string(),
+            registers("00"),
+            "60056106".to_string(),
+            "OK".to_string(),
+            "abcd".to_string(),
+            "OK".to_string(),
+            "T05swbreak:;".to_string(),
+            "05".to_string(),
+            "0202".to_string(),
+        ]);
+        assert_eq!(cpu.register.v[1], 0);
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let mut cpu = Cpu::new(&[0x12, 0x00]);
+        let mut stub = GdbStub::new(10);
+        let replies = session(&mut cpu, &mut stub, &[
+            "\u{e9}",
+            "mffffffffffffffff,2",
+            "m0,ffffffffffffffff",
+            "Mffffffffffffffff,2:0000",
+            "qXfer:features:read:target.xml:1,ffffffffffffffff",
+            "Z0,10200",
+            "z0,10200",
+            "c10200",
+            &"0".repeat(PACKET_SIZE + 1),
+            &format!("G{}", registers("ff")),
+            "g",
+            "?",
+        ]);
+        assert_eq!(replies, vec![
+            "".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            "E01".to_string(),
+            registers("00"),
+            "S05".to_string(),
+        ]);
+    }
+}
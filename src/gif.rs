@@ -0,0 +1,368 @@
+//GIF decoder and encoder, limited to what cartridges need: the color
+//indexes of every frame are decoded, disposal and transparency are ignored.
+//Frames must lie inside the logical screen and all of them together are
+//limited to MAX_PIXELS pixels
+
+use std::collections::HashMap;
+
+const MAX_CODES: usize = 4096;
+const MAX_PIXELS: usize = 1 << 24;     //decoded pixels of all the frames together
+const NO_PREFIX: u16 = 0xffff;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self.data.get(self.pos..self.pos+len).ok_or("truncated gif")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, &'static str> {
+        let bytes = self.bytes(2)?;
+        Ok(usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+    }
+
+    //Color tables are skipped, only the indexes matter
+    fn skip_palette(&mut self, packed: u8) -> Result<(), &'static str> {
+        if packed & 0x80 != 0 {
+            self.bytes((2 << (packed & 0x7)) * 3)?;
+        }
+        Ok(())
+    }
+
+    //Data sub-blocks up to the terminator
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, &'static str> {
+        let mut data = Vec::new();
+        loop {
+            let len = usize::from(self.u8()?);
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(self.bytes(len)?);
+        }
+    }
+}
+
+fn lzw_decode(min_size: u8, data: &[u8], len: usize) -> Result<Vec<u8>, &'static str> {
+    if !(2..=8).contains(&min_size) {
+        return Err("invalid lzw code size");
+    }
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut prefix = vec![NO_PREFIX; MAX_CODES];
+    let mut suffix = vec![0u8; MAX_CODES];
+    let mut first = vec![0u8; MAX_CODES];
+    for code in 0..clear {
+        suffix[usize::from(code)] = code as u8;
+        first[usize::from(code)] = code as u8;
+    }
+
+    let push = |pixels: &mut Vec<u8>, prefix: &[u16], suffix: &[u8], code: u16| {
+        let start = pixels.len();
+        let mut code = code;
+        loop {
+            pixels.push(suffix[usize::from(code)]);
+            if prefix[usize::from(code)] == NO_PREFIX {
+                break;
+            }
+            code = prefix[usize::from(code)];
+        }
+        pixels[start..].reverse();
+    };
+
+    let mut pixels = Vec::with_capacity(len);
+    let mut width = u32::from(min_size) + 1;
+    let mut next = end + 1;
+    let mut previous: Option<u16> = None;
+    let (mut bits, mut count, mut pos) = (0u32, 0u32, 0usize);
+
+    while pixels.len() < len {
+        while count < width && pos < data.len() {
+            bits |= u32::from(data[pos]) << count;
+            count += 8;
+            pos += 1;
+        }
+        if count < width {
+            break;
+        }
+        let code = (bits & ((1 << width) - 1)) as u16;
+        bits >>= width;
+        count -= width;
+
+        if code == clear {
+            width = u32::from(min_size) + 1;
+            next = end + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+
+        let previous_code = match previous {
+            None => {
+                if code >= clear {
+                    return Err("invalid lzw code");
+                }
+                pixels.push(code as u8);
+                previous = Some(code);
+                continue;
+            },
+            Some(previous_code) => previous_code,
+        };
+        let head = if code < next {
+            push(&mut pixels, &prefix, &suffix, code);
+            first[usize::from(code)]
+        } else if code == next {
+            let head = first[usize::from(previous_code)];
+            push(&mut pixels, &prefix, &suffix, previous_code);
+            pixels.push(head);
+            head
+        } else {
+            return Err("invalid lzw code");
+        };
+
+        if usize::from(next) < MAX_CODES {
+            prefix[usize::from(next)] = previous_code;
+            suffix[usize::from(next)] = head;
+            first[usize::from(next)] = first[usize::from(previous_code)];
+            next += 1;
+            if u32::from(next) == 1 << width && width < 12 {
+                width += 1;
+            }
+        }
+        previous = Some(code);
+    }
+
+    if pixels.len() < len {
+        return Err("truncated image data");
+    }
+    pixels.truncate(len);
+    Ok(pixels)
+}
+
+//Rows of an interlaced image in the order they are stored
+fn interlaced_rows(height: usize) -> Vec<usize> {
+    [(0, 8), (4, 8), (2, 4), (1, 2)].iter()
+        .flat_map(|&(start, step)| (start..height).step_by(step))
+        .collect()
+}
+
+//Color indexes of every frame, row by row
+pub fn decode(data: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+    let mut reader = Reader { data, pos: 0 };
+    let signature = reader.bytes(6).map_err(|_| "not a gif")?;
+    if signature != b"GIF87a" && signature != b"GIF89a" {
+        return Err("not a gif");
+    }
+
+    let screen_width = reader.u16()?;
+    let screen_height = reader.u16()?;
+    let packed = reader.u8()?;
+    reader.bytes(2)?;
+    reader.skip_palette(packed)?;
+
+    //The sizes come from the file, they are checked before anything is allocated
+    let mut budget = MAX_PIXELS;
+    let mut frames = Vec::new();
+    loop {
+        match reader.u8()? {
+            0x21 => {
+                reader.u8()?;
+                reader.sub_blocks()?;
+            },
+            0x2c => {
+                let left = reader.u16()?;
+                let top = reader.u16()?;
+                let frame_width = reader.u16()?;
+                let frame_height = reader.u16()?;
+                if left + frame_width > screen_width || top + frame_height > screen_height {
+                    return Err("frame outside the logical screen");
+                }
+                let len = frame_width * frame_height;
+                budget = budget.checked_sub(len).ok_or("gif too large")?;
+
+                let packed = reader.u8()?;
+                reader.skip_palette(packed)?;
+                let min_size = reader.u8()?;
+                let data = reader.sub_blocks()?;
+                let mut pixels = lzw_decode(min_size, &data, len)?;
+
+                if packed & 0x40 != 0 {
+                    let stored = pixels.clone();
+                    for (idx, row) in interlaced_rows(frame_height).into_iter().enumerate() {
+                        pixels[row*frame_width..(row+1)*frame_width]
+                            .copy_from_slice(&stored[idx*frame_width..(idx+1)*frame_width]);
+                    }
+                }
+                frames.push(pixels);
+            },
+            0x3b => break,
+            _ => return Err("invalid gif block"),
+        }
+    }
+
+    Ok(frames)
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, width: u32) {
+        self.bits |= u32::from(code) << self.count;
+        self.count += width;
+        while self.count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+fn lzw_encode(min_size: u8, pixels: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut writer = BitWriter { data: Vec::new(), bits: 0, count: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut width = u32::from(min_size) + 1;
+    let mut next = end + 1;
+    let mut current: Option<u16> = None;
+
+    writer.write(clear, width);
+    for &pixel in pixels {
+        let code = match current {
+            None => {
+                current = Some(u16::from(pixel));
+                continue;
+            },
+            Some(code) => code,
+        };
+        if let Some(&longer) = table.get(&(code, pixel)) {
+            current = Some(longer);
+            continue;
+        }
+
+        writer.write(code, width);
+        if usize::from(next) < MAX_CODES {
+            table.insert((code, pixel), next);
+            next += 1;
+            if u32::from(next) > 1 << width && width < 12 {
+                width += 1;
+            }
+        } else {
+            writer.write(clear, width);
+            table.clear();
+            width = u32::from(min_size) + 1;
+            next = end + 1;
+        }
+        current = Some(u16::from(pixel));
+    }
+    if let Some(code) = current {
+        writer.write(code, width);
+    }
+    writer.write(end, width);
+    writer.finish()
+}
+
+//GIF89a with a 256 color palette that loops over frames, delay is in
+//hundredths of a second
+pub fn encode(width: usize, height: usize, palette: &[u32; 256], frames: &[Vec<u8>], delay: u16) -> Vec<u8> {
+    let mut data = b"GIF89a".to_vec();
+    data.extend_from_slice(&(width as u16).to_le_bytes());
+    data.extend_from_slice(&(height as u16).to_le_bytes());
+    data.extend_from_slice(&[0xf7, 0, 0]);
+    for color in palette.iter() {
+        data.extend_from_slice(&color.to_be_bytes()[1..]);
+    }
+
+    if frames.len() > 1 {
+        data.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+    }
+    for pixels in frames {
+        data.extend_from_slice(&[0x21, 0xf9, 0x04, 0x00]);
+        data.extend_from_slice(&delay.to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x00]);
+
+        data.push(0x2c);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&(width as u16).to_le_bytes());
+        data.extend_from_slice(&(height as u16).to_le_bytes());
+        data.push(0x00);
+        data.push(8);
+        for block in lzw_encode(8, pixels).chunks(255) {
+            data.push(block.len() as u8);
+            data.extend_from_slice(block);
+        }
+        data.push(0x00);
+    }
+    data.push(0x3b);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Single frame gif with no palette
+    fn gif(screen: (u16, u16), frame: (u16, u16, u16, u16), min_size: u8, pixels: &[u8]) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        for value in [screen.0, screen.1].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x2c]);
+        for value in [frame.0, frame.1, frame.2, frame.3].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0x00, min_size]);
+        for block in lzw_encode(min_size.max(2), pixels).chunks(255) {
+            data.push(block.len() as u8);
+            data.extend_from_slice(block);
+        }
+        data.extend_from_slice(&[0x00, 0x3b]);
+        data
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![(0..64).map(|idx| (idx * 7 % 256) as u8).collect::<Vec<u8>>(), vec![3; 64]];
+        assert_eq!(decode(&encode(8, 8, &[0; 256], &frames, 5)).unwrap(), frames);
+
+        let pixels: Vec<u8> = (0..12).map(|idx| idx % 4).collect();
+        assert_eq!(decode(&gif((8, 8), (2, 2, 4, 3), 2, &pixels)).unwrap(), vec![pixels]);
+    }
+
+    #[test]
+    fn frames_larger_than_the_screen_are_rejected() {
+        let pixels = vec![1; 16];
+        assert_eq!(decode(&gif((4, 4), (1, 0, 4, 4), 2, &pixels)), Err("frame outside the logical screen"));
+        assert_eq!(decode(&gif((4, 4), (0, 0, 0xffff, 0xffff), 2, &pixels)), Err("frame outside the logical screen"));
+        assert_eq!(decode(&gif((0xffff, 0xffff), (0, 0, 0xffff, 0xffff), 2, &pixels)), Err("gif too large"));
+    }
+
+    #[test]
+    fn code_sizes_outside_the_gif_range_are_rejected() {
+        let pixels = vec![1; 16];
+        for &min_size in [0, 1, 9, 11].iter() {
+            assert_eq!(decode(&gif((4, 4), (0, 0, 4, 4), min_size, &pixels)), Err("invalid lzw code size"));
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fmt;
+
+//Decoded CHIP-8 instruction, registers are stored as indexes (0x0..=0xF)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(u16),                   //SYS   addr            0nnn
+    Cls,                        //CLS                   00E0
+    Ret,                        //RET                   00EE
+    Jp(u16),                    //JP    addr            1nnn
+    Call(u16),                  //CALL  addr            2nnn
+    SeByte(u8, u8),             //SE    Vx, byte        3xkk
+    SneByte(u8, u8),            //SNE   Vx, byte        4xkk
+    SeReg(u8, u8),              //SE    Vx, Vy          5xy0
+    LdByte(u8, u8),             //LD    Vx, byte        6xkk
+    AddByte(u8, u8),            //ADD   Vx, byte        7xkk
+    LdReg(u8, u8),              //LD    Vx, Vy          8xy0
+    Or(u8, u8),                 //OR    Vx, Vy          8xy1
+    And(u8, u8),                //AND   Vx, Vy          8xy2
+    Xor(u8, u8),                //XOR   Vx, Vy          8xy3
+    AddReg(u8, u8),             //ADD   Vx, Vy          8xy4
+    Sub(u8, u8),                //SUB   Vx, Vy          8xy5
+    Shr(u8, u8),                //SHR   Vx {, Vy}       8xy6
+    Subn(u8, u8),               //SUBN  Vx, Vy          8xy7
+    Shl(u8, u8),                //SHL   Vx {, Vy}       8xyE
+    SneReg(u8, u8),             //SNE   Vx, Vy          9xy0
+    LdI(u16),                   //LD    I,  addr        Annn
+    JpV0(u16),                  //JP    V0, addr        Bnnn
+    Rnd(u8, u8),                //RND   Vx, byte        Cxkk
+    Drw(u8, u8, u8),            //DRW   Vx, Vy, nibble  Dxyn
+    Skp(u8),                    //SKP   Vx              Ex9E
+    Sknp(u8),                   //SKNP  Vx              ExA1
+    LdVxDt(u8),                 //LD    Vx, DT          Fx07
+    LdVxK(u8),                  //LD    Vx, K           Fx0A
+    LdDtVx(u8),                 //LD    DT, Vx          Fx15
+    LdStVx(u8),                 //LD    ST, Vx          Fx18
+    AddI(u8),                   //ADD   I,  Vx          Fx1E
+    LdF(u8),                    //LD    F,  Vx          Fx29
+    LdB(u8),                    //LD    B,  Vx          Fx33
+    LdMemVx(u8),                //LD    [I], Vx         Fx55
+    LdVxMem(u8),                //LD    Vx, [I]         Fx65
+
+    //SUPER-CHIP
+    ScrollDown(u8),             //SCD   nibble          00Cn
+    ScrollRight,                //SCR                   00FB
+    ScrollLeft,                 //SCL                   00FC
+    Exit,                       //EXIT                  00FD
+    Low,                        //LOW                   00FE
+    High,                       //HIGH                  00FF
+    LdHf(u8),                   //LD    HF, Vx          Fx30
+    LdRVx(u8),                  //LD    R,  Vx          Fx75
+    LdVxR(u8),                  //LD    Vx, R           Fx85
+
+    //XO-CHIP
+    ScrollUp(u8),               //SCU   nibble          00Dn
+    SaveRange(u8, u8),          //LD    [I], Vx-Vy      5xy2
+    LoadRange(u8, u8),          //LD    Vx-Vy, [I]      5xy3
+    LdILong,                    //LD    I,  LONG nnnn   F000 nnnn, the address is the next word
+    Plane(u8),                  //PLANE nibble          Fn01
+    Audio,                      //AUDIO                 F002
+    Pitch(u8),                  //LD    PITCH, Vx       Fx3A
+}
+
+//The opcode does not match any known instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown opcode {:04x}", self.opcode)
+    }
+}
+
+impl Error for DecodeError {}
+
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    let addr = opcode & 0x0fff;
+    let nibble = (opcode & 0x000f) as u8;
+    let x = ((opcode & 0x0f00) >> 8) as u8;
+    let y = ((opcode & 0x00f0) >> 4) as u8;
+    let byte = (opcode & 0x00ff) as u8;
+
+    let instruction = match opcode >> 12 {
+        0x0 => {
+            match opcode {
+                0x00c0..=0x00cf => Instruction::ScrollDown(nibble),
+                0x00d0..=0x00df => Instruction::ScrollUp(nibble),
+                0x00e0 => Instruction::Cls,
+                0x00ee => Instruction::Ret,
+                0x00fb => Instruction::ScrollRight,
+                0x00fc => Instruction::ScrollLeft,
+                0x00fd => Instruction::Exit,
+                0x00fe => Instruction::Low,
+                0x00ff => Instruction::High,
+                _ => Instruction::Sys(addr),
+            }
+        },
+
+        0x8 => {
+            match nibble {
+                0x0 => Instruction::LdReg(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::AddReg(x, y),
+                0x5 => Instruction::Sub(x, y),
+                0x6 => Instruction::Shr(x, y),
+                0x7 => Instruction::Subn(x, y),
+                0xe => Instruction::Shl(x, y),
+                _ => return Err(DecodeError { opcode }),
+            }
+        },
+
+        0xe => {
+            match byte {
+                0x9e => Instruction::Skp(x),
+                0xa1 => Instruction::Sknp(x),
+                _ => return Err(DecodeError { opcode }),
+            }
+        },
+
+        0xf => {
+            match byte {
+                0x00 if x == 0 => Instruction::LdILong,
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0 => Instruction::Audio,
+                0x07 => Instruction::LdVxDt(x),
+                0x0a => Instruction::LdVxK(x),
+                0x15 => Instruction::LdDtVx(x),
+                0x18 => Instruction::LdStVx(x),
+                0x1e => Instruction::AddI(x),
+                0x29 => Instruction::LdF(x),
+                0x30 => Instruction::LdHf(x),
+                0x33 => Instruction::LdB(x),
+                0x3a => Instruction::Pitch(x),
+                0x55 => Instruction::LdMemVx(x),
+                0x65 => Instruction::LdVxMem(x),
+                0x75 => Instruction::LdRVx(x),
+                0x85 => Instruction::LdVxR(x),
+                _ => return Err(DecodeError { opcode }),
+            }
+        },
+
+        0x1 => Instruction::Jp(addr),
+        0x2 => Instruction::Call(addr),
+        0x3 => Instruction::SeByte(x, byte),
+        0x4 => Instruction::SneByte(x, byte),
+        0x5 if nibble == 0 => Instruction::SeReg(x, y),
+        0x5 if nibble == 2 => Instruction::SaveRange(x, y),
+        0x5 if nibble == 3 => Instruction::LoadRange(x, y),
+        0x6 => Instruction::LdByte(x, byte),
+        0x7 => Instruction::AddByte(x, byte),
+        0x9 if nibble == 0 => Instruction::SneReg(x, y),
+        0xa => Instruction::LdI(addr),
+        0xb => Instruction::JpV0(addr),
+        0xc => Instruction::Rnd(x, byte),
+        0xd => Instruction::Drw(x, y, nibble),
+        _ => return Err(DecodeError { opcode }),
+    };
+
+    Ok(instruction)
+}
+
+//Inverse of decode, operands wider than their field are truncated
+//LdILong only encodes the prefix, the address follows as a separate word
+pub fn encode(instruction: Instruction) -> u16 {
+    let addr = |op: u16, addr: u16| op | (addr & 0x0fff);
+    let xkk = |op: u16, x: u8, byte: u8| op | (u16::from(x & 0xf) << 8) | u16::from(byte);
+    let xyn = |op: u16, x: u8, y: u8, n: u8| op | (u16::from(x & 0xf) << 8) | (u16::from(y & 0xf) << 4) | u16::from(n & 0xf);
+
+    match instruction {
+        Instruction::Sys(a) => addr(0x0000, a),
+        Instruction::Cls => 0x00e0,
+        Instruction::Ret => 0x00ee,
+        Instruction::Jp(a) => addr(0x1000, a),
+        Instruction::Call(a) => addr(0x2000, a),
+        Instruction::SeByte(x, byte) => xkk(0x3000, x, byte),
+        Instruction::SneByte(x, byte) => xkk(0x4000, x, byte),
+        Instruction::SeReg(x, y) => xyn(0x5000, x, y, 0x0),
+        Instruction::LdByte(x, byte) => xkk(0x6000, x, byte),
+        Instruction::AddByte(x, byte) => xkk(0x7000, x, byte),
+        Instruction::LdReg(x, y) => xyn(0x8000, x, y, 0x0),
+        Instruction::Or(x, y) => xyn(0x8000, x, y, 0x1),
+        Instruction::And(x, y) => xyn(0x8000, x, y, 0x2),
+        Instruction::Xor(x, y) => xyn(0x8000, x, y, 0x3),
+        Instruction::AddReg(x, y) => xyn(0x8000, x, y, 0x4),
+        Instruction::Sub(x, y) => xyn(0x8000, x, y, 0x5),
+        Instruction::Shr(x, y) => xyn(0x8000, x, y, 0x6),
+        Instruction::Subn(x, y) => xyn(0x8000, x, y, 0x7),
+        Instruction::Shl(x, y) => xyn(0x8000, x, y, 0xe),
+        Instruction::SneReg(x, y) => xyn(0x9000, x, y, 0x0),
+        Instruction::LdI(a) => addr(0xa000, a),
+        Instruction::JpV0(a) => addr(0xb000, a),
+        Instruction::Rnd(x, byte) => xkk(0xc000, x, byte),
+        Instruction::Drw(x, y, n) => xyn(0xd000, x, y, n),
+        Instruction::Skp(x) => xkk(0xe000, x, 0x9e),
+        Instruction::Sknp(x) => xkk(0xe000, x, 0xa1),
+        Instruction::LdVxDt(x) => xkk(0xf000, x, 0x07),
+        Instruction::LdVxK(x) => xkk(0xf000, x, 0x0a),
+        Instruction::LdDtVx(x) => xkk(0xf000, x, 0x15),
+        Instruction::LdStVx(x) => xkk(0xf000, x, 0x18),
+        Instruction::AddI(x) => xkk(0xf000, x, 0x1e),
+        Instruction::LdF(x) => xkk(0xf000, x, 0x29),
+        Instruction::LdB(x) => xkk(0xf000, x, 0x33),
+        Instruction::LdMemVx(x) => xkk(0xf000, x, 0x55),
+        Instruction::LdVxMem(x) => xkk(0xf000, x, 0x65),
+        Instruction::ScrollDown(n) => xyn(0x0000, 0x0, 0xc, n),
+        Instruction::ScrollRight => 0x00fb,
+        Instruction::ScrollLeft => 0x00fc,
+        Instruction::Exit => 0x00fd,
+        Instruction::Low => 0x00fe,
+        Instruction::High => 0x00ff,
+        Instruction::LdHf(x) => xkk(0xf000, x, 0x30),
+        Instruction::LdRVx(x) => xkk(0xf000, x, 0x75),
+        Instruction::LdVxR(x) => xkk(0xf000, x, 0x85),
+        Instruction::ScrollUp(n) => xyn(0x0000, 0x0, 0xd, n),
+        Instruction::SaveRange(x, y) => xyn(0x5000, x, y, 0x2),
+        Instruction::LoadRange(x, y) => xyn(0x5000, x, y, 0x3),
+        Instruction::LdILong => 0xf000,
+        Instruction::Plane(n) => xkk(0xf000, n, 0x01),
+        Instruction::Audio => 0xf002,
+        Instruction::Pitch(x) => xkk(0xf000, x, 0x3a),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xffff {
+            if let Ok(instruction) = decode(opcode) {
+                assert_eq!(encode(instruction), opcode, "{:04x} decodes to {:?}", opcode, instruction);
+            }
+        }
+    }
+}
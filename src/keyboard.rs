@@ -26,6 +26,12 @@ pub struct Keyboard {
     pub state: Vec<bool>,
 }
 
+impl Default for Keyboard {
+    fn default() -> Self {
+        Keyboard::new()
+    }
+}
+
 impl Keyboard {
     pub fn new() -> Self {
         Keyboard {
@@ -36,4 +42,38 @@ impl Keyboard {
     pub fn set_key(&mut self, idx: usize, value: bool) {
         self.state[idx] = value
     }
-}
\ No newline at end of file
+
+    //State of the keys as a bitmask, bit n is set if key n is pressed
+    pub fn bits(&self) -> u16 {
+        self.state.iter()
+            .enumerate()
+            .fold(0, |bits, (idx, &pressed)| bits | (u16::from(pressed) << idx))
+    }
+
+    pub fn set_bits(&mut self, bits: u16) {
+        for (idx, pressed) in self.state.iter_mut().enumerate() {
+            *pressed = (bits & (1 << idx)) != 0;
+        }
+    }
+}
+
+//Host keys a rom database entry can bind, by name
+pub const NAMED_KEYS: &[(&str, Key)] = &[
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("a", Key::Space),
+    ("b", Key::LeftShift),
+];
+
+//BINDINGS plus the named keys of keys, e.g. ("left", 4) makes the left arrow press key 4
+pub fn bindings_with(keys: &[(String, usize)]) -> Vec<(Key, usize)> {
+    let mut bindings = BINDINGS.to_vec();
+    for (name, idx) in keys {
+        if let Some((_, key)) = NAMED_KEYS.iter().find(|(named, _)| named == name) {
+            bindings.push((*key, *idx));
+        }
+    }
+    bindings
+}
@@ -5,8 +5,57 @@ mod cpu;
 mod bit;
 mod display;
 mod keyboard;
+mod error;
+mod instruction;
+mod quirks;
+mod platform;
+mod audio;
+mod random;
+mod state;
+mod debugger;
+mod watch;
+mod gdb;
+mod rewind;
+mod movie;
+mod trace;
+mod profile;
+mod coverage;
+mod assembler;
+mod octo;
+mod json;
+mod gif;
+mod cartridge;
+mod sha1;
+mod romdb;
+mod analyzer;
+mod stack;
 
-pub use disassembler::disassemble;
-pub use cpu::Cpu;
-pub use display::Display;
-pub use keyboard::{Keyboard, BINDINGS};
\ No newline at end of file
+pub use disassembler::{disassemble, disassemble_recursive, disassemble_rom, Disassembly, Line, Listing, ListingLine};
+pub use cpu::{Config, Cpu, FrameInfo, StepInfo, DEFAULT_INSTRUCTIONS_PER_FRAME, FRAME_RATE};
+pub use display::{Display, HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH, PLANES};
+pub use keyboard::{bindings_with, Keyboard, BINDINGS, NAMED_KEYS};
+pub use memory::{Data, Memory};
+pub use register::Register;
+pub use error::{CpuError, LoadError};
+pub use instruction::{decode, encode, DecodeError, Instruction};
+pub use quirks::{IndexIncrement, Quirks};
+pub use platform::Platform;
+pub use audio::Audio;
+pub use random::{Random, RandomSource};
+pub use state::StateError;
+pub use debugger::{format_hit, format_memory, format_registers, format_stack, Debugger, Pattern, Stop};
+pub use watch::{WatchHit, WatchKind, Watchpoint, Watchpoints};
+pub use gdb::{Action, GdbStub};
+pub use rewind::Rewind;
+pub use movie::{state_hash, Movie, MovieError, MovieFrame};
+pub use trace::{diff_traces, format_trace, Divergence, Tracer};
+pub use profile::{FrameStats, Profiler, Subroutine, OPCODE_CLASSES};
+pub use coverage::{Coverage, CoverageSummary, EXECUTED, READ, WRITTEN};
+pub use assembler::{assemble, assemble_at, AsmError};
+pub use octo::compile_octo;
+pub use json::Json;
+pub use cartridge::{Cartridge, CartridgeError, CartridgeOptions};
+pub use sha1::{sha1, sha1_hex};
+pub use romdb::{guess, parse_platform, parse_quirks, RomDb, RomDbError, RomInfo};
+pub use analyzer::{analyze, Analysis, Evidence, Finding};
+pub use stack::{memory_slot, Stack, StackFrame, DEFAULT_STACK_DEPTH, MAX_STACK_DEPTH, MEMORY_STACK_DEPTH, MEMORY_STACK_END, MEMORY_STACK_START};
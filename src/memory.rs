@@ -2,6 +2,7 @@ pub trait Memory {
     fn get_u16(&self, idx: usize) -> u16;
     fn set_u8(&mut self, idx: usize, value: u8);
     fn get_u8(&self, idx: usize) -> u8;
+    fn size(&self) -> usize;
     fn clear(&mut self);
 }
 
@@ -31,9 +32,13 @@ impl Memory for Data {
         self.data[idx]
     }
 
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
     fn clear(&mut self) {
         for i in self.data.iter_mut() {
             *i = 0;
         }
     }
-}
\ No newline at end of file
+}
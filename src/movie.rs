@@ -0,0 +1,281 @@
+//Movie file format, every integer is big endian:
+//  magic       4 bytes "C8MV"
+//  version     u16
+//  platform    u8, quirks 5 x u8, same encoding as save states
+//  stack       u8 depth, u8 1 if the stack is kept in memory
+//  seed        u64, seed of the RND generator
+//  ipf         u32, instructions per frame
+//  rom         u32, CRC-32 of the rom
+//  frames      u32 count, then for every frame:
+//                keys  u16 bitmask of the keys pressed during the frame
+//                hash  u32, state_hash after the frame
+//  checksum    u32, CRC-32 of everything before it
+//
+//A frame runs like Cpu::run_frame: the keys are set, ipf instructions are
+//executed and the timers are decremented
+
+use super::cpu::{Config, Cpu, FrameInfo};
+use super::error::{CpuError, LoadError};
+use super::platform::Platform;
+use super::quirks::Quirks;
+use super::random::Random;
+use super::stack::Stack;
+use super::state::{crc32, read_platform, read_quirks, write_platform, write_quirks, Reader, StateError, Writer};
+
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8] = b"C8MV";
+const VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    BadChecksum,
+    Truncated,
+    Invalid(&'static str),
+    RomMismatch,                                            //the movie was recorded with another rom
+    Load(LoadError),                                        //the rom does not fit in memory
+    Cpu { frame: usize, error: CpuError },
+    Diverged { frame: usize, expected: u32, actual: u32 },  //state hashes after the frame
+    Ended { frame: usize },                                 //the frame is past the last recorded one
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::BadMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::BadChecksum => write!(f, "movie checksum mismatch"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Invalid(field) => write!(f, "invalid {} in movie", field),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different rom"),
+            MovieError::Load(error) => write!(f, "{}", error),
+            MovieError::Cpu { frame, error } => write!(f, "frame {}: {}", frame, error),
+            MovieError::Diverged { frame, expected, actual } =>
+                write!(f, "replay diverged at frame {}: state hash {:08x}, expected {:08x}", frame, actual, expected),
+            MovieError::Ended { frame } => write!(f, "movie has no frame {}", frame),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        match err {
+            StateError::BadMagic => MovieError::BadMagic,
+            StateError::UnsupportedVersion(version) => MovieError::UnsupportedVersion(version),
+            StateError::BadChecksum => MovieError::BadChecksum,
+            StateError::Truncated => MovieError::Truncated,
+            StateError::Invalid(field) => MovieError::Invalid(field),
+        }
+    }
+}
+
+//CRC-32 of the memory, the registers, the display, the halted flag and the
+//RND state. The keyboard and the palette are not part of it
+pub fn state_hash(cpu: &Cpu) -> u32 {
+    let mut writer = Writer::new();
+    writer.bytes(&cpu.memory.data);
+    writer.bytes(&cpu.register.v);
+    writer.u16(cpu.register.i);
+    writer.u16(cpu.register.pc);
+    writer.u8(cpu.register.stack.len() as u8);
+    for &addr in cpu.register.stack.iter() {
+        writer.u16(addr);
+    }
+    writer.u8(cpu.register.sound);
+    writer.u8(cpu.register.delay);
+    writer.bytes(&cpu.register.flags);
+    writer.u8(cpu.display.is_hires() as u8);
+    writer.u8(cpu.display.planes());
+    writer.bytes(cpu.display.pixels());
+    writer.bytes(&cpu.audio.pattern);
+    writer.u8(cpu.audio.pitch);
+    writer.u8(cpu.halted as u8);
+    writer.u64(cpu.rng.state());
+    crc32(&writer.finish())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub keys: u16,
+    pub hash: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub stack_depth: usize,
+    pub memory_stack: bool,
+    pub seed: u64,
+    pub instructions_per_frame: usize,
+    pub rom: u32,                   //CRC-32 of the rom
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    //Empty movie for rom, a seed is picked if config has none
+    pub fn new(rom: &[u8], config: Config, instructions_per_frame: usize) -> Self {
+        Movie {
+            platform: config.platform,
+            quirks: config.quirks,
+            stack_depth: Stack::new(config.stack_depth, config.memory_stack).depth(),
+            memory_stack: config.memory_stack,
+            seed: config.seed.unwrap_or_else(|| Random::from_entropy().next_u64()),
+            instructions_per_frame,
+            rom: crc32(rom),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            platform: self.platform,
+            quirks: self.quirks,
+            seed: Some(self.seed),
+            stack_depth: self.stack_depth,
+            memory_stack: self.memory_stack,
+        }
+    }
+
+    //Cpu in the state the movie starts from
+    pub fn cpu(&self, rom: &[u8]) -> Result<Cpu, MovieError> {
+        if crc32(rom) != self.rom {
+            return Err(MovieError::RomMismatch);
+        }
+        Cpu::try_with_config(rom, self.config()).map_err(MovieError::Load)
+    }
+
+    //Appends the frame cpu just ran, call it after every frame
+    pub fn record(&mut self, cpu: &Cpu) {
+        self.frames.push(MovieFrame {
+            keys: cpu.keyboard.bits(),
+            hash: state_hash(cpu),
+        });
+    }
+
+    //Runs frame number frame on cpu and checks the state it ends in
+    pub fn play_frame(&self, cpu: &mut Cpu, frame: usize) -> Result<FrameInfo, MovieError> {
+        let expected = *self.frames.get(frame).ok_or(MovieError::Ended { frame })?;
+        cpu.keyboard.set_bits(expected.keys);
+        let info = cpu.run_frame(self.instructions_per_frame)
+            .map_err(|error| MovieError::Cpu { frame, error })?;
+
+        let actual = state_hash(cpu);
+        if actual != expected.hash {
+            return Err(MovieError::Diverged { frame, expected: expected.hash, actual });
+        }
+        Ok(info)
+    }
+
+    //Replays the whole movie, returns the cpu after the last frame
+    pub fn play(&self, rom: &[u8]) -> Result<Cpu, MovieError> {
+        let mut cpu = self.cpu(rom)?;
+        for frame in 0..self.frames.len() {
+            self.play_frame(&mut cpu, frame)?;
+        }
+        Ok(cpu)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+        write_platform(&mut writer, self.platform);
+        write_quirks(&mut writer, &self.quirks);
+        writer.u8(self.stack_depth as u8);
+        writer.u8(self.memory_stack as u8);
+        writer.u64(self.seed);
+        writer.u32(self.instructions_per_frame as u32);
+        writer.u32(self.rom);
+        writer.u32(self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            writer.u16(frame.keys);
+            writer.u32(frame.hash);
+        }
+
+        let mut data = writer.finish();
+        let checksum = crc32(&data);
+        data.extend_from_slice(&checksum.to_be_bytes());
+        data
+    }
+
+    pub fn from_bytes(movie: &[u8]) -> Result<Self, MovieError> {
+        if movie.len() < MAGIC.len() || &movie[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        if movie.len() < MAGIC.len() + 2 + 4 {
+            return Err(MovieError::Truncated);
+        }
+        let (data, checksum) = movie.split_at(movie.len() - 4);
+        if crc32(data).to_be_bytes() != checksum {
+            return Err(MovieError::BadChecksum);
+        }
+
+        let mut reader = Reader::new(&data[MAGIC.len()..]);
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let platform = read_platform(&mut reader)?;
+        let quirks = read_quirks(&mut reader)?;
+        let stack_depth = usize::from(reader.u8()?);
+        let memory_stack = reader.bool()?;
+        let seed = reader.u64()?;
+        let instructions_per_frame = reader.u32()? as usize;
+        let rom = reader.u32()?;
+        let count = reader.u32()? as usize;
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let keys = reader.u16()?;
+            let hash = reader.u32()?;
+            frames.push(MovieFrame { keys, hash });
+        }
+
+        if !reader.is_empty() {
+            return Err(MovieError::Invalid("length"));
+        }
+        Ok(Movie { platform, quirks, stack_depth, memory_stack, seed, instructions_per_frame, rom, frames })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_movie_round_trips_and_replays() {
+        let rom = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/rom/INVADERS")).unwrap();
+        let config = Config { seed: Some(42), stack_depth: 12, ..Config::new(Platform::Chip8) };
+        let mut movie = Movie::new(&rom, config, 10);
+        let mut cpu = movie.cpu(&rom).unwrap();
+        for frame in 0..120 {
+            cpu.keyboard.set_key(5, frame % 20 < 10);
+            cpu.run_frame(movie.instructions_per_frame).unwrap();
+            movie.record(&cpu);
+        }
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded, movie);
+        assert_eq!(state_hash(&loaded.play(&rom).unwrap()), state_hash(&cpu));
+    }
+
+    #[test]
+    fn divergence_and_wrong_roms_are_reported() {
+        let rom = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+        let mut movie = Movie::new(&rom, Config::default(), 10);
+        let mut cpu = movie.cpu(&rom).unwrap();
+        cpu.run_frame(10).unwrap();
+        movie.record(&cpu);
+
+        assert_eq!(movie.play(&[0x12, 0x00]).err(), Some(MovieError::RomMismatch));
+        movie.frames[0].hash ^= 1;
+        assert!(matches!(movie.play(&rom).err(), Some(MovieError::Diverged { frame: 0, .. })));
+        assert_eq!(movie.play_frame(&mut cpu, 1).err(), Some(MovieError::Ended { frame: 1 }));
+    }
+}
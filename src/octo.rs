@@ -0,0 +1,1024 @@
+//Compiler for Octo (.8o) sources
+//
+//Tokens are separated by whitespace, # starts a comment. Besides the Octo
+//statements (v0 := 5, i := label, sprite v0 v1 5, save v3, ...) it accepts:
+//  : name                  label, the program starts at the one named main
+//  :alias name vX          another name for a register
+//  :const name value       constant
+//  :calc name { expr }     constant computed by an expression, can be redefined
+//  :macro name args { }    macro, invoked by its name followed by its args
+//  :org :byte :pointer :call :next :unpack
+//  loop ... while cond ... again
+//  if cond then statement
+//  if cond begin ... else ... end
+//A number or a constant in place of a statement is emitted as a byte, any
+//other name is a call. Expressions inside { } are evaluated right to left
+//with no operator precedence, like Octo does, parentheses group them
+
+use super::assembler::{parse_number, AsmError};
+use super::instruction::{encode, Instruction};
+
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts;
+
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x10000;
+const MAX_EXPANSIONS: usize = 0x10000;
+
+const KEYWORDS: &[&str] = &[
+    ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=",
+    ";", "return", "clear", "bcd", "save", "load", "saveflags", "loadflags", "sprite", "jump", "jump0",
+    "native", "delay", "buzzer", "pitch", "key", "-key", "random", "hex", "bighex", "long", "i",
+    "if", "then", "begin", "else", "end", "loop", "while", "again", "hires", "lores", "scroll-down",
+    "scroll-up", "scroll-left", "scroll-right", "exit", "plane", "audio",
+];
+
+const UNARY: &[&str] = &["-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor", "@"];
+
+const BINARY: &[&str] = &[
+    "-", "+", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", "==", "!=", ">=", ">",
+];
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+//Value that is either known or the name of a label defined later
+enum Value {
+    Known(i64),
+    Forward(String),
+}
+
+//Way a label defined later is written in the rom
+#[derive(Clone, Copy)]
+enum Patch {
+    Addr,                   //low 12 bits of the instruction
+    Word,                   //the whole word
+    Unpack(Option<u8>),     //v0 and v1 loads of :unpack, with the nibble or long
+}
+
+struct Fixup {
+    addr: usize,
+    patch: Patch,
+    name: String,
+    line: usize,
+}
+
+enum Block {
+    If { jump: usize, line: usize },                        //jump over the body
+    Else { jump: usize, line: usize },                      //jump over the else branch
+    Loop { start: usize, breaks: Vec<usize>, line: usize }, //jumps of the whiles
+}
+
+//Register compared by a condition and what it is compared to
+struct Condition {
+    x: u8,
+    op: String,
+    rhs: Option<Result<u8, u8>>,    //Ok(register) or Err(byte), None for key tests
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (idx, line) in source.lines().enumerate() {
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            let end = match rest.strip_prefix('"') {
+                Some(text) => text.find('"').map_or(rest.len(), |end| end + 2),
+                None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+            };
+            tokens.push_back(Token { text: rest[..end].to_string(), line: idx + 1 });
+            rest = &rest[end..];
+        }
+    }
+    tokens
+}
+
+fn parse_value(text: &str) -> Option<i64> {
+    match text.strip_prefix('-') {
+        Some(rest) => parse_number(rest).map(|value| -value),
+        None => parse_number(text),
+    }
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => digit.to_digit(16).map(|idx| idx as u8),
+        _ => None,
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && !KEYWORDS.contains(&text)
+        && parse_register(text).is_none()
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    used: Vec<bool>,
+    here: usize,
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    has_main: bool,
+    expansions: usize,
+    line: usize,                    //line of the last token read
+}
+
+impl Compiler {
+    fn new(source: &str) -> Self {
+        let mut used = vec![false; MEMORY_SIZE];
+        //Room for the jump to main
+        used[START] = true;
+        used[START + 1] = true;
+        Compiler {
+            tokens: tokenize(source),
+            rom: vec![0; MEMORY_SIZE],
+            used,
+            here: START + 2,
+            end: START + 2,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            has_main: false,
+            expansions: 0,
+            line: 0,
+        }
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.pop_front().ok_or_else(|| "unexpected end of file".to_string())?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.constants.contains_key(name)
+            || self.aliases.contains_key(name) || self.macros.contains_key(name)
+    }
+
+    //Name of something new
+    fn new_name(&mut self) -> Result<String, String> {
+        let name = self.next()?;
+        if !is_name(&name) {
+            return Err(format!("invalid name '{}'", name));
+        }
+        if self.is_defined(&name) {
+            return Err(format!("'{}' is already defined", name));
+        }
+        Ok(name)
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        parse_register(text).or_else(|| self.aliases.get(text).cloned())
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.register_of(&token).ok_or_else(|| format!("expected a register, found '{}'", token))
+    }
+
+    fn peek_register(&self) -> Option<u8> {
+        self.peek().and_then(|token| self.register_of(token))
+    }
+
+    fn lookup(&self, name: &str) -> Option<f64> {
+        match self.constants.get(name) {
+            Some(&value) => Some(value),
+            None => self.labels.get(name).map(|&addr| addr as f64),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let token = self.next()?;
+        if token == "{" {
+            return Ok(Value::Known(self.calc_block()? as i64));
+        }
+        if let Some(value) = parse_value(&token) {
+            return Ok(Value::Known(value));
+        }
+        if let Some(value) = self.lookup(&token) {
+            return Ok(Value::Known(value as i64));
+        }
+        if is_name(&token) && !self.is_defined(&token) {
+            return Ok(Value::Forward(token));
+        }
+        Err(format!("expected a value, found '{}'", token))
+    }
+
+    fn known(&mut self) -> Result<i64, String> {
+        match self.value()? {
+            Value::Known(value) => Ok(value),
+            Value::Forward(name) => Err(format!("undefined name '{}'", name)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.known()?;
+        if !(-128..=0xff).contains(&value) {
+            return Err(format!("byte {} out of range", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        let value = self.known()?;
+        if !(0..=0xf).contains(&value) {
+            return Err(format!("nibble {} out of range", value));
+        }
+        Ok(value as u8)
+    }
+
+    //Address used by the instruction about to be emitted at here, a name
+    //defined later is patched at the end
+    fn address(&mut self, patch: Patch) -> Result<u16, String> {
+        match self.value()? {
+            Value::Known(value) => check_address(value, patch),
+            Value::Forward(name) => {
+                self.fixups.push(Fixup { addr: self.here, patch, name, line: self.line });
+                Ok(0)
+            },
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= MEMORY_SIZE {
+            return Err("program does not fit in memory".to_string());
+        }
+        if self.used[self.here] {
+            return Err(format!("data overlap at {:#06x}", self.here));
+        }
+        self.rom[self.here] = byte;
+        self.used[self.here] = true;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit_word(&mut self, word: u16) -> Result<(), String> {
+        let [high, low] = word.to_be_bytes();
+        self.emit_byte(high)?;
+        self.emit_byte(low)
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result<(), String> {
+        self.emit_word(encode(instruction))
+    }
+
+    //Jump whose target is patched when the block ends, returns its address
+    fn emit_placeholder(&mut self) -> Result<usize, String> {
+        let addr = self.here;
+        self.emit(Instruction::Jp(0))?;
+        Ok(addr)
+    }
+
+    fn patch_jump(&mut self, addr: usize, target: usize) -> Result<(), String> {
+        let target = check_address(target as i64, Patch::Addr)?;
+        self.rom[addr..addr+2].copy_from_slice(&encode(Instruction::Jp(target)).to_be_bytes());
+        Ok(())
+    }
+
+    fn label(&mut self, name: String) -> Result<(), String> {
+        if name == "main" {
+            if self.here == START + 2 && self.end == START + 2 {
+                self.used[START] = false;
+                self.used[START + 1] = false;
+                self.here = START;
+                self.end = START;
+            } else {
+                let target = check_address(self.here as i64, Patch::Addr)?;
+                self.rom[START..START+2].copy_from_slice(&encode(Instruction::Jp(target)).to_be_bytes());
+            }
+            self.has_main = true;
+        }
+        self.labels.insert(name, self.here);
+        Ok(())
+    }
+
+    //Tokens up to the closing brace, the opening one was already read
+    fn block_tokens(&mut self) -> Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.tokens.pop_front().ok_or_else(|| "missing '}'".to_string())?;
+            self.line = token.line;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {},
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn calc_block(&mut self) -> Result<f64, String> {
+        let tokens: Vec<String> = self.block_tokens()?.into_iter().map(|token| token.text).collect();
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(format!("unexpected '{}' in expression", token)),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(&self, tokens: &[String], pos: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*pos).ok_or_else(|| "incomplete expression".to_string())?.as_str();
+        *pos += 1;
+
+        if UNARY.contains(&token) {
+            let value = self.expression(tokens, pos)?;
+            return Ok(match token {
+                "-" => -value,
+                "~" => !(value as i64) as f64,
+                "!" => (value == 0.0) as i64 as f64,
+                "sin" => value.sin(),
+                "cos" => value.cos(),
+                "tan" => value.tan(),
+                "exp" => value.exp(),
+                "log" => value.ln(),
+                "abs" => value.abs(),
+                "sqrt" => value.sqrt(),
+                "sign" => if value == 0.0 { 0.0 } else { value.signum() },
+                "ceil" => value.ceil(),
+                "floor" => value.floor(),
+                _ => f64::from(*self.rom.get(value as usize).unwrap_or(&0)),
+            });
+        }
+
+        let left = match token {
+            "(" => {
+                let value = self.expression(tokens, pos)?;
+                if tokens.get(*pos).map(String::as_str) != Some(")") {
+                    return Err("missing ')' in expression".to_string());
+                }
+                *pos += 1;
+                value
+            },
+            "PI" => consts::PI,
+            "E" => consts::E,
+            "HERE" => self.here as f64,
+            _ => match parse_value(token) {
+                Some(value) => value as f64,
+                None => self.lookup(token).ok_or_else(|| format!("undefined name '{}'", token))?,
+            },
+        };
+
+        let op = match tokens.get(*pos) {
+            Some(op) if BINARY.contains(&op.as_str()) => op.as_str(),
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.expression(tokens, pos)?;
+        let int = |value: f64| value as i64;
+        Ok(match op {
+            "-" => left - right,
+            "+" => left + right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (int(left) & int(right)) as f64,
+            "|" => (int(left) | int(right)) as f64,
+            "^" => (int(left) ^ int(right)) as f64,
+            "<<" => int(left).checked_shl(int(right) as u32).unwrap_or(0) as f64,
+            ">>" => int(left).checked_shr(int(right) as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            _ => (left > right) as i64 as f64,
+        })
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.new_name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+        let body = self.block_tokens()?;
+        self.macros.insert(name, (args, body));
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(format!("too many expansions of macro '{}'", name));
+        }
+        let (args, body) = self.macros[name].clone();
+        let mut values = Vec::new();
+        for _ in 0..args.len() {
+            values.push(self.next()?);
+        }
+        for token in body.into_iter().rev() {
+            let text = match args.iter().position(|arg| *arg == token.text) {
+                Some(idx) => values[idx].clone(),
+                None => token.text,
+            };
+            self.tokens.push_front(Token { text, line: token.line });
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+        let op = self.next()?;
+        let rhs = match op.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => Some(match self.peek_register() {
+                Some(y) => {
+                    self.next()?;
+                    Ok(y)
+                },
+                None => Err(self.byte()?),
+            }),
+            _ => return Err(format!("invalid comparison '{}'", op)),
+        };
+        Ok(Condition { x, op, rhs })
+    }
+
+    //Instructions that skip the next one when the condition is when. The
+    //ordered comparisons use VF as a scratch register
+    fn skip_if(&mut self, condition: Condition, when: bool) -> Result<(), String> {
+        let Condition { x, op, rhs } = condition;
+        let rhs = rhs.unwrap_or(Err(0));
+        let equal = (op == "==") == when;
+        match (op.as_str(), rhs) {
+            ("key", _) => self.emit(if when { Instruction::Skp(x) } else { Instruction::Sknp(x) }),
+            ("-key", _) => self.emit(if when { Instruction::Sknp(x) } else { Instruction::Skp(x) }),
+            ("==", Ok(y)) | ("!=", Ok(y)) => self.emit(if equal { Instruction::SeReg(x, y) } else { Instruction::SneReg(x, y) }),
+            ("==", Err(kk)) | ("!=", Err(kk)) => self.emit(if equal { Instruction::SeByte(x, kk) } else { Instruction::SneByte(x, kk) }),
+            (op, rhs) => {
+                self.emit(match rhs {
+                    Ok(y) => Instruction::LdReg(0xf, y),
+                    Err(kk) => Instruction::LdByte(0xf, kk),
+                })?;
+                //VF = x - rhs flags x >= rhs, VF = rhs - x flags x <= rhs
+                let (instruction, flag) = match op {
+                    "<" => (Instruction::Subn(0xf, x), 0),
+                    ">=" => (Instruction::Subn(0xf, x), 1),
+                    ">" => (Instruction::Sub(0xf, x), 0),
+                    _ => (Instruction::Sub(0xf, x), 1),
+                };
+                self.emit(instruction)?;
+                self.emit(Instruction::SeByte(0xf, if when { flag } else { 1 - flag }))
+            },
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), String> {
+        let op = self.next()?;
+        let y = self.peek_register();
+        if y.is_some() {
+            self.next()?;
+        }
+        let instruction = match (op.as_str(), y) {
+            (":=", Some(y)) => Instruction::LdReg(x, y),
+            (":=", None) => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    Instruction::Rnd(x, self.byte()?)
+                },
+                Some("key") => {
+                    self.next()?;
+                    Instruction::LdVxK(x)
+                },
+                Some("delay") => {
+                    self.next()?;
+                    Instruction::LdVxDt(x)
+                },
+                _ => Instruction::LdByte(x, self.byte()?),
+            },
+            ("+=", Some(y)) => Instruction::AddReg(x, y),
+            ("+=", None) => Instruction::AddByte(x, self.byte()?),
+            ("-=", Some(y)) => Instruction::Sub(x, y),
+            ("-=", None) => Instruction::AddByte(x, self.byte()?.wrapping_neg()),
+            ("=-", Some(y)) => Instruction::Subn(x, y),
+            ("|=", Some(y)) => Instruction::Or(x, y),
+            ("&=", Some(y)) => Instruction::And(x, y),
+            ("^=", Some(y)) => Instruction::Xor(x, y),
+            (">>=", Some(y)) => Instruction::Shr(x, y),
+            ("<<=", Some(y)) => Instruction::Shl(x, y),
+            ("=-", None) | ("|=", None) | ("&=", None) | ("^=", None) | (">>=", None) | ("<<=", None) =>
+                return Err(format!("'{}' needs a register", op)),
+            _ => return Err(format!("invalid operator '{}'", op)),
+        };
+        self.emit(instruction)
+    }
+
+    fn i_statement(&mut self) -> Result<(), String> {
+        match self.next()?.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::LdF(x))
+                },
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::LdHf(x))
+                },
+                Some("long") => {
+                    self.next()?;
+                    self.emit(Instruction::LdILong)?;
+                    let addr = self.address(Patch::Word)?;
+                    self.emit_word(addr)
+                },
+                _ => {
+                    let addr = self.address(Patch::Addr)?;
+                    self.emit(Instruction::LdI(addr))
+                },
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddI(x))
+            },
+            op => Err(format!("invalid operator '{}' for i", op)),
+        }
+    }
+
+    fn unpack(&mut self) -> Result<(), String> {
+        let nibble = if self.peek() == Some("long") {
+            self.next()?;
+            None
+        } else {
+            Some(self.nibble()?)
+        };
+        let patch = Patch::Unpack(nibble);
+        let addr = self.address(patch)?;
+        let (high, low) = unpack_bytes(addr, nibble);
+        self.emit(Instruction::LdByte(0, high))?;
+        self.emit(Instruction::LdByte(1, low))
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.new_name()?;
+                self.label(name)
+            },
+            ":alias" => {
+                let name = self.new_name()?;
+                let x = self.register()?;
+                self.aliases.insert(name, x);
+                Ok(())
+            },
+            ":const" => {
+                let name = self.new_name()?;
+                let value = self.known()?;
+                self.constants.insert(name, value as f64);
+                Ok(())
+            },
+            ":calc" => {
+                let name = self.next()?;
+                if !is_name(&name) || (self.is_defined(&name) && !self.constants.contains_key(&name)) {
+                    return Err(format!("invalid name '{}'", name));
+                }
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":macro" => self.define_macro(),
+            ":org" => {
+                //The rom starts at 0x200, code below it would be lost
+                let addr = self.known()?;
+                if !(START as i64..MEMORY_SIZE as i64).contains(&addr) {
+                    return Err(format!("address {} out of range, the program starts at 0x200", addr));
+                }
+                self.here = addr as usize;
+                Ok(())
+            },
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(byte)
+            },
+            ":pointer" => {
+                let addr = self.address(Patch::Word)?;
+                self.emit_word(addr)
+            },
+            ":call" => {
+                let addr = self.address(Patch::Addr)?;
+                self.emit(Instruction::Call(addr))
+            },
+            ":next" => {
+                let name = self.new_name()?;
+                self.labels.insert(name, self.here + 1);
+                Ok(())
+            },
+            ":unpack" => self.unpack(),
+            ":breakpoint" | ":proto" => self.next().map(|_| ()),
+            ":monitor" => self.next().and_then(|_| self.next()).map(|_| ()),
+            ";" | "return" => self.emit(Instruction::Ret),
+            "clear" => self.emit(Instruction::Cls),
+            "hires" => self.emit(Instruction::High),
+            "lores" => self.emit(Instruction::Low),
+            "scroll-left" => self.emit(Instruction::ScrollLeft),
+            "scroll-right" => self.emit(Instruction::ScrollRight),
+            "exit" => self.emit(Instruction::Exit),
+            "audio" => self.emit(Instruction::Audio),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollDown(n))
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollUp(n))
+            },
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::Plane(n))
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdB(x))
+            },
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdRVx(x))
+            },
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdVxR(x))
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                let range = if self.peek() == Some("-") {
+                    self.next()?;
+                    Some(self.register()?)
+                } else {
+                    None
+                };
+                self.emit(match (token.as_str(), range) {
+                    ("save", Some(y)) => Instruction::SaveRange(x, y),
+                    ("save", None) => Instruction::LdMemVx(x),
+                    (_, Some(y)) => Instruction::LoadRange(x, y),
+                    (_, None) => Instruction::LdVxMem(x),
+                })
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Drw(x, y, n))
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token.as_str() {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                })
+            },
+            "jump" => {
+                let addr = self.address(Patch::Addr)?;
+                self.emit(Instruction::Jp(addr))
+            },
+            "jump0" => {
+                let addr = self.address(Patch::Addr)?;
+                self.emit(Instruction::JpV0(addr))
+            },
+            "native" => {
+                let addr = self.address(Patch::Addr)?;
+                self.emit(Instruction::Sys(addr))
+            },
+            "i" => self.i_statement(),
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.skip_if(condition, false),
+                    "begin" => {
+                        self.skip_if(condition, true)?;
+                        let jump = self.emit_placeholder()?;
+                        self.blocks.push(Block::If { jump, line: self.line });
+                        Ok(())
+                    },
+                    other => Err(format!("expected 'then' or 'begin', found '{}'", other)),
+                }
+            },
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => {
+                    let skip = self.emit_placeholder()?;
+                    self.patch_jump(jump, self.here)?;
+                    self.blocks.push(Block::Else { jump: skip, line: self.line });
+                    Ok(())
+                },
+                _ => Err("'else' without 'if ... begin'".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) | Some(Block::Else { jump, .. }) => self.patch_jump(jump, self.here),
+                _ => Err("'end' without 'if ... begin'".to_string()),
+            },
+            "loop" => {
+                self.blocks.push(Block::Loop { start: self.here, breaks: Vec::new(), line: self.line });
+                Ok(())
+            },
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_if(condition, true)?;
+                let jump = self.emit_placeholder()?;
+                let innermost = self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                });
+                match innermost {
+                    Some(breaks) => {
+                        breaks.push(jump);
+                        Ok(())
+                    },
+                    None => Err("'while' outside of a loop".to_string()),
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks, .. }) => {
+                    let start = check_address(start as i64, Patch::Addr)?;
+                    self.emit(Instruction::Jp(start))?;
+                    for jump in breaks {
+                        self.patch_jump(jump, self.here)?;
+                    }
+                    Ok(())
+                },
+                _ => Err("'again' without 'loop'".to_string()),
+            },
+            _ => {
+                if let Some(x) = self.register_of(&token) {
+                    return self.register_statement(x);
+                }
+                if self.macros.contains_key(&token) {
+                    return self.expand_macro(&token);
+                }
+                if let Some(value) = parse_value(&token).or_else(|| self.constants.get(&token).map(|&value| value as i64)) {
+                    if !(-128..=0xff).contains(&value) {
+                        return Err(format!("byte {} out of range", value));
+                    }
+                    return self.emit_byte(value as u8);
+                }
+                if is_name(&token) {
+                    self.tokens.push_front(Token { text: token, line: self.line });
+                    let addr = self.address(Patch::Addr)?;
+                    return self.emit(Instruction::Call(addr));
+                }
+                Err(format!("unexpected '{}'", token))
+            },
+        }
+    }
+
+    fn resolve(&mut self) -> Result<(), AsmError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let error = |message: String| AsmError { line: fixup.line, message };
+            let value = self.lookup(&fixup.name)
+                .ok_or_else(|| error(format!("undefined name '{}'", fixup.name)))?;
+            let value = check_address(value as i64, fixup.patch).map_err(error)?;
+            let addr = fixup.addr;
+            match fixup.patch {
+                Patch::Addr => {
+                    self.rom[addr] = (self.rom[addr] & 0xf0) | (value >> 8) as u8;
+                    self.rom[addr + 1] = value as u8;
+                },
+                Patch::Word => self.rom[addr..addr+2].copy_from_slice(&value.to_be_bytes()),
+                Patch::Unpack(nibble) => {
+                    let (high, low) = unpack_bytes(value, nibble);
+                    self.rom[addr + 1] = high;
+                    self.rom[addr + 3] = low;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_address(value: i64, patch: Patch) -> Result<u16, String> {
+    let max = match patch {
+        Patch::Addr | Patch::Unpack(Some(_)) => 0xfff,
+        Patch::Word | Patch::Unpack(None) => 0xffff,
+    };
+    if !(0..=max).contains(&value) {
+        return Err(format!("address {:#x} out of range", value));
+    }
+    Ok(value as u16)
+}
+
+//Values of v0 and v1 set by :unpack
+fn unpack_bytes(addr: u16, nibble: Option<u8>) -> (u8, u8) {
+    let [high, low] = addr.to_be_bytes();
+    match nibble {
+        Some(nibble) => (nibble << 4 | high, low),
+        None => (high, low),
+    }
+}
+
+//Compiles an Octo program, the rom starts at 0x200
+pub fn compile_octo(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut compiler = Compiler::new(source);
+    while !compiler.tokens.is_empty() {
+        compiler.statement().map_err(|message| AsmError { line: compiler.line, message })?;
+    }
+
+    if let Some(block) = compiler.blocks.last() {
+        let (line, message) = match block {
+            Block::If { line, .. } | Block::Else { line, .. } => (*line, "'if ... begin' without 'end'"),
+            Block::Loop { line, .. } => (*line, "'loop' without 'again'"),
+        };
+        return Err(AsmError { line, message: message.to_string() });
+    }
+    if !compiler.has_main {
+        return Err(AsmError { line: compiler.line, message: "missing main label".to_string() });
+    }
+    compiler.resolve()?;
+
+    Ok(compiler.rom[START..compiler.end.max(START)].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn rom(instructions: &[Instruction]) -> Vec<u8> {
+        instructions.iter().flat_map(|&instruction| encode(instruction).to_be_bytes().to_vec()).collect()
+    }
+
+    fn holds(op: &str, a: u8, b: u8) -> bool {
+        match op {
+            "==" => a == b,
+            "!=" => a != b,
+            "<" => a < b,
+            ">" => a > b,
+            "<=" => a <= b,
+            _ => a >= b,
+        }
+    }
+
+    //Compiles source and runs it for steps instructions
+    fn run(source: &str, steps: usize, pressed: Option<usize>) -> Cpu {
+        let mut cpu = Cpu::new(&compile_octo(source).unwrap());
+        if let Some(key) = pressed {
+            cpu.keyboard.set_key(key, true);
+        }
+        for _ in 0..steps {
+            cpu.next().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn main_is_jumped_to_when_it_is_not_first() {
+        assert_eq!(compile_octo(": main v0 := 1").unwrap(), rom(&[Instruction::LdByte(0, 1)]));
+        assert_eq!(compile_octo(": helper v0 := 1 ; : main helper").unwrap(), rom(&[
+            Instruction::Jp(0x206),
+            Instruction::LdByte(0, 1),
+            Instruction::Ret,
+            Instruction::Call(0x202),
+        ]));
+        assert_eq!(compile_octo(": helper ;").unwrap_err().message, "missing main label");
+    }
+
+    #[test]
+    fn forward_labels_are_fixed_up() {
+        let source = "
+            : main
+                jump done
+                i := long data
+                :unpack 0xA data
+                :unpack long data
+                i := data
+            : done ;
+            : data 0x12";
+        let mut expected = rom(&[
+            Instruction::Jp(0x210),
+            Instruction::LdILong,
+        ]);
+        expected.extend_from_slice(&[0x02, 0x12]);
+        expected.extend(rom(&[
+            Instruction::LdByte(0, 0xa2),
+            Instruction::LdByte(1, 0x12),
+            Instruction::LdByte(0, 0x02),
+            Instruction::LdByte(1, 0x12),
+            Instruction::LdI(0x212),
+            Instruction::Ret,
+        ]));
+        expected.push(0x12);
+        assert_eq!(compile_octo(source).unwrap(), expected);
+
+        let error = compile_octo(": main\njump nowhere").unwrap_err();
+        assert_eq!(error, AsmError { line: 2, message: "undefined name 'nowhere'".to_string() });
+    }
+
+    #[test]
+    fn every_comparison_branches_like_its_operator() {
+        for op in ["==", "!=", "<", ">", "<=", ">="].iter() {
+            for &a in &[3, 5, 7] {
+                for rhs in &["v2", "5"] {
+                    let setup = format!(": main v1 := {} v2 := 5 if v1 {} {}", a, op, rhs);
+                    let then = run(&format!("{} then v3 := 1 v4 := 1 loop again", setup), 8, None);
+                    assert_eq!(then.register.v[3] == 1, holds(op, a, 5), "{} then, v1 = {}", op, a);
+                    assert_eq!(then.register.v[4], 1);
+
+                    let branches = run(&format!("{} begin v3 := 1 else v3 := 2 end v4 := 1 loop again", setup), 8, None);
+                    assert_eq!(branches.register.v[3], if holds(op, a, 5) { 1 } else { 2 }, "{} begin, v1 = {}", op, a);
+                    assert_eq!(branches.register.v[4], 1);
+                }
+            }
+        }
+
+        for &(op, pressed) in &[("key", true), ("-key", false)] {
+            for &key in &[None, Some(4)] {
+                let source = format!(": main v1 := 4 if v1 {} then v3 := 1 v4 := 1 loop again", op);
+                let cpu = run(&source, 4, key);
+                assert_eq!(cpu.register.v[3] == 1, key.is_some() == pressed, "{} {:?}", op, key);
+            }
+        }
+    }
+
+    #[test]
+    fn loops_exit_through_while() {
+        let source = "
+            : main
+                v0 := 0
+                loop
+                    v0 += 1
+                    while v0 != 5
+                    v1 += 2
+                again
+                v2 := 7
+            : stop
+                jump stop";
+        let cpu = run(source, 100, None);
+        assert_eq!(&cpu.register.v[0..3], &[5, 8, 7]);
+
+        let error = compile_octo(": main\nloop\nv0 += 1").unwrap_err();
+        assert_eq!(error, AsmError { line: 2, message: "'loop' without 'again'".to_string() });
+        assert!(compile_octo(": main while v0 == 1").is_err());
+    }
+
+    #[test]
+    fn macros_and_calc_expand_to_constants() {
+        let source = "
+            :macro set reg value { reg := value }
+            :calc double { 2 * 21 }
+            :calc double { double + 1 }
+            :calc mixed { 1 + 2 * 3 }
+            : main
+                set v3 double
+                set v4 mixed";
+        assert_eq!(compile_octo(source).unwrap(), rom(&[Instruction::LdByte(3, 43), Instruction::LdByte(4, 7)]));
+
+        let error = compile_octo(":macro forever { forever } : main forever").unwrap_err();
+        assert_eq!(error.message, "too many expansions of macro 'forever'");
+    }
+
+    #[test]
+    fn org_moves_the_output_and_rejects_overlaps() {
+        let program = compile_octo(": main jump far :org 0x300 : far ;").unwrap();
+        assert_eq!(program.len(), 0x102);
+        assert_eq!(&program[..2], &rom(&[Instruction::Jp(0x300)])[..]);
+        assert_eq!(&program[0x100..], &rom(&[Instruction::Ret])[..]);
+
+        let error = compile_octo(": main v0 := 1\n:org 0x200 v1 := 2").unwrap_err();
+        assert_eq!(error, AsmError { line: 2, message: "data overlap at 0x0200".to_string() });
+    }
+}
@@ -0,0 +1,56 @@
+use super::quirks::Quirks;
+
+//Interpreter whose instruction set the cpu implements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Chip8,                      //original instruction set, 64x32 display
+    SuperChip,                  //SUPER-CHIP 1.1, adds scrolling, 128x64 mode, big font and RPL flags
+    XoChip,                     //XO-CHIP, SUPER-CHIP plus 64 KiB of memory, two bitplanes and audio
+}
+
+impl Platform {
+    //Quirks preset of the reference interpreter of the platform
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::cosmac_vip(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xochip(),
+        }
+    }
+
+    //maxSize Octo writes in the cartridges of the platform, the memory it
+    //leaves to the program
+    pub fn octo_max_size(self) -> usize {
+        match self {
+            Platform::Chip8 => 3216,
+            Platform::SuperChip => 3583,
+            Platform::XoChip => 65024,
+        }
+    }
+
+    //Levels of the call stack of the reference interpreter
+    pub fn stack_depth(self) -> usize {
+        match self {
+            Platform::Chip8 => 12,
+            Platform::SuperChip | Platform::XoChip => 16,
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    pub fn has_superchip(self) -> bool {
+        match self {
+            Platform::Chip8 => false,
+            Platform::SuperChip | Platform::XoChip => true,
+        }
+    }
+
+    pub fn has_xochip(self) -> bool {
+        self == Platform::XoChip
+    }
+}
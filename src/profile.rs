@@ -0,0 +1,209 @@
+//Instruction level profiler, fed by the cpu after every executed instruction
+//and every frame
+
+use super::disassembler::disassemble;
+
+use std::collections::BTreeMap;
+
+//Opcode classes of the histogram, indexed by the high nibble
+pub const OPCODE_CLASSES: [&str; 16] = [
+    "0NNN system",
+    "1NNN JP",
+    "2NNN CALL",
+    "3XNN SE",
+    "4XNN SNE",
+    "5XYN SE/range",
+    "6XNN LD",
+    "7XNN ADD",
+    "8XYN ALU",
+    "9XY0 SNE",
+    "ANNN LD I",
+    "BNNN JP V0",
+    "CXNN RND",
+    "DXYN DRW",
+    "EXNN SKP/SKNP",
+    "FXNN misc",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subroutine {
+    pub calls: u64,
+    pub returns: u64,
+    pub cycles: u64,                //instructions executed inside, nested calls included
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub frames: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+}
+
+pub struct Profiler {
+    pub counts: Vec<u64>,                           //executions of each PC
+    pub classes: [u64; 16],                         //executions of each opcode class
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    pub frames: FrameStats,                         //instructions executed per frame
+    pub key_wait: u64,                              //FX0A executions that found no key pressed
+    pub instructions: u64,
+    calls: Vec<(u16, u64)>,                         //subroutines being executed and when they were called
+    frame_start: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            counts: vec![0; 0x10000],
+            classes: [0; 16],
+            subroutines: BTreeMap::new(),
+            frames: FrameStats::default(),
+            key_wait: 0,
+            instructions: 0,
+            calls: Vec::new(),
+            frame_start: 0,
+        }
+    }
+
+    //Instruction opcode at pc was executed, next is the PC after it
+    pub fn on_step(&mut self, pc: u16, opcode: u16, next: u16) {
+        self.instructions += 1;
+        self.counts[usize::from(pc)] += 1;
+        self.classes[usize::from(opcode >> 12)] += 1;
+
+        if opcode & 0xf000 == 0x2000 {
+            let target = opcode & 0x0fff;
+            self.subroutines.entry(target).or_default().calls += 1;
+            self.calls.push((target, self.instructions));
+        } else if opcode == 0x00ee {
+            if let Some((target, start)) = self.calls.pop() {
+                let subroutine = self.subroutines.entry(target).or_default();
+                subroutine.returns += 1;
+                subroutine.cycles += self.instructions - start;
+            }
+        } else if opcode & 0xf0ff == 0xf00a && next == pc {
+            self.key_wait += 1;
+        }
+    }
+
+    pub fn on_frame(&mut self) {
+        let cycles = self.instructions - self.frame_start;
+        self.frame_start = self.instructions;
+
+        let frames = &mut self.frames;
+        frames.min = if frames.frames == 0 { cycles } else { frames.min.min(cycles) };
+        frames.max = frames.max.max(cycles);
+        frames.total += cycles;
+        frames.frames += 1;
+    }
+
+    //Most executed addresses, busiest first
+    pub fn hot_spots(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = self.counts.iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(addr, &count)| (addr as u16, count))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(count);
+        hot
+    }
+
+    //Subroutines by instructions spent inside them, busiest first
+    pub fn busiest_subroutines(&self, count: usize) -> Vec<(u16, Subroutine)> {
+        let mut busiest: Vec<(u16, Subroutine)> = self.subroutines.iter()
+            .map(|(&addr, &subroutine)| (addr, subroutine))
+            .collect();
+        busiest.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(b.1.calls.cmp(&a.1.calls)).then(a.0.cmp(&b.0)));
+        busiest.truncate(count);
+        busiest
+    }
+
+    //Text report, memory is used to disassemble the hot addresses
+    pub fn report(&self, memory: &[u8], top: usize) -> String {
+        let percent = |count: u64| if self.instructions == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.instructions as f64
+        };
+        let mut lines = Vec::new();
+
+        lines.push(format!("instructions: {}", self.instructions));
+        if self.frames.frames > 0 {
+            lines.push(format!("frames: {}, instructions per frame: min {} avg {:.1} max {}",
+                self.frames.frames, self.frames.min,
+                self.frames.total as f64 / self.frames.frames as f64, self.frames.max));
+        }
+        lines.push(format!("key wait (FX0A): {} instructions, {:.1}%", self.key_wait, percent(self.key_wait)));
+
+        lines.push(String::new());
+        lines.push("hot addresses:".to_string());
+        for (addr, count) in self.hot_spots(top) {
+            let addr = usize::from(addr);
+            let text = match memory.get(addr..addr+2) {
+                Some(bytes) => disassemble(u16::from(bytes[0]) << 8 | u16::from(bytes[1])).to_string(),
+                None => String::new(),
+            };
+            lines.push(format!("  {:04x}  {:>10}  {:>5.1}%  {}", addr, count, percent(count), text));
+        }
+
+        lines.push(String::new());
+        lines.push("opcode classes:".to_string());
+        for (class, &count) in OPCODE_CLASSES.iter().zip(self.classes.iter()) {
+            if count > 0 {
+                lines.push(format!("  {:<14}  {:>10}  {:>5.1}%", class, count, percent(count)));
+            }
+        }
+
+        lines.push(String::new());
+        lines.push("subroutines:".to_string());
+        for (addr, subroutine) in self.busiest_subroutines(top) {
+            lines.push(format!("  {:04x}  {:>8} calls  {:>10} instructions  {:>5.1}%",
+                addr, subroutine.calls, subroutine.cycles, percent(subroutine.cycles)));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    //Calls a subroutine twice, then waits for a key
+    const PROGRAM: &[u8] = &[
+        0x60, 0x02,     //200: LD V0, 2
+        0x22, 0x0c,     //202: CALL 20C
+        0x70, 0xff,     //204: ADD V0, FF
+        0x30, 0x00,     //206: SE V0, 0
+        0x12, 0x02,     //208: JP 202
+        0xf1, 0x0a,     //20A: LD V1, K
+        0x61, 0x05,     //20C: LD V1, 5
+        0x00, 0xee,     //20E: RET
+    ];
+
+    #[test]
+    fn profiler_counts_instructions_subroutines_and_key_waits() {
+        let mut cpu = Cpu::new(PROGRAM);
+        cpu.profiler = Some(Profiler::new());
+        for _ in 0..3 {
+            cpu.run_frame(5).unwrap();
+        }
+        let profiler = cpu.profiler.unwrap();
+
+        assert_eq!(profiler.instructions, 15);
+        assert_eq!(profiler.frames, FrameStats { frames: 3, min: 5, max: 5, total: 15 });
+        assert_eq!(profiler.key_wait, 3);
+        assert_eq!(profiler.hot_spots(2), vec![(0x20a, 3), (0x202, 2)]);
+        assert_eq!((profiler.classes[0x0], profiler.classes[0x2], profiler.classes[0xf]), (2, 2, 3));
+        assert_eq!(profiler.busiest_subroutines(1),
+            vec![(0x20c, Subroutine { calls: 2, returns: 2, cycles: 4 })]);
+    }
+}
@@ -0,0 +1,76 @@
+//How FX55 and FX65 update I after accessing memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    Unchanged,                  //I is left untouched
+    X,                          //I = I + x
+    XPlusOne,                   //I = I + x + 1
+}
+
+//Behaviour of the instructions that differ between interpreters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,            //8XY6/8XYE: Vx = Vy shifted instead of shifting Vx in place
+    pub load_store: IndexIncrement,     //FX55/FX65: I increment after the transfer
+    pub jump_uses_vx: bool,             //BNNN: jump to NNN + Vx (X = high nibble of NNN) instead of V0
+    pub logic_resets_vf: bool,          //8XY1/8XY2/8XY3: VF = 0 after the operation
+    pub clip_sprites: bool,             //DXYN: sprites are clipped at the screen edges instead of wrapping
+}
+
+//The behaviour this interpreter had before quirks were configurable
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: IndexIncrement::Unchanged,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+}
+
+impl Quirks {
+    //Original RCA COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: IndexIncrement::XPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+        }
+    }
+
+    //CHIP-48 for the HP-48 calculators
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: IndexIncrement::X,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    //SUPER-CHIP 1.1
+    pub fn superchip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: IndexIncrement::Unchanged,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    //XO-CHIP as implemented by Octo
+    pub fn xochip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: IndexIncrement::XPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+}
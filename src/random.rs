@@ -0,0 +1,53 @@
+extern crate rand;
+
+use rand::Rng;
+
+//Source of the numbers returned by RND, the whole state must fit in a u64 so
+//that save states and replays can restore it
+pub trait RandomSource {
+    fn next_u8(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+//xorshift64* generator, small enough to snapshot its whole state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        let mut random = Random { state: 0 };
+        random.set_state(seed);
+        random
+    }
+
+    //Seeded from the operating system
+    pub fn from_entropy() -> Self {
+        Random::new(rand::thread_rng().gen())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+}
+
+impl RandomSource for Random {
+    fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    //The all zero state is a fixed point of xorshift, it is replaced by a constant
+    fn set_state(&mut self, state: u64) {
+        self.state = if state == 0 { 0x9e37_79b9_7f4a_7c15 } else { state };
+    }
+}
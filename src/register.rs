@@ -1,11 +1,14 @@
-#[derive(Default, Debug)]
+use super::stack::Stack;
+
+#[derive(Default, Debug, Clone)]
 pub struct Register {
     pub v: Vec<u8>,         //16 8-bit register indexed from 0x0 to 0xF, V[0xF] contains Flags
     pub i: u16,             //only the first 12 bits are used, memory address
     pub pc: u16,            //Program Counter
-    pub stack: Vec<u16>,    //Stack
+    pub stack: Stack,       //Stack
     pub sound: u8,          //Sound timer
     pub delay: u8,          //Delay timer
+    pub flags: Vec<u8>,     //SUPER-CHIP RPL user flags
 }
 
 impl Register {
@@ -14,9 +17,10 @@ impl Register {
             v: vec![0x0; 0x10],
             i: 0,
             pc: 0x200,
-            stack: Vec::new(),
+            stack: Stack::default(),
             sound: 0,
             delay: 0,
+            flags: vec![0x0; 0x10],
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,297 @@
+//Rewind buffer: a bounded ring of snapshots taken at frame boundaries
+//
+//Only the newest snapshot keeps a full copy of the memory, every older one
+//stores the bytes that differ from the snapshot after it, so a snapshot
+//costs its registers, the display packed at 2 bits per pixel and the bytes
+//the rom wrote in between
+
+use super::audio::Audio;
+use super::cpu::Cpu;
+use super::display::{HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH};
+use super::register::Register;
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+struct Snapshot {
+    frame: u64,                 //cpu.frames when taken
+    cycles: u64,                //cpu.cycles when taken
+    register: Register,
+    hires: bool,
+    planes: u8,
+    pixels: Vec<u8>,            //4 pixels per byte
+    keys: u16,
+    audio: Audio,
+    halted: bool,
+    rng: u64,
+    undo: Vec<(u16, u8)>,       //bytes to write over the memory of the next snapshot to get this one's
+    inputs: Vec<u16>,           //keyboard of every frame run after the snapshot
+}
+
+fn pack(pixels: &[u8]) -> Vec<u8> {
+    pixels.chunks(4)
+        .map(|chunk| chunk.iter()
+            .enumerate()
+            .fold(0, |byte, (idx, &pixel)| byte | ((pixel & 0x3) << (idx * 2))))
+        .collect()
+}
+
+fn unpack(packed: &[u8], len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|idx| (packed[idx / 4] >> ((idx % 4) * 2)) & 0x3)
+        .collect()
+}
+
+pub struct Rewind {
+    pub capacity: usize,            //snapshots kept, the oldest is dropped first
+    pub interval: u64,              //frames between two snapshots
+    snapshots: VecDeque<Snapshot>,
+    memory: Vec<u8>,                //memory at the newest snapshot
+}
+
+impl Rewind {
+    pub fn new(capacity: usize, interval: u64) -> Self {
+        Rewind {
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            snapshots: VecDeque::new(),
+            memory: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.memory.clear();
+    }
+
+    //Oldest frame rewind can go back to
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.frame)
+    }
+
+    //Approximate bytes used by the snapshots
+    pub fn size(&self) -> usize {
+        let snapshots: usize = self.snapshots.iter()
+            .map(|snapshot| size_of::<Snapshot>()
+                + snapshot.pixels.len()
+                + snapshot.undo.len() * size_of::<(u16, u8)>()
+                + snapshot.inputs.len() * size_of::<u16>()
+                + snapshot.register.v.len() + snapshot.register.flags.len()
+                + snapshot.register.stack.len() * size_of::<u16>())
+            .sum();
+        snapshots + self.memory.len()
+    }
+
+    //Must be called once per frame, right after the timers are decremented.
+    //Takes a snapshot every interval frames
+    pub fn record(&mut self, cpu: &Cpu) {
+        let stale = match self.snapshots.back() {
+            Some(newest) => cpu.frames <= newest.frame || cpu.memory.data.len() != self.memory.len(),
+            None => false,
+        };
+        if stale {
+            self.clear();
+        }
+
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.inputs.push(cpu.keyboard.bits());
+            if cpu.frames - newest.frame < self.interval {
+                return;
+            }
+
+            newest.undo = self.memory.iter()
+                .zip(cpu.memory.data.iter())
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(addr, (&old, _))| (addr as u16, old))
+                .collect();
+        }
+
+        self.memory.clear();
+        self.memory.extend_from_slice(&cpu.memory.data);
+        self.snapshots.push_back(Snapshot {
+            frame: cpu.frames,
+            cycles: cpu.cycles,
+            register: cpu.register.clone(),
+            hires: cpu.display.is_hires(),
+            planes: cpu.display.planes(),
+            pixels: pack(cpu.display.pixels()),
+            keys: cpu.keyboard.bits(),
+            audio: cpu.audio,
+            halted: cpu.halted,
+            rng: cpu.rng.state(),
+            undo: Vec::new(),
+            inputs: Vec::new(),
+        });
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    //Restores snapshot idx, the newer ones are dropped
+    fn restore(&mut self, cpu: &mut Cpu, idx: usize) {
+        for snapshot in self.snapshots.iter().skip(idx).rev() {
+            for &(addr, value) in snapshot.undo.iter() {
+                self.memory[usize::from(addr)] = value;
+            }
+        }
+        self.snapshots.truncate(idx + 1);
+
+        let snapshot = &mut self.snapshots[idx];
+        snapshot.undo.clear();
+        snapshot.inputs.clear();
+
+        let (width, height) = if snapshot.hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        };
+        cpu.display.restore(snapshot.hires, snapshot.planes, unpack(&snapshot.pixels, width * height));
+        cpu.memory.data.copy_from_slice(&self.memory);
+        cpu.register = snapshot.register.clone();
+        cpu.keyboard.set_bits(snapshot.keys);
+        cpu.audio = snapshot.audio;
+        cpu.halted = snapshot.halted;
+        cpu.rng.set_state(snapshot.rng);
+        cpu.frames = snapshot.frame;
+        cpu.cycles = snapshot.cycles;
+    }
+
+    //Goes back to the newest snapshot at least frames frames old, or the
+    //oldest one. Returns the number of frames actually rewound
+    pub fn rewind(&mut self, cpu: &mut Cpu, frames: u64) -> Option<u64> {
+        let target = cpu.frames.saturating_sub(frames);
+        let idx = self.snapshots.iter()
+            .rposition(|snapshot| snapshot.frame <= target)
+            .or(if self.is_empty() { None } else { Some(0) })?;
+
+        let current = cpu.frames;
+        self.restore(cpu, idx);
+        Some(current - cpu.frames)
+    }
+
+    //Undoes the last instruction by restoring the snapshot before it and
+    //executing again up to it, with the timers decremented every
+    //instructions_per_frame instructions and the keyboard recorded for every
+    //frame. Returns the instructions executed in the current frame
+    pub fn step_back(&mut self, cpu: &mut Cpu, instructions_per_frame: usize) -> Option<usize> {
+        let target = cpu.cycles.checked_sub(1)?;
+        let idx = self.snapshots.iter().rposition(|snapshot| snapshot.cycles <= target)?;
+        let inputs = self.snapshots[idx].inputs.clone();
+        let keys = cpu.keyboard.bits();
+        self.restore(cpu, idx);
+
+        //The replayed instructions already ran once, the tracer, profiler and
+        //coverage must not see them again
+        let tracer = cpu.tracer.take();
+        let profiler = cpu.profiler.take();
+        let coverage = cpu.coverage.take();
+        let mut frame = 0;
+        let mut executed = 0;
+        while cpu.cycles < target {
+            cpu.keyboard.set_bits(inputs.get(frame).copied().unwrap_or(keys));
+            if cpu.next().is_err() {
+                break;
+            }
+            executed += 1;
+            if executed == instructions_per_frame {
+                executed = 0;
+                frame += 1;
+                cpu.decrement_timers();
+            }
+        }
+
+        cpu.tracer = tracer;
+        cpu.profiler = profiler;
+        cpu.coverage = coverage;
+        cpu.keyboard.set_bits(keys);
+        cpu.watchpoints.take_hits();
+        self.snapshots[idx].inputs = inputs.into_iter().take(frame).collect();
+        Some(executed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Config;
+
+    const IPF: usize = 10;
+
+    fn invaders() -> Cpu {
+        let rom = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/rom/INVADERS")).unwrap();
+        Cpu::with_config(&rom, Config { seed: Some(3), ..Config::default() })
+    }
+
+    //Runs frames frames recording every one, returns the state after each frame
+    fn record(cpu: &mut Cpu, rewind: &mut Rewind, frames: usize) -> Vec<Vec<u8>> {
+        let mut states = vec![cpu.save_state()];
+        rewind.record(cpu);
+        for frame in 0..frames {
+            cpu.keyboard.set_key(5, (20..30).contains(&frame));
+            cpu.run_frame(IPF).unwrap();
+            rewind.record(cpu);
+            states.push(cpu.save_state());
+        }
+        states
+    }
+
+    #[test]
+    fn rewind_restores_the_state_of_a_snapshot() {
+        let mut cpu = invaders();
+        let mut rewind = Rewind::new(100, 4);
+        let states = record(&mut cpu, &mut rewind, 60);
+
+        assert_eq!(rewind.rewind(&mut cpu, 10), Some(12));
+        assert_eq!(cpu.frames, 48);
+        assert_eq!(cpu.save_state(), states[48]);
+
+        //Running again from the snapshot gives the same frames
+        let mut again = record(&mut cpu, &mut rewind, 12);
+        assert_eq!(again.pop(), states.last().cloned());
+    }
+
+    #[test]
+    fn step_back_restores_the_state_before_the_last_instruction() {
+        let mut cpu = invaders();
+        let mut rewind = Rewind::new(100, 4);
+        let states = record(&mut cpu, &mut rewind, 27);
+
+        //The reference runs the last frame one instruction at a time
+        let mut reference = invaders();
+        for frame in 0..26 {
+            reference.keyboard.set_key(5, (20..30).contains(&frame));
+            reference.run_frame(IPF).unwrap();
+        }
+        let mut before = Vec::new();
+        for _ in 0..IPF {
+            before.push(reference.save_state());
+            reference.next().unwrap();
+        }
+        reference.decrement_timers();
+        assert_eq!(reference.save_state(), states[27]);
+
+        for _ in 0..3 {
+            cpu.next().unwrap();
+        }
+        for _ in 0..3 {
+            rewind.step_back(&mut cpu, IPF).unwrap();
+        }
+        assert_eq!(cpu.save_state(), states[27]);
+
+        //Back across the frame boundary, before the timers were decremented
+        for expected in before.iter().rev() {
+            rewind.step_back(&mut cpu, IPF).unwrap();
+            assert_eq!(&cpu.save_state(), expected);
+        }
+        assert_eq!(cpu.save_state(), states[26]);
+    }
+}
@@ -0,0 +1,268 @@
+//Rom database, known programs looked up by the SHA-1 of their image
+//
+//The database is a JSON file:
+//  {"roms": [{
+//      "sha1": "1ba5...",              lowercase hex SHA-1 of the rom
+//      "title": "IBM Logo",
+//      "author": "...",                optional
+//      "platform": "chip8",            chip8, schip or xochip
+//      "quirks": "vip",                optional, legacy, vip, chip48, schip, xochip or an object
+//                                      {"shift": b, "loadStore": "none"|"x"|"x+1", "jump": b,
+//                                       "logic": b, "clip": b} overriding the platform preset
+//      "tickrate": 15,                 optional, instructions per frame
+//      "keys": {"left": 4, "a": 5},    optional, up, down, left, right, a and b to CHIP-8 keys
+//      "colors": ["#000000", ...]      optional, up to 4 colors indexed by pixel value
+//  }]}
+//The bundled database is rom/roms.json, a local one can be put in front of it
+
+use super::analyzer::analyze;
+use super::json::Json;
+use super::keyboard::NAMED_KEYS;
+use super::platform::Platform;
+use super::quirks::{IndexIncrement, Quirks};
+use super::sha1::sha1_hex;
+
+use std::error::Error;
+use std::fmt;
+
+const BUNDLED: &str = include_str!("../rom/roms.json");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomDbError {
+    Json(String),
+    Invalid { entry: usize, reason: String },     //entries are numbered from 0
+}
+
+impl fmt::Display for RomDbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomDbError::Json(reason) => write!(f, "invalid rom database: {}", reason),
+            RomDbError::Invalid { entry, reason } => write!(f, "rom database entry {}: {}", entry, reason),
+        }
+    }
+}
+
+impl Error for RomDbError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    pub sha1: String,
+    pub title: Option<String>,          //None when the rom is not in the database
+    pub author: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub tickrate: Option<usize>,
+    pub keys: Vec<(String, usize)>,     //name of a host key and the CHIP-8 key it presses
+    pub colors: Option<[u32; 4]>,
+}
+
+pub fn parse_platform(name: &str) -> Option<Platform> {
+    match name {
+        "chip8" => Some(Platform::Chip8),
+        "schip" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+pub fn parse_quirks(name: &str) -> Option<Quirks> {
+    match name {
+        "legacy" => Some(Quirks::default()),
+        "vip" => Some(Quirks::cosmac_vip()),
+        "chip48" => Some(Quirks::chip48()),
+        "schip" => Some(Quirks::superchip()),
+        "xochip" => Some(Quirks::xochip()),
+        _ => None,
+    }
+}
+
+fn parse_color(value: &Json) -> Option<u32> {
+    let text = value.as_str()?.strip_prefix('#')?;
+    if text.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn quirks_from_json(value: &Json, platform: Platform) -> Result<Quirks, String> {
+    if let Some(name) = value.as_str() {
+        return parse_quirks(name).ok_or_else(|| format!("unknown quirks '{}'", name));
+    }
+    let entries = match value {
+        Json::Object(entries) => entries,
+        _ => return Err("quirks must be a name or an object".to_string()),
+    };
+
+    let mut quirks = platform.quirks();
+    for (key, value) in entries {
+        let flag = || value.as_bool().ok_or_else(|| format!("quirk {} must be true or false", key));
+        match key.as_str() {
+            "shift" => quirks.shift_uses_vy = flag()?,
+            "jump" => quirks.jump_uses_vx = flag()?,
+            "logic" => quirks.logic_resets_vf = flag()?,
+            "clip" => quirks.clip_sprites = flag()?,
+            "loadStore" => quirks.load_store = match value.as_str() {
+                Some("none") => IndexIncrement::Unchanged,
+                Some("x") => IndexIncrement::X,
+                Some("x+1") => IndexIncrement::XPlusOne,
+                _ => return Err("loadStore must be none, x or x+1".to_string()),
+            },
+            _ => return Err(format!("unknown quirk {}", key)),
+        }
+    }
+    Ok(quirks)
+}
+
+impl RomInfo {
+    fn from_json(value: &Json) -> Result<Self, String> {
+        let text = |key: &str| value.get(key).and_then(Json::as_str).map(str::to_string);
+
+        let sha1 = text("sha1").ok_or("missing sha1")?.to_lowercase();
+        if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid sha1 '{}'", sha1));
+        }
+        let title = Some(text("title").ok_or("missing title")?);
+        let platform = match text("platform") {
+            Some(name) => parse_platform(&name).ok_or_else(|| format!("unknown platform '{}'", name))?,
+            None => Platform::Chip8,
+        };
+        let quirks = match value.get("quirks") {
+            Some(quirks) => quirks_from_json(quirks, platform)?,
+            None => platform.quirks(),
+        };
+        let tickrate = match value.get("tickrate") {
+            Some(tickrate) => Some(tickrate.as_f64().filter(|&n| n >= 1.0).ok_or("invalid tickrate")? as usize),
+            None => None,
+        };
+
+        let mut keys = Vec::new();
+        if let Some(Json::Object(entries)) = value.get("keys") {
+            for (name, key) in entries {
+                if !NAMED_KEYS.iter().any(|(named, _)| named == name) {
+                    return Err(format!("unknown key name '{}'", name));
+                }
+                let key = key.as_f64().filter(|&key| (0.0..16.0).contains(&key)).ok_or("keys must be 0 to 15")?;
+                keys.push((name.clone(), key as usize));
+            }
+        }
+
+        let colors = match value.get("colors").map(|colors| colors.as_array()) {
+            Some(Some(colors)) if colors.len() <= 4 => {
+                let mut palette = [0x000000, 0xffffff, 0xaaaaaa, 0x555555];
+                for (color, value) in palette.iter_mut().zip(colors.iter()) {
+                    *color = parse_color(value).ok_or("colors must be #rrggbb")?;
+                }
+                Some(palette)
+            },
+            Some(_) => return Err("colors must be an array of up to 4 colors".to_string()),
+            None => None,
+        };
+
+        Ok(RomInfo { sha1, title, author: text("author"), platform, quirks, tickrate, keys, colors })
+    }
+}
+
+//Guess for a rom that is not in the database, from the static analysis of
+//its code
+pub fn guess(rom: &[u8]) -> RomInfo {
+    let analysis = analyze(rom);
+    RomInfo {
+        sha1: sha1_hex(rom),
+        title: None,
+        author: None,
+        platform: analysis.platform,
+        quirks: analysis.quirks,
+        tickrate: None,
+        keys: Vec::new(),
+        colors: None,
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RomDb {
+    pub roms: Vec<RomInfo>,
+}
+
+impl RomDb {
+    pub fn from_json(text: &str) -> Result<Self, RomDbError> {
+        let json = Json::parse(text).map_err(RomDbError::Json)?;
+        let entries = json.get("roms")
+            .and_then(Json::as_array)
+            .ok_or_else(|| RomDbError::Json("missing roms array".to_string()))?;
+
+        let roms = entries.iter()
+            .enumerate()
+            .map(|(entry, value)| RomInfo::from_json(value).map_err(|reason| RomDbError::Invalid { entry, reason }))
+            .collect::<Result<Vec<RomInfo>, RomDbError>>()?;
+        Ok(RomDb { roms })
+    }
+
+    //Database shipped with the crate
+    pub fn bundled() -> Self {
+        RomDb::from_json(BUNDLED).expect("bundled rom database is valid")
+    }
+
+    //Puts the entries of local before these, so they win when both know a rom
+    pub fn with_local(mut self, local: RomDb) -> Self {
+        let mut roms = local.roms;
+        roms.append(&mut self.roms);
+        RomDb { roms }
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        let sha1 = sha1_hex(rom);
+        self.roms.iter().find(|info| info.sha1 == sha1)
+    }
+
+    //Entry of the rom, or a guess when it is unknown
+    pub fn identify(&self, rom: &[u8]) -> RomInfo {
+        self.lookup(rom).cloned().unwrap_or_else(|| guess(rom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/rom/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn bundled_database_identifies_the_bundled_roms() {
+        let db = RomDb::bundled();
+        assert_eq!(db.roms.len(), 4);
+
+        let ibm = db.lookup(&rom("IBM")).unwrap();
+        assert_eq!(ibm.sha1, "1ba58656810b67fd131eb9af3e3987863bf26c90");
+        assert_eq!(ibm.title.as_deref(), Some("IBM Logo"));
+        assert_eq!((ibm.platform, ibm.quirks), (Platform::Chip8, Quirks::cosmac_vip()));
+
+        let invaders = db.lookup(&rom("INVADERS")).unwrap();
+        assert_eq!(invaders.tickrate, Some(15));
+        assert_eq!(invaders.keys.len(), 3);
+        assert!(invaders.keys.contains(&("a".to_string(), 5)));
+    }
+
+    #[test]
+    fn unknown_roms_are_guessed() {
+        let db = RomDb::bundled();
+        assert_eq!(db.lookup(&[0x00, 0xe0]), None);
+        let info = db.identify(&[0x00, 0xff, 0x12, 0x02]);
+        assert_eq!((info.title, info.platform), (None, Platform::SuperChip));
+    }
+
+    #[test]
+    fn local_entries_win_and_invalid_ones_are_reported() {
+        let ibm = rom("IBM");
+        let local = RomDb::from_json(&format!(
+            r#"{{"roms": [{{"sha1": "{}", "title": "Mine", "platform": "schip", "quirks": {{"clip": false}}}}]}}"#,
+            sha1_hex(&ibm))).unwrap();
+        let info = RomDb::bundled().with_local(local).identify(&ibm);
+        assert_eq!(info.title.as_deref(), Some("Mine"));
+        assert_eq!(info.quirks, Quirks { clip_sprites: false, ..Quirks::superchip() });
+
+        let error = RomDb::from_json(r#"{"roms": [{"sha1": "00", "title": "Short"}]}"#).unwrap_err();
+        assert_eq!(error, RomDbError::Invalid { entry: 0, reason: "invalid sha1 '00'".to_string() });
+    }
+}
@@ -0,0 +1,217 @@
+//Call stack with a fixed number of levels
+//
+//The COSMAC VIP interpreter kept the return addresses in memory, in the 48
+//bytes below 0xED0, growing down from there one big endian word per level.
+//With in_memory set every CALL also writes its return address there and RET
+//reads it back, so programs that overwrite that area see what they would
+//on the VIP
+
+use super::cpu::Cpu;
+use super::error::Fault;
+use super::memory::Memory;
+
+//Save states and movies store the depth in a byte
+pub const MAX_STACK_DEPTH: usize = 0xff;
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+
+//VIP stack area, 24 levels fit in it
+pub const MEMORY_STACK_START: u16 = 0xea0;
+pub const MEMORY_STACK_END: u16 = 0xed0;
+pub const MEMORY_STACK_DEPTH: usize = (MEMORY_STACK_END - MEMORY_STACK_START) as usize / 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stack {
+    entries: Vec<u16>,      //return addresses, outermost first
+    depth: usize,           //levels available, a CALL past them overflows
+    pub in_memory: bool,    //return addresses are also kept in memory
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::new(DEFAULT_STACK_DEPTH, false)
+    }
+}
+
+impl Stack {
+    //depth is capped at MAX_STACK_DEPTH, or at MEMORY_STACK_DEPTH when the
+    //stack is kept in memory so that it never grows past its area
+    pub fn new(depth: usize, in_memory: bool) -> Self {
+        let depth = depth.min(if in_memory { MEMORY_STACK_DEPTH } else { MAX_STACK_DEPTH });
+        Stack {
+            entries: Vec::with_capacity(depth),
+            depth,
+            in_memory,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.depth
+    }
+
+    //Outermost first
+    pub fn iter(&self) -> std::slice::Iter<'_, u16> {
+        self.entries.iter()
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn push(&mut self, addr: u16) -> Result<(), Fault> {
+        if self.is_full() {
+            return Err(Fault::StackOverflow);
+        }
+        self.entries.push(addr);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<u16, Fault> {
+        self.entries.pop().ok_or(Fault::StackUnderflow)
+    }
+
+    //Grows with zeros or drops the innermost levels, false past the depth
+    pub fn resize(&mut self, len: usize) -> bool {
+        if len > self.depth {
+            return false;
+        }
+        self.entries.resize(len, 0);
+        true
+    }
+}
+
+//Address of the memory word of level, 0 being the outermost
+pub fn memory_slot(level: usize) -> usize {
+    usize::from(MEMORY_STACK_END) - 2 * (level + 1)
+}
+
+//Level of a backtrace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackFrame {
+    pub level: usize,               //0 is the outermost call
+    pub call: u16,                  //address of the CALL
+    pub subroutine: Option<u16>,    //target of the CALL, None if it was overwritten
+    pub ret: u16,                   //return address
+}
+
+impl Cpu {
+    //Return address of level, read from memory when the stack lives there
+    pub fn return_address(&self, level: usize) -> Option<u16> {
+        let stack = &self.register.stack;
+        if level >= stack.len() {
+            return None;
+        }
+        let slot = memory_slot(level);
+        if stack.in_memory && slot + 1 < self.memory.size() {
+            Some(self.memory.get_u16(slot))
+        } else {
+            stack.as_slice().get(level).copied()
+        }
+    }
+
+    //Active calls, innermost first
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        (0..self.register.stack.len())
+            .rev()
+            .filter_map(|level| {
+                let ret = self.return_address(level)?;
+                let call = ret.wrapping_sub(2);
+                let opcode = if usize::from(call) + 1 < self.memory.size() {
+                    self.memory.get_u16(usize::from(call))
+                } else {
+                    0
+                };
+                let subroutine = if opcode & 0xf000 == 0x2000 { Some(opcode & 0x0fff) } else { None };
+                Some(StackFrame { level, call, subroutine, ret })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Config;
+    use crate::error::CpuError;
+    use crate::platform::Platform;
+
+    //CALL 200, forever
+    const RECURSE: &[u8] = &[0x22, 0x00];
+
+    //Calls executed before the stack overflows
+    fn calls(config: Config) -> usize {
+        let mut cpu = Cpu::with_config(RECURSE, config);
+        let mut calls = 0;
+        loop {
+            match cpu.next() {
+                Ok(_) => calls += 1,
+                Err(error) => {
+                    assert_eq!(error, CpuError::StackOverflow { pc: 0x200, opcode: 0x2200 });
+                    return calls;
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn memory_slots_grow_down_from_the_vip_stack_end() {
+        assert_eq!(memory_slot(0), 0xece);
+        assert_eq!(memory_slot(1), 0xecc);
+        assert_eq!(memory_slot(23), usize::from(MEMORY_STACK_START));
+    }
+
+    #[test]
+    fn depth_follows_the_platform() {
+        assert_eq!(calls(Config::new(Platform::Chip8)), 12);
+        assert_eq!(calls(Config::new(Platform::SuperChip)), 16);
+        assert_eq!(calls(Config::new(Platform::XoChip)), 16);
+        assert_eq!(calls(Config::default()), DEFAULT_STACK_DEPTH);
+        assert_eq!(calls(Config { stack_depth: 3, ..Config::default() }), 3);
+    }
+
+    #[test]
+    fn memory_stack_fits_in_the_vip_stack_area() {
+        assert_eq!(MEMORY_STACK_DEPTH, 24);
+        assert_eq!(Stack::new(100, true).depth(), MEMORY_STACK_DEPTH);
+        assert_eq!(Stack::new(1000, false).depth(), MAX_STACK_DEPTH);
+        let config = Config { stack_depth: 100, memory_stack: true, ..Config::default() };
+        assert_eq!(calls(config), 24);
+    }
+
+    #[test]
+    fn memory_stack_is_visible_to_the_rom() {
+        //CALL 206; JP 202; (206) CALL 20A; (20A) RET
+        let rom = [0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x22, 0x0a, 0x00, 0x00, 0x00, 0xee];
+        let mut cpu = Cpu::with_config(&rom, Config { memory_stack: true, ..Config::default() });
+        cpu.next().unwrap();
+        cpu.next().unwrap();
+        assert_eq!(cpu.memory.get_u16(memory_slot(0)), 0x202);
+        assert_eq!(cpu.memory.get_u16(memory_slot(1)), 0x208);
+        assert_eq!(cpu.backtrace(), vec![
+            StackFrame { level: 1, call: 0x206, subroutine: Some(0x20a), ret: 0x208 },
+            StackFrame { level: 0, call: 0x200, subroutine: Some(0x206), ret: 0x202 },
+        ]);
+
+        //RET follows the address the rom wrote over the slot
+        cpu.memory.data[memory_slot(1)..memory_slot(1) + 2].copy_from_slice(&[0x02, 0x04]);
+        assert_eq!(cpu.return_address(1), Some(0x204));
+        cpu.next().unwrap();
+        assert_eq!(cpu.register.pc, 0x204);
+        assert_eq!(cpu.register.stack.as_slice(), &[0x202]);
+    }
+}
@@ -0,0 +1,353 @@
+//Save state format, every integer is big endian:
+//  magic       4 bytes "C8ST"
+//  version     u16
+//  platform    u8, quirks 5 x u8
+//  memory      u32 length, bytes
+//  register    V0..VF, I u16, PC u16, stack u8 depth + u8 1 if kept in
+//              memory + u8 length + u16 entries,
+//              sound u8, delay u8, RPL flags 16 x u8
+//  display     hires u8, planes u8, palette 4 x u32, u32 length, pixels
+//  keyboard    u16 bitmask
+//  audio       pattern 16 x u8, pitch u8
+//  halted      u8
+//  rng         u64
+//  checksum    u32, CRC-32 of everything before it
+
+use super::cpu::Cpu;
+use super::memory::Data;
+use super::platform::Platform;
+use super::quirks::{IndexIncrement, Quirks};
+use super::register::Register;
+use super::stack::Stack;
+
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8] = b"C8ST";
+const VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    BadChecksum,
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            StateError::BadChecksum => write!(f, "save state checksum mismatch"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(field) => write!(f, "invalid {} in save state", field),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+pub(crate) struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Self {
+        Writer { data: Vec::new() }
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StateError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(value))
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+pub(crate) fn write_platform(writer: &mut Writer, platform: Platform) {
+    writer.u8(match platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2,
+    });
+}
+
+pub(crate) fn read_platform(reader: &mut Reader) -> Result<Platform, StateError> {
+    match reader.u8()? {
+        0 => Ok(Platform::Chip8),
+        1 => Ok(Platform::SuperChip),
+        2 => Ok(Platform::XoChip),
+        _ => Err(StateError::Invalid("platform")),
+    }
+}
+
+pub(crate) fn write_quirks(writer: &mut Writer, quirks: &Quirks) {
+    writer.u8(quirks.shift_uses_vy as u8);
+    writer.u8(match quirks.load_store {
+        IndexIncrement::Unchanged => 0,
+        IndexIncrement::X => 1,
+        IndexIncrement::XPlusOne => 2,
+    });
+    writer.u8(quirks.jump_uses_vx as u8);
+    writer.u8(quirks.logic_resets_vf as u8);
+    writer.u8(quirks.clip_sprites as u8);
+}
+
+pub(crate) fn read_quirks(reader: &mut Reader) -> Result<Quirks, StateError> {
+    Ok(Quirks {
+        shift_uses_vy: reader.bool()?,
+        load_store: match reader.u8()? {
+            0 => IndexIncrement::Unchanged,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::XPlusOne,
+            _ => return Err(StateError::Invalid("quirks")),
+        },
+        jump_uses_vx: reader.bool()?,
+        logic_resets_vf: reader.bool()?,
+        clip_sprites: reader.bool()?,
+    })
+}
+
+impl Cpu {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+
+        write_platform(&mut writer, self.platform);
+        write_quirks(&mut writer, &self.quirks);
+
+        writer.u32(self.memory.data.len() as u32);
+        writer.bytes(&self.memory.data);
+
+        writer.bytes(&self.register.v);
+        writer.u16(self.register.i);
+        writer.u16(self.register.pc);
+        writer.u8(self.register.stack.depth() as u8);
+        writer.u8(self.register.stack.in_memory as u8);
+        writer.u8(self.register.stack.len() as u8);
+        for &addr in self.register.stack.iter() {
+            writer.u16(addr);
+        }
+        writer.u8(self.register.sound);
+        writer.u8(self.register.delay);
+        writer.bytes(&self.register.flags);
+
+        writer.u8(self.display.is_hires() as u8);
+        writer.u8(self.display.planes());
+        for &color in self.display.palette.iter() {
+            writer.u32(color);
+        }
+        writer.u32(self.display.pixels().len() as u32);
+        writer.bytes(self.display.pixels());
+
+        writer.u16(self.keyboard.bits());
+
+        writer.bytes(&self.audio.pattern);
+        writer.u8(self.audio.pitch);
+
+        writer.u8(self.halted as u8);
+        writer.u64(self.rng.state());
+
+        let mut data = writer.finish();
+        let checksum = crc32(&data);
+        data.extend_from_slice(&checksum.to_be_bytes());
+        data
+    }
+
+    //Restores a state produced by save_state, the cpu is left untouched on error
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() < MAGIC.len() || &state[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        if state.len() < MAGIC.len() + 2 + 4 {
+            return Err(StateError::Truncated);
+        }
+        let (data, checksum) = state.split_at(state.len() - 4);
+        if crc32(data).to_be_bytes() != checksum {
+            return Err(StateError::BadChecksum);
+        }
+
+        let mut reader = Reader::new(&data[MAGIC.len()..]);
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let platform = read_platform(&mut reader)?;
+        let quirks = read_quirks(&mut reader)?;
+
+        let len = reader.u32()? as usize;
+        if len != platform.memory_size() {
+            return Err(StateError::Invalid("memory size"));
+        }
+        let memory = Data { data: reader.bytes(len)?.to_vec() };
+
+        let mut register = Register::new();
+        register.v = reader.bytes(0x10)?.to_vec();
+        register.i = reader.u16()?;
+        register.pc = reader.u16()?;
+        let depth = usize::from(reader.u8()?);
+        register.stack = Stack::new(depth, reader.bool()?);
+        for _ in 0..reader.u8()? {
+            register.stack.push(reader.u16()?).map_err(|_| StateError::Invalid("stack"))?;
+        }
+        register.sound = reader.u8()?;
+        register.delay = reader.u8()?;
+        register.flags = reader.bytes(0x10)?.to_vec();
+
+        let hires = reader.bool()?;
+        let planes = reader.u8()?;
+        let mut palette = [0; 4];
+        for color in palette.iter_mut() {
+            *color = reader.u32()?;
+        }
+        let len = reader.u32()? as usize;
+        let pixels = reader.bytes(len)?.to_vec();
+
+        let keys = reader.u16()?;
+
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(reader.bytes(16)?);
+        let pitch = reader.u8()?;
+
+        let halted = reader.bool()?;
+        let rng = reader.u64()?;
+
+        if !reader.is_empty() {
+            return Err(StateError::Invalid("length"));
+        }
+        if !self.display.restore(hires, planes, pixels) {
+            return Err(StateError::Invalid("display"));
+        }
+
+        self.platform = platform;
+        self.quirks = quirks;
+        self.memory = memory;
+        self.register = register;
+        self.display.palette = palette;
+        self.keyboard.set_bits(keys);
+        self.audio.pattern = pattern;
+        self.audio.pitch = pitch;
+        self.halted = halted;
+        self.rng.set_state(rng);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Config;
+
+    fn ibm() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/rom/IBM")).unwrap()
+    }
+
+    #[test]
+    fn save_state_round_trips() {
+        let config = Config { seed: Some(7), memory_stack: true, ..Config::new(Platform::XoChip) };
+        let mut cpu = Cpu::with_config(&ibm(), config);
+        cpu.run_frame(25).unwrap();
+        cpu.keyboard.set_key(5, true);
+        let state = cpu.save_state();
+
+        let mut other = Cpu::new(&[]);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        assert_eq!(other.register.stack, cpu.register.stack);
+
+        cpu.run_frame(25).unwrap();
+        other.run_frame(25).unwrap();
+        assert_eq!(other.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn corrupted_states_are_rejected() {
+        let mut cpu = Cpu::new(&ibm());
+        let mut state = cpu.save_state();
+        let last = state.len() - 5;
+        state[last] ^= 1;
+        assert_eq!(cpu.load_state(&state), Err(StateError::BadChecksum));
+        assert_eq!(cpu.load_state(b"C8"), Err(StateError::BadMagic));
+    }
+}
@@ -0,0 +1,211 @@
+//Execution tracer, one line per instruction with the state before it runs:
+//  cycle  pc   op    mnemonic                  registers
+//  000012 0204 a22a  LD   I, 0x22a             v=000c0800000000000000000000000000 i=0000 sp=0 dt=00 st=00
+//
+//The first three columns and the key=value fields are what diff_traces
+//compares, the mnemonic is only there for the reader
+
+use super::disassembler::disassemble;
+use super::register::Register;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+enum Sink {
+    Writer(Box<dyn Write>),
+    Ring(VecDeque<String>, usize),  //last lines, capacity
+}
+
+pub struct Tracer {
+    pub range: Option<RangeInclusive<u16>>,     //only instructions with PC in range are traced
+    sink: Sink,
+    error: Option<io::Error>,                   //first write error, tracing stops after it
+}
+
+pub fn format_trace(cycle: u64, pc: u16, opcode: u16, register: &Register) -> String {
+    let v: String = register.v.iter().map(|value| format!("{:02x}", value)).collect();
+    format!("{:06} {:04x} {:04x}  {:<24} v={} i={:04x} sp={:x} dt={:02x} st={:02x}",
+        cycle, pc, opcode, disassemble(opcode).to_string(), v,
+        register.i, register.stack.len(), register.delay, register.sound)
+}
+
+impl Tracer {
+    pub fn to_writer(writer: Box<dyn Write>) -> Self {
+        Tracer {
+            range: None,
+            sink: Sink::Writer(writer),
+            error: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Tracer::to_writer(Box::new(BufWriter::new(file))))
+    }
+
+    //Keeps the last capacity lines in memory
+    pub fn ring(capacity: usize) -> Self {
+        Tracer {
+            range: None,
+            sink: Sink::Ring(VecDeque::with_capacity(capacity), capacity.max(1)),
+            error: None,
+        }
+    }
+
+    pub fn with_range(self, range: RangeInclusive<u16>) -> Self {
+        Tracer { range: Some(range), ..self }
+    }
+
+    pub fn trace(&mut self, cycle: u64, pc: u16, opcode: u16, register: &Register) {
+        if self.range.as_ref().is_some_and(|range| !range.contains(&pc)) {
+            return;
+        }
+
+        let line = format_trace(cycle, pc, opcode, register);
+        match &mut self.sink {
+            Sink::Writer(writer) => {
+                if self.error.is_none() {
+                    if let Err(err) = writeln!(writer, "{}", line) {
+                        self.error = Some(err);
+                    }
+                }
+            },
+            Sink::Ring(lines, capacity) => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            },
+        }
+    }
+
+    //Lines kept by a ring tracer, oldest first
+    pub fn lines(&self) -> Vec<String> {
+        match &self.sink {
+            Sink::Ring(lines, _) => lines.iter().cloned().collect(),
+            Sink::Writer(_) => Vec::new(),
+        }
+    }
+
+    //Flushes a writer tracer, reports the first write error
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        match &mut self.sink {
+            Sink::Writer(writer) => writer.flush(),
+            Sink::Ring(..) => Ok(()),
+        }
+    }
+}
+
+//First place where two traces disagree. Lines are numbered from 1 in each
+//file, blank and comment lines included, a trace that ended reports the line
+//after its last one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub left_line: usize,
+    pub right_line: usize,
+    pub field: String,
+    pub left: Option<String>,       //None if the trace ended
+    pub right: Option<String>,
+}
+
+//Fields of a trace line: cycle, pc, op and every key=value token
+fn trace_fields(line: &str) -> Vec<(String, String)> {
+    let mut tokens = line.split_whitespace();
+    let mut fields: Vec<(String, String)> = ["cycle", "pc", "op"].iter()
+        .zip(tokens.by_ref())
+        .map(|(key, value)| (key.to_string(), value.to_lowercase()))
+        .collect();
+    fields.extend(tokens.filter_map(|token| {
+        let mut parts = token.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => Some((key.to_lowercase(), value.to_lowercase())),
+            _ => None,
+        }
+    }));
+    fields
+}
+
+//Values are compared as numbers so that 0x200, 200 and 0200 match, the cycle
+//is decimal and every other field hex
+fn same_value(key: &str, a: &str, b: &str) -> bool {
+    let parse = |value: &str| if key == "cycle" {
+        value.parse::<u128>().ok()
+    } else {
+        u128::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+    };
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+//Compares two traces line by line, fields missing from one of the lines are
+//skipped so a trace with fewer registers can be compared against a full one.
+//Empty lines and lines starting with # are ignored
+pub fn diff_traces(left: &str, right: &str) -> Option<Divergence> {
+    //Trace lines with their line number in the file
+    let lines = |text: &'_ str| text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| (idx + 1, line.to_string()))
+        .collect::<Vec<(usize, String)>>();
+    let ends = |text: &'_ str| text.lines().count() + 1;
+    let (left_end, right_end) = (ends(left), ends(right));
+    let (left, right) = (lines(left), lines(right));
+
+    for idx in 0..left.len().max(right.len()) {
+        let (left_line, a, right_line, b) = match (left.get(idx), right.get(idx)) {
+            (Some((left_line, a)), Some((right_line, b))) => (*left_line, a, *right_line, b),
+            (a, b) => return Some(Divergence {
+                left_line: a.map_or(left_end, |(line, _)| *line),
+                right_line: b.map_or(right_end, |(line, _)| *line),
+                field: "end".to_string(),
+                left: a.map(|(_, line)| line.clone()),
+                right: b.map(|(_, line)| line.clone()),
+            }),
+        };
+
+        let fields = trace_fields(b);
+        for (key, value) in trace_fields(a) {
+            let other = fields.iter().find(|(other, _)| *other == key);
+            if let Some((_, other)) = other {
+                if !same_value(&key, &value, other) {
+                    return Some(Divergence {
+                        left_line,
+                        right_line,
+                        field: key,
+                        left: Some(a.clone()),
+                        right: Some(b.clone()),
+                    });
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divergences_report_the_line_in_each_file() {
+        let left = "# left\n0 0200 6005 i=0000\n\n1 0202 7001 i=0000 dt=00\n";
+        let right = "0 0x200 6005\n1 202 7001 dt=01\n";
+        let divergence = diff_traces(left, right).unwrap();
+        assert_eq!((divergence.left_line, divergence.right_line), (4, 2));
+        assert_eq!(divergence.field, "dt");
+
+        let divergence = diff_traces(left, "0 200 6005\n").unwrap();
+        assert_eq!((divergence.left_line, divergence.right_line), (4, 2));
+        assert_eq!((divergence.field.as_str(), divergence.right), ("end", None));
+        assert_eq!(diff_traces(left, "\n0 200 6005\n# skipped\n1 202 7001 dt=0"), None);
+    }
+}
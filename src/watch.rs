@@ -0,0 +1,159 @@
+use std::ops::RangeInclusive;
+
+//Memory access that triggers a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,                       //any read by an instruction, instruction fetches excluded
+    Write,                      //any write
+    Change,                     //a write that modifies the stored value
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<usize>,
+    pub kind: WatchKind,
+}
+
+//Access that triggered a watchpoint, old and new are the same for reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub pc: u16,
+    pub opcode: u16,
+    pub addr: usize,
+    pub kind: WatchKind,
+    pub old: u8,
+    pub new: u8,
+}
+
+//Only the accesses of executing instructions are checked: they go through
+//Cpu::read_u8 and Cpu::write_u8. Direct accesses to Cpu::memory through the
+//Memory trait, such as the writes of the GDB stub, debugger tools or
+//load_state, are not seen
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    hits: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints::default()
+    }
+
+    pub fn add(&mut self, range: RangeInclusive<usize>, kind: WatchKind) {
+        let watchpoint = Watchpoint { range, kind };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    //Removes every watchpoint whose range contains addr, returns how many were removed
+    pub fn remove(&mut self, addr: usize) -> usize {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| !watchpoint.range.contains(&addr));
+        len - self.watchpoints.len()
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    pub fn has_hits(&self) -> bool {
+        !self.hits.is_empty()
+    }
+
+    //Hits since the last call, in execution order
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    fn fire(&mut self, hit: WatchHit) {
+        let triggered = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.range.contains(&hit.addr) && match watchpoint.kind {
+                WatchKind::Read => hit.kind == WatchKind::Read,
+                WatchKind::Write => hit.kind != WatchKind::Read,
+                WatchKind::Change => hit.kind != WatchKind::Read && hit.old != hit.new,
+            }
+        });
+        if triggered {
+            self.hits.push(hit);
+        }
+    }
+
+    pub(crate) fn on_read(&mut self, pc: u16, opcode: u16, addr: usize, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.fire(WatchHit { pc, opcode, addr, kind: WatchKind::Read, old: value, new: value });
+        }
+    }
+
+    pub(crate) fn on_write(&mut self, pc: u16, opcode: u16, addr: usize, old: u8, new: u8) {
+        if !self.watchpoints.is_empty() {
+            let kind = if old != new { WatchKind::Change } else { WatchKind::Write };
+            self.fire(WatchHit { pc, opcode, addr, kind, old, new });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    //LD I, 300; LD V0, 5; LD [I], V0; LD [I], V0; LD V0, [I]
+    const PROGRAM: &[u8] = &[0xa3, 0x00, 0x60, 0x05, 0xf0, 0x55, 0xf0, 0x55, 0xf0, 0x65];
+
+    fn run(watchpoints: &[(RangeInclusive<usize>, WatchKind)]) -> Vec<WatchHit> {
+        let mut cpu = Cpu::new(PROGRAM);
+        for (range, kind) in watchpoints {
+            cpu.watchpoints.add(range.clone(), *kind);
+        }
+        let mut hits = Vec::new();
+        for _ in 0..5 {
+            cpu.next().unwrap();
+            hits.extend(cpu.watchpoints.take_hits());
+        }
+        hits
+    }
+
+    #[test]
+    fn watchpoints_trigger_on_matching_accesses() {
+        let hits = run(&[(0x300..=0x300, WatchKind::Write)]);
+        assert_eq!(hits, vec![
+            WatchHit { pc: 0x204, opcode: 0xf055, addr: 0x300, kind: WatchKind::Change, old: 0, new: 5 },
+            WatchHit { pc: 0x206, opcode: 0xf055, addr: 0x300, kind: WatchKind::Write, old: 5, new: 5 },
+        ]);
+
+        let hits = run(&[(0x2ff..=0x301, WatchKind::Change)]);
+        assert_eq!(hits.iter().map(|hit| hit.pc).collect::<Vec<_>>(), vec![0x204]);
+
+        let hits = run(&[(0x300..=0x300, WatchKind::Read)]);
+        assert_eq!(hits, vec![
+            WatchHit { pc: 0x208, opcode: 0xf065, addr: 0x300, kind: WatchKind::Read, old: 5, new: 5 },
+        ]);
+
+        assert!(run(&[(0x301..=0x3ff, WatchKind::Write)]).is_empty());
+    }
+
+    #[test]
+    fn unwatch_removes_the_covering_watchpoints() {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.add(0x300..=0x30f, WatchKind::Write);
+        watchpoints.add(0x300..=0x30f, WatchKind::Write);
+        watchpoints.add(0x308..=0x308, WatchKind::Read);
+        watchpoints.add(0x400..=0x400, WatchKind::Read);
+        assert_eq!(watchpoints.list().len(), 3);
+
+        assert_eq!(watchpoints.remove(0x308), 2);
+        assert_eq!(watchpoints.remove(0x308), 0);
+        assert_eq!(watchpoints.list(), &[Watchpoint { range: 0x400..=0x400, kind: WatchKind::Read }]);
+
+        watchpoints.on_write(0x200, 0xf055, 0x300, 0, 1);
+        assert!(!watchpoints.has_hits());
+        assert_eq!(watchpoints.remove(0x400), 1);
+        assert!(watchpoints.is_empty());
+    }
+}